@@ -0,0 +1,217 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    codec::{Bincode, Codec},
+    frame::FrameCodec,
+    freeze,
+    vendored::tokio_serde_codec::{IoErrorWrapper, ReadCodec},
+    Incoming, Transport,
+};
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use futures_legacy::{Sink as Sink01, Stream as Stream01};
+use rpc::transport::{Address, Peer};
+use serde::{Deserialize, Serialize};
+use std::{io, marker::PhantomData, net::SocketAddr, time::Duration};
+use tokio::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::{TcpListener, TcpStream};
+
+/// Configures the framing, serialization, and socket options of transports. The `tls` and `unix`
+/// modules' `*_with_builder` functions take a builder too; Unix domain sockets ignore the TCP
+/// socket options.
+///
+/// Frames larger than the max frame length are rejected by both ends without breaking the
+/// connection. A transport fails to send such a frame with an
+/// [`InvalidInput`](io::ErrorKind::InvalidInput) error, which clients and servers report to the
+/// caller of the offending request. A transport that receives such a frame discards it and yields
+/// a [`SkippedMessage`](rpc::transport::SkippedMessage) error in its place: a server answers the
+/// request with an error, and a client fails the request.
+#[derive(Clone, Debug)]
+pub struct Builder<C = Bincode> {
+    codec: C,
+    max_frame_length: usize,
+    length_field_length: usize,
+    nodelay: Option<bool>,
+    keepalive: Option<Option<Duration>>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            codec: Bincode::default(),
+            max_frame_length: 8_000_000,
+            length_field_length: 4,
+            nodelay: None,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Returns a builder of bincode transports with an 8MB max frame length and the operating
+    /// system's default socket options.
+    pub fn new() -> Self {
+        Builder::default()
+    }
+}
+
+impl<C> Builder<C> {
+    /// Sets the codec that serializes messages, such as a [`Bincode`] codec with a size limit.
+    pub fn codec<D: Codec>(self, codec: D) -> Builder<D> {
+        Builder {
+            codec,
+            max_frame_length: self.max_frame_length,
+            length_field_length: self.length_field_length,
+            nodelay: self.nodelay,
+            keepalive: self.keepalive,
+            send_buffer_size: self.send_buffer_size,
+            recv_buffer_size: self.recv_buffer_size,
+        }
+    }
+
+    /// Sets the max length, in bytes, of a serialized message. Defaults to 8MB.
+    pub fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Sets the number of bytes, between 1 and 8, of the big-endian integer that prefixes each
+    /// frame with its length. Defaults to 4.
+    pub fn length_field_length(mut self, length_field_length: usize) -> Self {
+        self.length_field_length = length_field_length;
+        self
+    }
+
+    /// Sets whether TCP sockets send data as soon as possible, rather than buffering small
+    /// writes (`TCP_NODELAY`).
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Sets how long TCP sockets are idle before they send keepalive probes, or disables
+    /// keepalive if `None`.
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the size of TCP sockets' send buffers (`SO_SNDBUF`).
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of TCP sockets' receive buffers (`SO_RCVBUF`).
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+}
+
+impl<C: Codec> Builder<C> {
+    /// Returns a new transport that reads from and writes to `io`, after applying the configured
+    /// socket options to it.
+    pub fn transport<Item, SinkItem>(
+        &self,
+        io: TcpStream,
+    ) -> io::Result<Transport<Item, SinkItem, TcpStream, C>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        self.configure(&io)?;
+        let peer = io.peer_addr().map(|addr| Peer::from(Address::from(addr)));
+        let local_addr = io.local_addr().map(Address::from);
+        Ok(self.framed(io, peer, local_addr))
+    }
+
+    /// Connects to `addr`, wrapping the connection in a transport.
+    pub async fn connect<'a, Item, SinkItem>(
+        &'a self,
+        addr: &'a SocketAddr,
+    ) -> io::Result<Transport<Item, SinkItem, TcpStream, C>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let stream = await!(TcpStream::connect(addr).compat())?;
+        self.transport(stream)
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in transports.
+    pub fn listen<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+    ) -> io::Result<Incoming<Item, SinkItem, C>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let incoming = listener.incoming().compat();
+        Ok(Incoming {
+            incoming,
+            local_addr,
+            builder: self.clone(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// Applies the configured socket options to `io`.
+    pub(crate) fn configure(&self, io: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            io.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            io.set_keepalive(keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            io.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            io.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a new transport that reads from and writes to `io`, a stream connecting
+    /// `local_addr` to `peer`.
+    pub(crate) fn framed<S, Item, SinkItem>(
+        &self,
+        io: S,
+        peer: io::Result<Peer>,
+        local_addr: io::Result<Address>,
+    ) -> Transport<Item, SinkItem, S, C>
+    where
+        S: AsyncRead + AsyncWrite,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let codec = FrameCodec::new(self.max_frame_length, self.length_field_length);
+        let inner = Framed::new(io, codec)
+            .map_err(IoErrorWrapper as _)
+            .sink_map_err(IoErrorWrapper as _)
+            .with(freeze as _);
+        let inner = ReadCodec::new(inner, self.codec.clone());
+
+        Transport {
+            inner,
+            codec: self.codec.clone(),
+            max_frame_length: self.max_frame_length,
+            staged_frame: None,
+            peer,
+            local_addr,
+            ghost: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serialization formats that a [`Transport`](crate::Transport) can speak.
+//!
+//! [`Bincode`] is always available. [`Json`], [`MessagePack`] and [`Cbor`] are enabled by the
+//! `json`, `messagepack` and `cbor` cargo features, respectively.
+//!
+//! Any codec can be wrapped in [`Compressed`] to compress large frames, when the `zstd` or `lz4`
+//! cargo feature is enabled.
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "zstd")]
+use std::io::Read;
+use std::io;
+
+/// Serializes messages into, and deserializes messages from, the frames of a transport. Each
+/// frame holds exactly one message.
+pub trait Codec: Clone {
+    /// Serializes `item` into a new frame.
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>>;
+
+    /// Deserializes the message at the start of `frame`. A codec may fail if `frame` has trailing
+    /// bytes, but needn't: transports also use this to read the header of a message from the
+    /// start of a frame they skip.
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T>;
+}
+
+/// The [bincode](https://github.com/TyOverby/bincode) format: compact, and fast to encode, but
+/// only readily decoded by Rust programs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode {
+    limit: Option<u64>,
+}
+
+impl Bincode {
+    /// Returns a bincode codec that fails to serialize or deserialize any message larger than
+    /// `limit` bytes. This bounds the memory allocated for a message whose length prefixes, e.g.
+    /// of strings or vectors, are corrupt or malicious.
+    pub fn with_limit(limit: u64) -> Self {
+        Bincode { limit: Some(limit) }
+    }
+
+    fn config(&self) -> bincode::Config {
+        let mut config = bincode::config();
+        if let Some(limit) = self.limit {
+            config.limit(limit);
+        }
+        config
+    }
+}
+
+impl Codec for Bincode {
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        self.config().serialize(item).map_err(bincode_error)
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        self.config().deserialize(frame).map_err(bincode_error)
+    }
+}
+
+fn bincode_error(e: bincode::Error) -> io::Error {
+    match *e {
+        bincode::ErrorKind::Io(e) => e,
+        bincode::ErrorKind::InvalidUtf8Encoding(e) => {
+            io::Error::new(io::ErrorKind::InvalidInput, e)
+        }
+        bincode::ErrorKind::InvalidBoolEncoding(e) => {
+            io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+        }
+        bincode::ErrorKind::InvalidTagEncoding(e) => {
+            io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+        }
+        bincode::ErrorKind::InvalidCharEncoding => {
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid char encoding")
+        }
+        bincode::ErrorKind::DeserializeAnyNotSupported => {
+            io::Error::new(io::ErrorKind::InvalidInput, "Deserialize Any not supported")
+        }
+        bincode::ErrorKind::SizeLimit => {
+            io::Error::new(io::ErrorKind::InvalidInput, "Size limit exceeded")
+        }
+        bincode::ErrorKind::SequenceMustHaveLength => {
+            io::Error::new(io::ErrorKind::InvalidInput, "Sequence must have length")
+        }
+        bincode::ErrorKind::Custom(s) => io::Error::new(io::ErrorKind::Other, s),
+    }
+}
+
+/// The JSON format, which is human-readable and understood by practically every language.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(item).map_err(json_error)
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(frame).map_err(json_error)
+    }
+}
+
+#[cfg(feature = "json")]
+fn json_error(e: serde_json::Error) -> io::Error {
+    use serde_json::error::Category;
+
+    match e.classify() {
+        Category::Io => e.into(),
+        Category::Syntax | Category::Data => io::Error::new(io::ErrorKind::InvalidData, e),
+        Category::Eof => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+    }
+}
+
+/// The [MessagePack](https://msgpack.org) format, a compact binary format with implementations
+/// in many languages. Structs are encoded as maps keyed by field name, for interoperability.
+#[cfg(feature = "messagepack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "messagepack")]
+impl Codec for MessagePack {
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(item).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        rmp_serde::from_slice(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The [CBOR](https://cbor.io) format, a compact binary format standardized in RFC 7049.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        serde_cbor::to_vec(item).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        serde_cbor::from_slice(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// An algorithm used by [`Compressed`] to compress frames.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// [Zstandard](https://facebook.github.io/zstd/) at the given level, from 1 (fastest) to 21
+    /// (smallest).
+    #[cfg(feature = "zstd")]
+    Zstd {
+        /// The compression level.
+        level: i32,
+    },
+    /// [LZ4](https://lz4.github.io/lz4/), which trades compression ratio for speed.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Wraps a codec, compressing the frames it produces that are at least `threshold` bytes long.
+///
+/// Each frame is prefixed with a byte identifying how it was compressed, so both ends must wrap
+/// their codecs in `Compressed`, but they need not agree on the algorithm or threshold: each end
+/// decompresses any frame it receives, provided the algorithm's cargo feature is enabled.
+///
+/// A small compressed frame can expand to a huge message, so frames that decompress to more than
+/// the [max decompressed length](Compressed::max_decompressed_length) are rejected with an
+/// [`InvalidData`](io::ErrorKind::InvalidData) error, without being decompressed in full.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+#[derive(Clone, Copy, Debug)]
+pub struct Compressed<C> {
+    codec: C,
+    compression: Compression,
+    threshold: usize,
+    max_decompressed_length: usize,
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+impl<C> Compressed<C> {
+    /// Returns a codec that compresses frames of at least `threshold` bytes serialized by `codec`,
+    /// using `compression`. Smaller frames are sent raw, as compressing them costs more time
+    /// than it saves bandwidth.
+    pub fn new(codec: C, compression: Compression, threshold: usize) -> Self {
+        Compressed {
+            codec,
+            compression,
+            threshold,
+            max_decompressed_length: 8_000_000,
+        }
+    }
+
+    /// Sets the max length, in bytes, of a decompressed frame. Defaults to 8MB, the default max
+    /// frame length of a [`Builder`](crate::Builder).
+    pub fn max_decompressed_length(mut self, max_decompressed_length: usize) -> Self {
+        self.max_decompressed_length = max_decompressed_length;
+        self
+    }
+
+    fn check_decompressed_length(&self, len: usize) -> io::Result<()> {
+        if len > self.max_decompressed_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Frame decompresses to more than the max decompressed length ({} bytes)",
+                    self.max_decompressed_length
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+const RAW: u8 = 0;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 2;
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+impl<C: Codec> Codec for Compressed<C> {
+    fn serialize<T: Serialize>(&self, item: &T) -> io::Result<Vec<u8>> {
+        let frame = self.codec.serialize(item)?;
+        if frame.len() < self.threshold {
+            let mut raw = Vec::with_capacity(frame.len() + 1);
+            raw.push(RAW);
+            raw.extend_from_slice(&frame);
+            return Ok(raw);
+        }
+        let (tag, compressed) = match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => (ZSTD, zstd::encode_all(&frame[..], level)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => (LZ4, lz4::block::compress(&frame, None, true)?),
+        };
+        let mut tagged = Vec::with_capacity(compressed.len() + 1);
+        tagged.push(tag);
+        tagged.extend_from_slice(&compressed);
+        Ok(tagged)
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        let (&tag, body) = frame.split_first().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Empty frame")
+        })?;
+        match tag {
+            RAW => self.codec.deserialize(body),
+            #[cfg(feature = "zstd")]
+            ZSTD => {
+                // Reads one byte past the limit, to tell a frame at the limit from one beyond it.
+                let mut decompressed = vec![];
+                zstd::stream::Decoder::new(body)?
+                    .take(self.max_decompressed_length as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                self.check_decompressed_length(decompressed.len())?;
+                self.codec.deserialize(&decompressed)
+            }
+            #[cfg(feature = "lz4")]
+            LZ4 => {
+                // The frame starts with its decompressed length, as a little-endian i32, which
+                // lz4 allocates up front.
+                if body.len() < 4 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "LZ4 frame is missing its length",
+                    ));
+                }
+                let len = body[..4]
+                    .iter()
+                    .rev()
+                    .fold(0u32, |len, &byte| len << 8 | u32::from(byte));
+                self.check_decompressed_length(len as usize)?;
+                self.codec.deserialize(&lz4::block::decompress(body, None)?)
+            }
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame compressed with unknown or disabled algorithm {}", tag),
+            )),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "lz4")))]
+mod tests {
+    use super::{Bincode, Codec, Compressed, Compression};
+    use std::io;
+
+    fn compressions() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 3 },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ]
+    }
+
+    #[test]
+    fn small_frames_are_raw() {
+        for compression in compressions() {
+            let codec = Compressed::new(Bincode::default(), compression, 1024);
+            let frame = codec.serialize(&"hello".to_string()).unwrap();
+            assert_eq!(&frame[1..], &Bincode::default().serialize(&"hello".to_string()).unwrap()[..]);
+            assert_eq!(codec.deserialize::<String>(&frame).unwrap(), "hello");
+        }
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        let blob = vec![7u8; 100_000];
+        for compression in compressions() {
+            let codec = Compressed::new(Bincode::default(), compression, 1024);
+            let frame = codec.serialize(&blob).unwrap();
+            assert!(frame.len() < blob.len() / 10);
+            assert_eq!(codec.deserialize::<Vec<u8>>(&frame).unwrap(), blob);
+        }
+    }
+
+    #[test]
+    fn decompression_bombs_are_rejected() {
+        let blob = vec![0u8; 10_000_000];
+        for compression in compressions() {
+            let codec = Compressed::new(Bincode::default(), compression, 1024)
+                .max_decompressed_length(blob.len() + 8);
+            let frame = codec.serialize(&blob).unwrap();
+            assert!(frame.len() < 100_000);
+
+            let codec = codec.max_decompressed_length(1_000_000);
+            let e = codec.deserialize::<Vec<u8>>(&frame).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Length-delimited framing that skips frames exceeding the max frame length, rather than failing
+//! the stream.
+
+use bytes::{Bytes, BytesMut};
+use std::{cmp, error::Error, fmt, io};
+use tokio::codec::{length_delimited, Decoder, Encoder, LengthDelimitedCodec};
+
+/// The number of bytes kept from the start of an oversize frame, enough to hold the header of any
+/// message.
+const START_LENGTH: usize = 256;
+
+/// Frames are prefixed with their length, as a big-endian integer.
+pub(crate) struct FrameCodec {
+    encoder: LengthDelimitedCodec,
+    max_frame_length: usize,
+    length_field_length: usize,
+    /// The number of bytes of a skipped frame that have yet to be discarded.
+    discarding: usize,
+}
+
+impl FrameCodec {
+    pub(crate) fn new(max_frame_length: usize, length_field_length: usize) -> Self {
+        FrameCodec {
+            encoder: length_delimited::Builder::new()
+                .max_frame_length(max_frame_length)
+                .length_field_length(length_field_length)
+                .new_codec(),
+            max_frame_length,
+            length_field_length,
+            discarding: 0,
+        }
+    }
+}
+
+/// The error for a frame that was skipped because it exceeds the max frame length.
+#[derive(Debug)]
+pub(crate) struct OversizeFrame {
+    /// The first bytes of the frame.
+    pub(crate) start: Bytes,
+    length: u64,
+    max_frame_length: usize,
+}
+
+impl fmt::Display for OversizeFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Frame of {} bytes exceeds the max frame length of {} bytes",
+            self.length, self.max_frame_length
+        )
+    }
+}
+
+impl Error for OversizeFrame {}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    /// Fails with an [`OversizeFrame`] error once the start of an oversize frame is read. The rest
+    /// of the frame is discarded as it arrives, after which decoding resumes with the next frame.
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if self.discarding > 0 {
+            let discarded = cmp::min(self.discarding, src.len());
+            src.split_to(discarded);
+            self.discarding -= discarded;
+            if self.discarding > 0 {
+                return Ok(None);
+            }
+        }
+        if src.len() < self.length_field_length {
+            return Ok(None);
+        }
+        let length = src[..self.length_field_length]
+            .iter()
+            .fold(0u64, |length, &byte| length << 8 | u64::from(byte));
+        if length > self.max_frame_length as u64 {
+            let start_length = cmp::min(length, START_LENGTH as u64) as usize;
+            if src.len() < self.length_field_length + start_length {
+                return Ok(None);
+            }
+            src.split_to(self.length_field_length);
+            let start = src.split_to(start_length).freeze();
+            self.discarding = (length - start_length as u64) as usize;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                OversizeFrame {
+                    start,
+                    length,
+                    max_frame_length: self.max_frame_length,
+                },
+            ));
+        }
+        let frame_end = self.length_field_length + length as usize;
+        if src.len() < frame_end {
+            src.reserve(frame_end - src.len());
+            return Ok(None);
+        }
+        src.split_to(self.length_field_length);
+        Ok(Some(src.split_to(length as usize)))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.encoder.encode(frame, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameCodec, OversizeFrame};
+    use bytes::BytesMut;
+    use std::io;
+    use tokio::codec::Decoder;
+
+    fn frame(len: usize, byte: u8) -> Vec<u8> {
+        let mut frame: Vec<u8> = (0..4).rev().map(|i| (len >> (8 * i)) as u8).collect();
+        frame.extend(vec![byte; len]);
+        frame
+    }
+
+    #[test]
+    fn skips_oversize_frames() {
+        let mut codec = FrameCodec::new(4, 4);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&frame(3, 1));
+        src.extend_from_slice(&frame(10, 2));
+        src.extend_from_slice(&frame(4, 3));
+
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], &[1; 3]);
+        let e = codec.decode(&mut src).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let oversize = e.get_ref().unwrap().downcast_ref::<OversizeFrame>().unwrap();
+        assert_eq!(&oversize.start[..], &[2; 10]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], &[3; 4]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn discards_oversize_frames_as_they_arrive() {
+        let mut codec = FrameCodec::new(4, 4);
+        let oversize = frame(1000, 2);
+        let mut src = BytesMut::new();
+
+        // The error waits for the start of the frame.
+        src.extend_from_slice(&oversize[..100]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&oversize[100..500]);
+        let e = codec.decode(&mut src).unwrap_err();
+        let oversize_frame = e.get_ref().unwrap().downcast_ref::<OversizeFrame>().unwrap();
+        assert_eq!(oversize_frame.start.len(), 256);
+
+        // The rest is discarded without being buffered.
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.is_empty());
+        src.extend_from_slice(&oversize[500..]);
+        src.extend_from_slice(&frame(1, 3));
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], &[3]);
+    }
+}
@@ -4,7 +4,12 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-//! A TCP [`Transport`] that serializes as bincode.
+//! A [`Transport`] that serializes as bincode, over TCP or Unix domain sockets.
+//!
+//! Messages are sent as length-delimited frames. The format of each frame is determined by the
+//! transport's [`Codec`](codec::Codec), which is [`Bincode`](codec::Bincode) unless a transport
+//! is created with one of the `*_with_codec` functions or a [`Builder`]. Other codecs are enabled
+//! by cargo features; see the [`codec`] module.
 
 #![feature(
     futures_api,
@@ -16,13 +21,23 @@
 )]
 #![deny(missing_docs, missing_debug_implementations)]
 
+mod builder;
+pub mod codec;
+mod frame;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
 mod vendored;
 
 use bytes::{Bytes, BytesMut};
-use crate::vendored::tokio_serde_bincode::{IoErrorWrapper, ReadBincode, WriteBincode};
+use crate::{
+    codec::{Bincode, Codec},
+    vendored::tokio_serde_codec::{IoErrorWrapper, ReadCodec},
+};
 use futures::{
     Poll,
-    compat::{Compat01As03, Future01CompatExt, Stream01CompatExt},
+    compat::{Compat01As03, Stream01CompatExt},
     prelude::*,
     ready, task,
 };
@@ -37,10 +52,14 @@ use futures_legacy::{
     Async as Async01, AsyncSink as AsyncSink01, Sink as Sink01, Stream as Stream01,
 };
 use pin_utils::unsafe_pinned;
+use rpc::transport::{Address, Message, Peer, SkippedMessage};
 use serde::{Deserialize, Serialize};
 use std::{fmt, io, marker::PhantomData, net::SocketAddr, pin::Pin, task::LocalWaker};
-use tokio::codec::{Framed, LengthDelimitedCodec, length_delimited};
-use tokio_tcp::{self, TcpListener, TcpStream};
+use tokio::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::{self, TcpStream};
+
+pub use crate::builder::Builder;
 
 /// Returns a new bincode transport that reads from and writes to `io`.
 pub fn new<Item, SinkItem>(io: TcpStream) -> Transport<Item, SinkItem>
@@ -48,23 +67,22 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let peer_addr = io.peer_addr();
-    let local_addr = io.local_addr();
-    let inner = length_delimited::Builder::new()
-        .max_frame_length(8_000_000)
-        .new_framed(io)
-        .map_err(IoErrorWrapper as _)
-        .sink_map_err(IoErrorWrapper as _)
-        .with(freeze as _);
-    let inner = WriteBincode::new(inner);
-    let inner = ReadBincode::new(inner);
-
-    Transport {
-        inner,
-        staged_item: None,
-        peer_addr,
-        local_addr,
-    }
+    new_with_codec(io, Bincode::default())
+}
+
+/// Returns a new transport that reads from and writes to `io`, serializing messages with `codec`.
+pub fn new_with_codec<Item, SinkItem, C>(
+    io: TcpStream,
+    codec: C,
+) -> Transport<Item, SinkItem, TcpStream, C>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let peer = io.peer_addr().map(|addr| Peer::from(Address::from(addr)));
+    let local_addr = io.local_addr().map(Address::from);
+    Builder::new().codec(codec).framed(io, peer, local_addr)
 }
 
 fn freeze(bytes: BytesMut) -> Result<Bytes, IoErrorWrapper> {
@@ -77,8 +95,21 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let stream = await!(TcpStream::connect(addr).compat())?;
-    Ok(new(stream))
+    await!(connect_with_codec(addr, Bincode::default()))
+}
+
+/// Connects to `addr`, wrapping the connection in a transport that serializes messages with
+/// `codec`.
+pub async fn connect_with_codec<Item, SinkItem, C>(
+    addr: &SocketAddr,
+    codec: C,
+) -> io::Result<Transport<Item, SinkItem, TcpStream, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    await!(Builder::new().codec(codec).connect(addr))
 }
 
 /// Listens on `addr`, wrapping accepted connections in bincode transports.
@@ -87,25 +118,34 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let listener = TcpListener::bind(addr)?;
-    let local_addr = listener.local_addr()?;
-    let incoming = listener.incoming().compat();
-    Ok(Incoming {
-        incoming,
-        local_addr,
-        ghost: PhantomData,
-    })
+    listen_with_codec(addr, Bincode::default())
+}
+
+/// Listens on `addr`, wrapping accepted connections in transports that serialize messages with
+/// `codec`.
+pub fn listen_with_codec<Item, SinkItem, C>(
+    addr: &SocketAddr,
+    codec: C,
+) -> io::Result<Incoming<Item, SinkItem, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    Builder::new().codec(codec).listen(addr)
 }
 
-/// A [`TcpListener`] that wraps connections in bincode transports.
+/// A [`TcpListener`](tokio_tcp::TcpListener) that wraps connections in transports, by default
+/// bincode transports.
 #[derive(Debug)]
-pub struct Incoming<Item, SinkItem> {
+pub struct Incoming<Item, SinkItem, C = Bincode> {
     incoming: Compat01As03<tokio_tcp::Incoming>,
     local_addr: SocketAddr,
+    builder: Builder<C>,
     ghost: PhantomData<(Item, SinkItem)>,
 }
 
-impl<Item, SinkItem> Incoming<Item, SinkItem> {
+impl<Item, SinkItem, C> Incoming<Item, SinkItem, C> {
     unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
 
     /// Returns the address being listened on.
@@ -114,81 +154,116 @@ impl<Item, SinkItem> Incoming<Item, SinkItem> {
     }
 }
 
-impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+impl<Item, SinkItem, C> Stream for Incoming<Item, SinkItem, C>
 where
     Item: for<'a> Deserialize<'a>,
     SinkItem: Serialize,
+    C: Codec,
 {
-    type Item = io::Result<Transport<Item, SinkItem>>;
+    type Item = io::Result<Transport<Item, SinkItem, TcpStream, C>>;
 
     fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<Self::Item>> {
         let next = ready!(self.incoming().poll_next(waker)?);
-        Poll::Ready(next.map(|conn| Ok(new(conn))))
+        Poll::Ready(next.map(|conn| self.builder.transport(conn)))
     }
 }
 
-/// A transport that serializes to, and deserializes from, a [`TcpStream`].
-pub struct Transport<Item, SinkItem> {
-    inner: ReadBincode<
-        WriteBincode<
-            With01<
-                SinkMapErr01<
-                    MapErr01<
-                        Framed<tokio_tcp::TcpStream, LengthDelimitedCodec>,
-                        fn(std::io::Error) -> IoErrorWrapper,
-                    >,
+/// A transport that serializes to, and deserializes from, a byte stream, by default a
+/// [`TcpStream`], using a [`Codec`], by default [`Bincode`].
+pub struct Transport<Item, SinkItem, S = TcpStream, C = Bincode> {
+    inner: ReadCodec<
+        With01<
+            SinkMapErr01<
+                MapErr01<
+                    Framed<S, frame::FrameCodec>,
                     fn(std::io::Error) -> IoErrorWrapper,
                 >,
-                BytesMut,
-                fn(BytesMut) -> Result<Bytes, IoErrorWrapper>,
-                Result<Bytes, IoErrorWrapper>
+                fn(std::io::Error) -> IoErrorWrapper,
             >,
-            SinkItem,
+            BytesMut,
+            fn(BytesMut) -> Result<Bytes, IoErrorWrapper>,
+            Result<Bytes, IoErrorWrapper>
         >,
         Item,
+        C,
     >,
-    staged_item: Option<SinkItem>,
-    peer_addr: io::Result<SocketAddr>,
-    local_addr: io::Result<SocketAddr>,
+    /// Serializes items written to the transport. Items are serialized as soon as they're sent,
+    /// so that an item that can't be sent fails without affecting the rest of the transport.
+    codec: C,
+    max_frame_length: usize,
+    staged_frame: Option<BytesMut>,
+    peer: io::Result<Peer>,
+    local_addr: io::Result<Address>,
+    ghost: PhantomData<SinkItem>,
 }
 
-impl<Item, SinkItem> fmt::Debug for Transport<Item, SinkItem> {
+impl<Item, SinkItem, S, C> fmt::Debug for Transport<Item, SinkItem, S, C> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Transport")
     }
 }
 
-impl<Item, SinkItem> Stream for Transport<Item, SinkItem>
+impl<Item, SinkItem, S, C> Stream for Transport<Item, SinkItem, S, C>
 where
-    Item: for<'a> Deserialize<'a>,
+    S: AsyncRead + AsyncWrite,
+    Item: for<'a> Deserialize<'a> + Message,
+    Item::Header: for<'a> Deserialize<'a>,
+    C: Codec,
 {
     type Item = io::Result<Item>;
 
+    /// Yields a [`SkippedMessage`] error for a frame that exceeds the max frame length, and
+    /// carries on reading the frames after it.
     fn poll_next(self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<Item>>> {
         unsafe {
-            let inner = &mut Pin::get_mut_unchecked(self).inner;
-            let mut compat = inner.compat();
+            let me = Pin::get_mut_unchecked(self);
+            let mut compat = (&mut me.inner).compat();
             let compat = Pin::new_unchecked(&mut compat);
             match ready!(compat.poll_next(waker)) {
                 None => Poll::Ready(None),
                 Some(Ok(next)) => Poll::Ready(Some(Ok(next))),
-                Some(Err(e)) => Poll::Ready(Some(Err(e.0))),
+                Some(Err(e)) => Poll::Ready(Some(Err(skipped::<Item, _>(&me.codec, e.0)))),
             }
         }
     }
 }
 
-impl<Item, SinkItem> Sink for Transport<Item, SinkItem>
+/// Converts an error for an oversize frame into a [`SkippedMessage`], reading the message's header
+/// from the start of the frame if possible.
+fn skipped<Item, C>(codec: &C, e: io::Error) -> io::Error
+where
+    Item: Message,
+    Item::Header: for<'a> Deserialize<'a>,
+    C: Codec,
+{
+    let skipped = e
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<frame::OversizeFrame>())
+        .map(|oversize| {
+            SkippedMessage::<Item::Header>::new(
+                codec.deserialize(&oversize.start).ok(),
+                oversize.to_string(),
+            )
+        });
+    skipped.map(io::Error::from).unwrap_or(e)
+}
+
+impl<Item, SinkItem, S, C> Sink for Transport<Item, SinkItem, S, C>
 where
+    S: AsyncRead + AsyncWrite,
     SinkItem: Serialize,
+    C: Codec,
 {
     type SinkItem = SinkItem;
     type SinkError = io::Error;
 
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput), without writing anything, if
+    /// `item` can't be serialized or its frame exceeds the max frame length.
     fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
         let me = unsafe { Pin::get_mut_unchecked(self) };
-        assert!(me.staged_item.is_none());
-        me.staged_item = Some(item);
+        assert!(me.staged_frame.is_none());
+        let frame = me.frame(&item)?;
+        me.staged_frame = Some(frame.into());
         Ok(())
     }
 
@@ -197,11 +272,11 @@ where
 
         executor01::with_notify(notify, 0, move || {
             let me = unsafe { Pin::get_mut_unchecked(self) };
-            match me.staged_item.take() {
-                Some(staged_item) => match me.inner.start_send(staged_item)? {
+            match me.staged_frame.take() {
+                Some(staged_frame) => match me.inner.start_send(staged_frame)? {
                     AsyncSink01::Ready => Poll::Ready(Ok(())),
-                    AsyncSink01::NotReady(item) => {
-                        me.staged_item = Some(item);
+                    AsyncSink01::NotReady(frame) => {
+                        me.staged_frame = Some(frame);
                         Poll::Pending
                     }
                 },
@@ -235,22 +310,61 @@ where
     }
 }
 
-impl<Item, SinkItem> rpc::Transport for Transport<Item, SinkItem>
+impl<Item, SinkItem, S, C> Transport<Item, SinkItem, S, C>
 where
-    Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
+    C: Codec,
+{
+    /// Serializes `item` into a frame, failing with [`InvalidInput`](io::ErrorKind::InvalidInput)
+    /// if it can't be serialized or the frame exceeds the max frame length.
+    fn frame(&self, item: &SinkItem) -> io::Result<Vec<u8>> {
+        let frame = self.codec.serialize(item).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => e,
+            _ => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+        })?;
+        if frame.len() > self.max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame of {} bytes exceeds the max frame length of {} bytes",
+                    frame.len(),
+                    self.max_frame_length
+                ),
+            ));
+        }
+        Ok(frame)
+    }
+}
+
+impl<Item, SinkItem, S, C> rpc::Transport for Transport<Item, SinkItem, S, C>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de> + Message,
+    Item::Header: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
 {
     type Item = Item;
     type SinkItem = SinkItem;
 
-    fn peer_addr(&self) -> io::Result<SocketAddr> {
+    fn peer_addr(&self) -> io::Result<Address> {
         // TODO: should just access from the inner transport.
         // https://github.com/alexcrichton/tokio-serde-bincode/issues/4
-        Ok(*self.peer_addr.as_ref().unwrap())
+        Ok(self.peer.as_ref().unwrap().addr.clone())
+    }
+
+    fn peer(&self) -> io::Result<Peer> {
+        Ok(self.peer.as_ref().unwrap().clone())
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        Ok(self.local_addr.as_ref().unwrap().clone())
     }
 
-    fn local_addr(&self) -> io::Result<SocketAddr> {
-        Ok(*self.local_addr.as_ref().unwrap())
+    /// Serializes `item` to check it, so it's only worth calling where items are queued to be
+    /// sent later, as they are by the halves of a duplex transport.
+    fn check(&self, item: &SinkItem) -> io::Result<()> {
+        self.frame(item).map(|_| ())
     }
 }
 
@@ -0,0 +1,337 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Bincode transports secured with TLS, using [rustls](https://docs.rs/rustls).
+//!
+//! Servers present a certificate chain that clients verify against their trusted root
+//! certificates. Servers can additionally require clients to present a certificate chain signed
+//! by one of the server's trusted roots (mutual TLS), in which case request handlers can identify
+//! clients by their verified certificates; see [`Transport::peer_certificates`].
+
+use futures::{
+    Poll,
+    compat::{Compat01As03, Future01CompatExt, Stream01CompatExt},
+    prelude::*,
+    stream::FuturesUnordered,
+};
+use crate::{
+    codec::{Bincode, Codec},
+    Builder,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::transport::{Address, Identity, Message, Peer};
+use rustls::{
+    AllowAnyAuthenticatedClient, ClientConfig, NoClientAuth, ServerConfig, Session,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, marker::PhantomData, net::SocketAddr, pin::Pin, sync::Arc, task::LocalWaker};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{Accept, TlsAcceptor, TlsConnector};
+use tokio_tcp::{self, TcpListener, TcpStream};
+use webpki::DNSNameRef;
+
+pub use rustls::{Certificate, PrivateKey, RootCertStore};
+
+/// A transport, by default a bincode transport, over a TLS connection initiated by this side.
+pub type ClientTransport<Item, SinkItem, C = Bincode> =
+    Transport<Item, SinkItem, tokio_rustls::client::TlsStream<TcpStream>, C>;
+
+/// A transport, by default a bincode transport, over a TLS connection accepted by this side.
+pub type ServerTransport<Item, SinkItem, C = Bincode> =
+    Transport<Item, SinkItem, tokio_rustls::server::TlsStream<TcpStream>, C>;
+
+/// Returns a config for servers that present `cert_chain`, whose end-entity certificate is first
+/// and matches `key`. If `client_roots` is set, the server requires clients to present a
+/// certificate chain signed by one of `client_roots`.
+pub fn server_config(
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+    client_roots: Option<RootCertStore>,
+) -> io::Result<Arc<ServerConfig>> {
+    let client_verifier = match client_roots {
+        Some(client_roots) => AllowAnyAuthenticatedClient::new(client_roots),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(client_verifier);
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// Returns a config for clients that trust servers presenting a certificate chain signed by one
+/// of `roots`. If `identity` is set, the client presents its certificate chain and key to servers
+/// that require client authentication.
+pub fn client_config(
+    roots: RootCertStore,
+    identity: Option<(Vec<Certificate>, PrivateKey)>,
+) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = roots;
+    if let Some((cert_chain, key)) = identity {
+        config.set_single_client_cert(cert_chain, key);
+    }
+    Arc::new(config)
+}
+
+/// Connects to `addr` and performs a TLS handshake, verifying that the server's certificate is
+/// valid for `domain`, and wraps the connection in a bincode transport.
+pub async fn connect<'a, Item, SinkItem>(
+    addr: &'a SocketAddr,
+    domain: &'a str,
+    config: Arc<ClientConfig>,
+) -> io::Result<ClientTransport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(connect_with_builder(addr, domain, config, &Builder::new()))
+}
+
+/// Like [`connect`], but configures the connection and its transport with `builder`.
+pub async fn connect_with_builder<'a, Item, SinkItem, C>(
+    addr: &'a SocketAddr,
+    domain: &'a str,
+    config: Arc<ClientConfig>,
+    builder: &'a Builder<C>,
+) -> io::Result<ClientTransport<Item, SinkItem, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let dns_name = DNSNameRef::try_from_ascii_str(domain).map_err(|()| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid DNS name", domain),
+        )
+    })?;
+    let stream = await!(TcpStream::connect(addr).compat())?;
+    builder.configure(&stream)?;
+    let peer_addr = stream.peer_addr().map(Address::from);
+    let local_addr = stream.local_addr().map(Address::from);
+    let stream = await!(TlsConnector::from(config).connect(dns_name, stream).compat())?;
+    let peer_certificates = stream.get_ref().1.get_peer_certificates();
+    let peer = peer_addr.map(|addr| identified_peer(addr, &peer_certificates));
+    Ok(Transport {
+        inner: builder.framed(stream, peer, local_addr),
+        peer_certificates,
+    })
+}
+
+/// Returns the peer at `addr`, identified by its end-entity certificate, if it presented one.
+fn identified_peer(addr: Address, peer_certificates: &Option<Vec<Certificate>>) -> Peer {
+    let identity = peer_certificates
+        .as_ref()
+        .and_then(|certificates| certificates.first())
+        .map(|certificate| Identity::Certificate(certificate.0.clone()));
+    Peer::new(addr, identity)
+}
+
+/// Listens on `addr`, performing a TLS handshake with each accepted connection and wrapping it in
+/// a bincode transport.
+pub fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    config: Arc<ServerConfig>,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    listen_with_builder(addr, config, &Builder::new())
+}
+
+/// Like [`listen`], but configures accepted connections and their transports with `builder`.
+pub fn listen_with_builder<Item, SinkItem, C>(
+    addr: &SocketAddr,
+    config: Arc<ServerConfig>,
+    builder: &Builder<C>,
+) -> io::Result<Incoming<Item, SinkItem, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        listener_closed: false,
+        acceptor: TlsAcceptor::from(config),
+        handshakes: FuturesUnordered::new(),
+        local_addr,
+        builder: builder.clone(),
+        ghost: PhantomData,
+    })
+}
+
+/// A [`TcpListener`] that performs TLS handshakes with accepted connections and wraps them in
+/// transports, by default bincode transports. Handshakes proceed concurrently, so a slow client
+/// doesn't hold up others.
+pub struct Incoming<Item, SinkItem, C = Bincode> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    listener_closed: bool,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Compat01As03<Accept<TcpStream>>>,
+    local_addr: SocketAddr,
+    builder: Builder<C>,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem, C> fmt::Debug for Incoming<Item, SinkItem, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Incoming({})", self.local_addr)
+    }
+}
+
+impl<Item, SinkItem, C> Incoming<Item, SinkItem, C> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+    unsafe_unpinned!(listener_closed: bool);
+    unsafe_unpinned!(handshakes: FuturesUnordered<Compat01As03<Accept<TcpStream>>>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl<Item, SinkItem, C> Stream for Incoming<Item, SinkItem, C>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    type Item = io::Result<ServerTransport<Item, SinkItem, C>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<Self::Item>> {
+        while !self.listener_closed {
+            match self.incoming().poll_next(waker) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if let Err(e) = self.builder.configure(&conn) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    let handshake = self.acceptor.accept(conn).compat();
+                    self.handshakes().push(handshake);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => *self.listener_closed() = true,
+                Poll::Pending => break,
+            }
+        }
+
+        match self.handshakes().poll_next_unpin(waker) {
+            Poll::Ready(Some(Ok(stream))) => {
+                let (conn, session) = stream.get_ref();
+                let peer_addr = conn.peer_addr().map(Address::from);
+                let local_addr = conn.local_addr().map(Address::from);
+                let peer_certificates = session.get_peer_certificates();
+                let peer = peer_addr.map(|addr| identified_peer(addr, &peer_certificates));
+                Poll::Ready(Some(Ok(Transport {
+                    inner: self.builder.framed(stream, peer, local_addr),
+                    peer_certificates,
+                })))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            // No handshakes are in progress.
+            Poll::Ready(None) if self.listener_closed => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A transport that serializes to, and deserializes from, a TLS stream, using a [`Codec`], by
+/// default [`Bincode`].
+pub struct Transport<Item, SinkItem, S, C = Bincode> {
+    inner: crate::Transport<Item, SinkItem, S, C>,
+    peer_certificates: Option<Vec<Certificate>>,
+}
+
+impl<Item, SinkItem, S, C> Transport<Item, SinkItem, S, C> {
+    unsafe_pinned!(inner: crate::Transport<Item, SinkItem, S, C>);
+
+    /// Returns the certificate chain presented by the peer, end-entity certificate first, which
+    /// was verified during the TLS handshake. Returns `None` if the peer presented no
+    /// certificates, which, on the server side, only happens when the server does not require
+    /// client authentication.
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        self.peer_certificates.as_ref().map(Vec::as_slice)
+    }
+}
+
+impl<Item, SinkItem, S, C> fmt::Debug for Transport<Item, SinkItem, S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tls::Transport")
+    }
+}
+
+impl<Item, SinkItem, S, C> Stream for Transport<Item, SinkItem, S, C>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'a> Deserialize<'a> + Message,
+    Item::Header: for<'a> Deserialize<'a>,
+    C: Codec,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<Item>>> {
+        self.inner().poll_next(waker)
+    }
+}
+
+impl<Item, SinkItem, S, C> Sink for Transport<Item, SinkItem, S, C>
+where
+    S: AsyncRead + AsyncWrite,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    type SinkItem = SinkItem;
+    type SinkError = io::Error;
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.inner().start_send(item)
+    }
+
+    fn poll_ready(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(waker)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(waker)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
+        self.inner().poll_close(waker)
+    }
+}
+
+impl<Item, SinkItem, S, C> rpc::Transport for Transport<Item, SinkItem, S, C>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de> + Message,
+    Item::Header: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<Address> {
+        rpc::Transport::peer_addr(&self.inner)
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        rpc::Transport::local_addr(&self.inner)
+    }
+
+    fn peer(&self) -> io::Result<Peer> {
+        rpc::Transport::peer(&self.inner)
+    }
+
+    fn check(&self, item: &SinkItem) -> io::Result<()> {
+        rpc::Transport::check(&self.inner, item)
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Bincode transports over Unix domain sockets, for communication between processes on the same
+//! host.
+
+use crate::{
+    codec::{Bincode, Codec},
+    Builder,
+};
+use futures::{
+    Poll,
+    compat::{Compat01As03, Future01CompatExt, Stream01CompatExt},
+    prelude::*,
+    ready,
+};
+use pin_utils::unsafe_pinned;
+use rpc::transport::{Address, Identity, Peer};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io,
+    marker::PhantomData,
+    os::unix::net::SocketAddr as UnixSocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::LocalWaker,
+};
+use tokio_uds::{self, UnixListener, UnixStream};
+
+/// A transport, by default a bincode transport, over a Unix domain socket.
+pub type Transport<Item, SinkItem, C = Bincode> = crate::Transport<Item, SinkItem, UnixStream, C>;
+
+/// Returns a new bincode transport that reads from and writes to `io`.
+pub fn new<Item, SinkItem>(io: UnixStream) -> Transport<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    new_with_builder(io, &Builder::new())
+}
+
+/// Returns a new transport, configured by `builder`, that reads from and writes to `io`.
+pub fn new_with_builder<Item, SinkItem, C>(
+    io: UnixStream,
+    builder: &Builder<C>,
+) -> Transport<Item, SinkItem, C>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let identity = io
+        .peer_cred()
+        .ok()
+        .map(|cred| Identity::Unix {
+            uid: cred.uid,
+            gid: cred.gid,
+        });
+    let peer = io.peer_addr().map(|addr| Peer::new(address(addr), identity));
+    let local_addr = io.local_addr().map(address);
+    builder.framed(io, peer, local_addr)
+}
+
+fn address(addr: UnixSocketAddr) -> Address {
+    Address::Unix(addr.as_pathname().map(Path::to_path_buf))
+}
+
+/// Connects to the socket at `path`, wrapping the connection in a bincode transport.
+pub async fn connect<Item, SinkItem>(path: impl AsRef<Path>) -> io::Result<Transport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(connect_with_builder(path, &Builder::new()))
+}
+
+/// Like [`connect`], but configures the transport with `builder`.
+pub async fn connect_with_builder<'a, Item, SinkItem, C>(
+    path: impl AsRef<Path> + 'a,
+    builder: &'a Builder<C>,
+) -> io::Result<Transport<Item, SinkItem, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let stream = await!(UnixStream::connect(path).compat())?;
+    Ok(new_with_builder(stream, builder))
+}
+
+/// Listens on a new socket at `path`, wrapping accepted connections in bincode transports. The
+/// socket file is removed when the returned [`Incoming`] is dropped.
+pub fn listen<Item, SinkItem>(path: impl AsRef<Path>) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    listen_with_builder(path, &Builder::new())
+}
+
+/// Like [`listen`], but configures accepted connections' transports with `builder`.
+pub fn listen_with_builder<Item, SinkItem, C>(
+    path: impl AsRef<Path>,
+    builder: &Builder<C>,
+) -> io::Result<Incoming<Item, SinkItem, C>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    let path = path.as_ref().to_path_buf();
+    let listener = UnixListener::bind(&path)?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        path,
+        builder: builder.clone(),
+        ghost: PhantomData,
+    })
+}
+
+/// A [`UnixListener`] that wraps connections in transports, by default bincode transports.
+/// Removes the socket file when dropped.
+#[derive(Debug)]
+pub struct Incoming<Item, SinkItem, C = Bincode> {
+    incoming: Compat01As03<tokio_uds::Incoming>,
+    path: PathBuf,
+    builder: Builder<C>,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem, C> Incoming<Item, SinkItem, C> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_uds::Incoming>);
+
+    /// Returns the path of the socket being listened on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<Item, SinkItem, C> Drop for Incoming<Item, SinkItem, C> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl<Item, SinkItem, C> Stream for Incoming<Item, SinkItem, C>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    type Item = io::Result<Transport<Item, SinkItem, C>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<Self::Item>> {
+        let next = ready!(self.incoming().poll_next(waker)?);
+        Poll::Ready(next.map(|conn| Ok(new_with_builder(conn, &self.builder))))
+    }
+}
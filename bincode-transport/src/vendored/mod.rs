@@ -4,4 +4,4 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-pub(crate) mod tokio_serde_bincode;
+pub(crate) mod tokio_serde_codec;
@@ -0,0 +1,126 @@
+//! A `Stream` adaptor for deserializing values using a [`Codec`].
+//!
+//! This module provides an adaptor for going from a stream of buffers
+//! ([`Bytes`]) to a stream of values by decoding them with a codec. It is
+//! expected that each yielded buffer contains a single serialized value. The
+//! specific strategy by which this is done is left up to the user. One option
+//! is to use using [`length_delimited`] from [tokio-io].
+//!
+//! [`Bytes`]: https://docs.rs/bytes/0.4/bytes/struct.Bytes.html
+//! [`length_delimited`]: http://alexcrichton.com/tokio-io/tokio_io/codec/length_delimited/index.html
+//! [tokio-io]: http://github.com/alexcrichton/tokio-io
+
+#![allow(missing_debug_implementations)]
+
+use bytes::Bytes;
+use futures_legacy::{Poll, Sink, StartSend, Stream};
+use crate::codec::Codec;
+use serde::Deserialize;
+use std::io;
+use tokio_serde::{Deserializer, FramedRead};
+
+use std::marker::PhantomData;
+
+/// Adapts a stream of encoded buffers to a stream of values by deserializing
+/// them.
+///
+/// `ReadCodec` implements `Stream` by polling the inner buffer stream and
+/// deserializing the buffer with the codec. It expects that each yielded buffer
+/// represents a single value and does not contain any extra trailing bytes.
+pub(crate) struct ReadCodec<T, U, C> {
+    inner: FramedRead<T, U, Serde<U, C>>,
+}
+
+struct Serde<T, C> {
+    codec: C,
+    ghost: PhantomData<T>,
+}
+
+impl<T, U, C> ReadCodec<T, U, C>
+where
+    T: Stream<Error = IoErrorWrapper>,
+    U: for<'de> Deserialize<'de>,
+    C: Codec,
+    Bytes: From<T::Item>,
+{
+    /// Creates a new `ReadCodec` with the given buffer stream.
+    pub fn new(inner: T, codec: C) -> ReadCodec<T, U, C> {
+        let serde = Serde { codec, ghost: PhantomData };
+        ReadCodec {
+            inner: FramedRead::new(inner, serde),
+        }
+    }
+}
+
+impl<T, U, C> ReadCodec<T, U, C> {
+    /// Returns a mutable reference to the underlying stream wrapped by
+    /// `ReadCodec`.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data coming in as it may corrupt the stream of frames otherwise
+    /// being worked with.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T, U, C> Stream for ReadCodec<T, U, C>
+where
+    T: Stream<Error = IoErrorWrapper>,
+    U: for<'de> Deserialize<'de>,
+    C: Codec,
+    Bytes: From<T::Item>,
+{
+    type Item = U;
+    type Error = <T as Stream>::Error;
+
+    fn poll(&mut self) -> Poll<Option<U>, Self::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<T, U, C> Sink for ReadCodec<T, U, C>
+where
+    T: Sink,
+{
+    type SinkItem = T::SinkItem;
+    type SinkError = T::SinkError;
+
+    fn start_send(&mut self, item: T::SinkItem) -> StartSend<T::SinkItem, T::SinkError> {
+        self.get_mut().start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), T::SinkError> {
+        self.get_mut().poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), T::SinkError> {
+        self.get_mut().close()
+    }
+}
+
+pub(crate) struct IoErrorWrapper(pub io::Error);
+
+impl From<io::Error> for IoErrorWrapper {
+    fn from(e: io::Error) -> Self {
+        IoErrorWrapper(e)
+    }
+}
+
+impl From<IoErrorWrapper> for io::Error {
+    fn from(wrapper: IoErrorWrapper) -> io::Error {
+        wrapper.0
+    }
+}
+
+impl<T, C> Deserializer<T> for Serde<T, C>
+where
+    T: for<'de> Deserialize<'de>,
+    C: Codec,
+{
+    type Error = io::Error;
+
+    fn deserialize(&mut self, src: &Bytes) -> io::Result<T> {
+        self.codec.deserialize(src)
+    }
+}
@@ -49,7 +49,7 @@ async fn run() -> io::Result<()> {
             } else {
                 return;
             };
-            let client_addr = *channel.client_addr();
+            let client_addr = channel.client_addr().clone();
             let handler = channel.respond_with(move |ctx, request| {
                 // Sleep for a time sampled from a normal distribution with:
                 // - mean: 1/2 the deadline.
@@ -99,7 +99,7 @@ async fn run() -> io::Result<()> {
                 } else {
                     return;
                 };
-                let client_addr = *channel.client_addr();
+                let client_addr = channel.client_addr().clone();
                 let handler = channel.respond_with(move |ctx, request| {
                     trace!("[{}/{}] Proxying request.", ctx.trace_id(), client_addr);
                     let mut client = client.clone();
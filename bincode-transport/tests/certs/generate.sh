@@ -0,0 +1,33 @@
+#!/bin/sh
+# Regenerates the certificates used by the TLS tests: a CA, a server certificate for localhost
+# and a client certificate signed by the CA, and a client certificate signed by an untrusted CA.
+set -e
+cd "$(dirname "$0")"
+
+days=10000
+
+ca() {
+    openssl req -x509 -newkey rsa:2048 -nodes -sha256 -days $days \
+        -subj "/CN=$2" -keyout $1.key -out $1.pem \
+        -addext "basicConstraints=critical,CA:TRUE" \
+        -addext "keyUsage=critical,keyCertSign,cRLSign"
+}
+
+# leaf <name> <ca> <common name> <extensions>
+leaf() {
+    openssl req -newkey rsa:2048 -nodes -sha256 -subj "/CN=$3" -keyout $1.key -out $1.csr
+    printf "$4" > $1.ext
+    openssl x509 -req -sha256 -days $days -in $1.csr -CA $2.pem -CAkey $2.key \
+        -CAcreateserial -extfile $1.ext -out $1.pem
+    rm $1.csr $1.ext
+}
+
+ca ca "tarpc test CA"
+ca untrusted-ca "tarpc untrusted test CA"
+leaf server ca localhost \
+    "basicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\nsubjectAltName=DNS:localhost\n"
+leaf client ca client \
+    "basicConstraints=CA:FALSE\nextendedKeyUsage=clientAuth\n"
+leaf untrusted-client untrusted-ca untrusted-client \
+    "basicConstraints=CA:FALSE\nextendedKeyUsage=clientAuth\n"
+rm -f *.srl ca.key untrusted-ca.key
@@ -0,0 +1,105 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that oversize messages fail their requests without breaking the connection.
+
+#![feature(await_macro, async_await, futures_api,)]
+
+use bincode_transport::Builder;
+use futures::{compat::TokioDefaultSpawner, prelude::*};
+use rpc::{
+    client::{self, Client},
+    context,
+    server::{self, Handler, Server},
+    Code, ServerError,
+};
+use std::{io, net::SocketAddr};
+
+/// Serves one connection, responding to each request with a string of the requested length.
+fn serve(builder: &Builder) -> io::Result<SocketAddr> {
+    let listener = builder.listen(&"0.0.0.0:0".parse().unwrap())?;
+    let addr = listener.local_addr();
+    let server = Server::<(usize, String), String>::new(server::Config::default())
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, (len, _padding)| future::ready(Ok("x".repeat(len))));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+    Ok(addr)
+}
+
+/// Makes requests for a `response_len` string, padded to `request_len`, and for a short string.
+/// Returns the result of the first request.
+async fn call(
+    server: Builder,
+    client: Builder,
+    response_len: usize,
+    request_len: usize,
+) -> io::Result<String> {
+    let addr = serve(&server)?;
+    let transport = await!(client.connect(&addr))?;
+    let mut client = await!(Client::<(usize, String), String>::new(
+        client::Config::default(),
+        transport
+    ))?;
+
+    let response = await!(client.call(
+        context::current(),
+        (response_len, "x".repeat(request_len))
+    ));
+
+    // The connection survives.
+    let short = await!(client.call(context::current(), (10, String::new())))?;
+    assert_eq!(short, "x".repeat(10));
+
+    response
+}
+
+fn run_test(test: impl Future<Output = io::Result<()>> + Send + 'static) {
+    let _ = env_logger::try_init();
+    rpc::init(TokioDefaultSpawner);
+
+    tokio::run(test.boxed().map_err(|e| panic!(e)).compat());
+}
+
+#[test]
+fn oversize_response() {
+    run_test(
+        async {
+            let builder = Builder::new().max_frame_length(1024).nodelay(true);
+            let response = await!(call(builder.clone(), builder, 10_000, 0));
+            let e = response.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Other);
+            assert_eq!(ServerError::downcast_ref(&e).unwrap().code, Code::Internal);
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn oversize_request_received() {
+    run_test(
+        async {
+            let server = Builder::new().max_frame_length(1024);
+            let client = Builder::new();
+            let response = await!(call(server, client, 10, 10_000));
+            assert_eq!(response.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn oversize_response_received() {
+    run_test(
+        async {
+            let server = Builder::new();
+            let client = Builder::new().max_frame_length(1024);
+            let response = await!(call(server, client, 10_000, 0));
+            assert_eq!(response.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            Ok(())
+        },
+    );
+}
@@ -48,7 +48,7 @@ async fn run() -> io::Result<()> {
             } else {
                 return;
             };
-            let client_addr = *channel.client_addr();
+            let client_addr = channel.client_addr().clone();
             let handler = channel.respond_with(move |ctx, request| {
                 // Sleep for a time sampled from a normal distribution with:
                 // - mean: 1/2 the deadline.
@@ -0,0 +1,134 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests TLS connections, with and without client authentication. The certificates are generated
+//! by `tests/certs/generate.sh`.
+
+#![cfg(feature = "tls")]
+#![feature(await_macro, async_await, futures_api,)]
+
+use bincode_transport::tls::{self, Certificate, PrivateKey, RootCertStore};
+use futures::{compat::TokioDefaultSpawner, prelude::*};
+use rpc::{
+    client::{self, Client},
+    context,
+    server::{self, Server},
+    transport::Identity,
+};
+use rustls::internal::pemfile;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+fn open(file: &str) -> BufReader<File> {
+    let path = format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), file);
+    BufReader::new(File::open(path).unwrap())
+}
+
+fn certs(name: &str) -> Vec<Certificate> {
+    pemfile::certs(&mut open(&format!("{}.pem", name))).unwrap()
+}
+
+fn key(name: &str) -> PrivateKey {
+    pemfile::pkcs8_private_keys(&mut open(&format!("{}.key", name)))
+        .unwrap()
+        .remove(0)
+}
+
+fn roots(name: &str) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add(&certs(name)[0]).unwrap();
+    roots
+}
+
+/// Serves one connection at the returned address, responding to each request with the
+/// certificate that identifies the client, if any.
+fn serve(client_roots: Option<RootCertStore>) -> io::Result<SocketAddr> {
+    let config = tls::server_config(certs("server"), key("server"), client_roots)?;
+    let listener = tls::listen(&"0.0.0.0:0".parse().unwrap(), config)?;
+    let addr = listener.local_addr();
+    let server = Server::<(), Option<Vec<u8>>>::new(server::Config::default())
+        .incoming(listener)
+        .take(1)
+        .for_each(async move |channel| {
+            let channel = match channel {
+                Ok(channel) => channel,
+                Err(_) => return,
+            };
+            let certificate = match &channel.peer().identity {
+                Some(Identity::Certificate(der)) => Some(der.clone()),
+                _ => None,
+            };
+            let handler =
+                channel.respond_with(move |_ctx, ()| future::ready(Ok(certificate.clone())));
+            tokio_executor::spawn(handler.unit_error().boxed().compat());
+        });
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+    Ok(addr)
+}
+
+/// Connects to `addr` and makes a request, returning the client certificate the server saw.
+async fn call(
+    addr: SocketAddr,
+    client_identity: Option<(Vec<Certificate>, PrivateKey)>,
+) -> io::Result<Option<Vec<u8>>> {
+    let config = tls::client_config(roots("ca"), client_identity);
+    let transport = await!(tls::connect(&addr, "localhost", config))?;
+    let mut client = await!(Client::new(client::Config::default(), transport))?;
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(2);
+    await!(client.call(ctx, ()))
+}
+
+fn run_test(test: impl Future<Output = io::Result<()>> + Send + 'static) {
+    let _ = env_logger::try_init();
+    rpc::init(TokioDefaultSpawner);
+
+    tokio::run(test.boxed().map_err(|e| panic!(e)).compat());
+}
+
+#[test]
+fn round_trip() {
+    run_test(
+        async {
+            let addr = serve(None)?;
+            assert_eq!(await!(call(addr, None))?, None);
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn client_certificate() {
+    run_test(
+        async {
+            let addr = serve(Some(roots("ca")))?;
+            let certificate = await!(call(addr, Some((certs("client"), key("client")))))?;
+            assert_eq!(certificate, Some(certs("client")[0].0.clone()));
+            Ok(())
+        },
+    );
+}
+
+#[test]
+fn rejected_client_certificate() {
+    run_test(
+        async {
+            let addr = serve(Some(roots("ca")))?;
+            // Depending on the TLS version, the server rejects the certificate either during the
+            // client's handshake or as soon as the client sends a request.
+            let response = await!(call(
+                addr,
+                Some((certs("untrusted-client"), key("untrusted-client")))
+            ));
+            assert!(response.is_err(), "Unexpected response: {:?}", response);
+            Ok(())
+        },
+    );
+}
@@ -0,0 +1,74 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests connections over Unix domain sockets.
+
+#![cfg(unix)]
+#![feature(await_macro, async_await, futures_api,)]
+
+use bincode_transport::unix;
+use futures::{compat::TokioDefaultSpawner, prelude::*};
+use rpc::{
+    client::{self, Client},
+    context,
+    server::{self, Server},
+    transport::{Address, Identity},
+};
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    process,
+};
+
+async fn run(path: PathBuf) -> io::Result<()> {
+    let listener = unix::listen(&path)?;
+    let server = Server::<(), Option<(u32, u32)>>::new(server::Config::default())
+        .incoming(listener)
+        .take(1)
+        .for_each(async move |channel| {
+            let channel = match channel {
+                Ok(channel) => channel,
+                Err(_) => return,
+            };
+            let credentials = match &channel.peer().identity {
+                Some(Identity::Unix { uid, gid }) => Some((*uid, *gid)),
+                _ => None,
+            };
+            let handler =
+                channel.respond_with(move |_ctx, ()| future::ready(Ok(credentials)));
+            tokio_executor::spawn(handler.unit_error().boxed().compat());
+        });
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let transport = await!(unix::connect(&path))?;
+    assert_eq!(
+        rpc::Transport::peer_addr(&transport)?,
+        Address::Unix(Some(path.clone()))
+    );
+    let mut client = await!(Client::new(client::Config::default(), transport))?;
+
+    // The server identifies the client by the credentials of this process, which also owns the
+    // socket file.
+    let metadata = fs::metadata(&path)?;
+    let credentials = await!(client.call(context::current(), ()))?;
+    assert_eq!(credentials, Some((metadata.uid(), metadata.gid())));
+
+    Ok(())
+}
+
+#[test]
+fn round_trip() {
+    let _ = env_logger::try_init();
+    rpc::init(TokioDefaultSpawner);
+
+    let path = std::env::temp_dir().join(format!("tarpc-unix-test-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    tokio::run(run(path.clone()).boxed().map_err(|e| panic!(e)).compat());
+
+    // Dropping the listener removed the socket file.
+    assert!(!path.exists());
+}
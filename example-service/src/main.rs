@@ -53,7 +53,7 @@ async fn run() -> io::Result<()> {
         .take(1)
         // serve is generated by the service! macro. It takes as input any type implementing
         // the generated Service trait.
-        .respond_with(service::serve(HelloServer));
+        .respond_with_extras(service::serve(HelloServer));
 
     tokio_executor::spawn(server.unit_error().boxed().compat());
 
@@ -0,0 +1,59 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A [`Transport`] that serializes as JSON, over TCP.
+//!
+//! Each message is a single JSON value, prefixed by its length in bytes as a 4-byte big-endian
+//! unsigned integer. This makes traffic easy to inspect with standard tools, and lets clients and
+//! servers written in other languages speak to tarpc services without a bincode implementation.
+//!
+//! This is a [`bincode_transport::Transport`] using the [`Json`] codec.
+
+#![feature(
+    futures_api,
+    await_macro,
+    async_await,
+)]
+#![deny(missing_docs, missing_debug_implementations)]
+
+pub use bincode_transport::codec::Json;
+
+use serde::{Deserialize, Serialize};
+use std::{io, net::SocketAddr};
+use tokio_tcp::TcpStream;
+
+/// A transport that serializes to, and deserializes from, a [`TcpStream`] as JSON.
+pub type Transport<Item, SinkItem> = bincode_transport::Transport<Item, SinkItem, TcpStream, Json>;
+
+/// A [`TcpListener`](tokio_tcp::TcpListener) that wraps connections in JSON transports.
+pub type Incoming<Item, SinkItem> = bincode_transport::Incoming<Item, SinkItem, Json>;
+
+/// Returns a new JSON transport that reads from and writes to `io`.
+pub fn new<Item, SinkItem>(io: TcpStream) -> Transport<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    bincode_transport::new_with_codec(io, Json)
+}
+
+/// Connects to `addr`, wrapping the connection in a JSON transport.
+pub async fn connect<Item, SinkItem>(addr: &SocketAddr) -> io::Result<Transport<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(bincode_transport::connect_with_codec(addr, Json))
+}
+
+/// Listens on `addr`, wrapping accepted connections in JSON transports.
+pub fn listen<Item, SinkItem>(addr: &SocketAddr) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    bincode_transport::listen_with_codec(addr, Json)
+}
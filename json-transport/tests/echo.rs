@@ -0,0 +1,46 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests a client and server communicating over JSON.
+
+#![feature(await_macro, async_await, futures_api,)]
+
+use futures::{compat::TokioDefaultSpawner, prelude::*};
+use rpc::{
+    client::{self, Client},
+    context,
+    server::{self, Handler, Server},
+};
+use std::io;
+
+async fn run() -> io::Result<()> {
+    let listener = json_transport::listen(&"0.0.0.0:0".parse().unwrap())?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::new(server::Config::default())
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, request: String| future::ready(Ok(request.to_uppercase())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let transport = await!(json_transport::connect(&addr))?;
+    let mut client = await!(Client::<String, String>::new(
+        client::Config::default(),
+        transport
+    ))?;
+
+    let response = await!(client.call(context::current(), "ping".into()))?;
+    assert_eq!(response, "PING");
+
+    Ok(())
+}
+
+#[test]
+fn echo() {
+    let _ = env_logger::try_init();
+    rpc::init(TokioDefaultSpawner);
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
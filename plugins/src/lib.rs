@@ -14,16 +14,83 @@ use proc_macro::TokenStream;
 
 use itertools::Itertools;
 use quote::ToTokens;
-use syn::{Ident, TraitItemType, TypePath, parse};
+use syn::{Ident, LitStr, TraitItemType, TypePath, parse};
 use proc_macro2::Span;
 use std::str::FromStr;
 
 #[proc_macro]
 pub fn snake_to_camel(input: TokenStream) -> TokenStream {
+    assoc_type_to_camel(input, "Fut")
+}
+
+/// Like `snake_to_camel`, but for the stream types of streaming rpcs.
+#[proc_macro]
+pub fn snake_to_camel_stream(input: TokenStream) -> TokenStream {
+    assoc_type_to_camel(input, "Stream")
+}
+
+#[proc_macro]
+pub fn ty_snake_to_camel(input: TokenStream) -> TokenStream {
+    ty_to_camel(input, "Fut")
+}
+
+/// Like `ty_snake_to_camel`, but for the stream types of streaming rpcs.
+#[proc_macro]
+pub fn ty_snake_to_camel_stream(input: TokenStream) -> TokenStream {
+    ty_to_camel(input, "Stream")
+}
+
+/// Expands to the `std::time::Duration` written in a string literal, such as `"250ms"` or
+/// `"1m 30s"`. Units are `h`, `m`, `s`, `ms`, `us` and `ns`.
+#[proc_macro]
+pub fn parse_duration(input: TokenStream) -> TokenStream {
+    let i = input.clone();
+    let lit = parse::<LitStr>(input)
+        .unwrap_or_else(|_| panic!("Expected a duration string, not:\n{}", i));
+    let nanos = parse_nanos(&lit.value())
+        .unwrap_or_else(|e| panic!("Invalid duration {:?}: {}", lit.value(), e));
+    TokenStream::from_str(&format!("::std::time::Duration::from_nanos({})", nanos)).unwrap()
+}
+
+/// Parses a duration as a sum of numbers followed by units, returning it in nanoseconds.
+fn parse_nanos(duration: &str) -> Result<u64, String> {
+    let mut nanos = 0u64;
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        return Err("no duration given".into());
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| rest.len());
+        if digits == 0 {
+            return Err(format!("expected a number at {:?}", rest));
+        }
+        let number: u64 = rest[..digits].parse().map_err(|e| format!("{}", e))?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or_else(|| rest.len());
+        let unit_nanos = match &rest[..unit] {
+            "h" => 3_600_000_000_000,
+            "m" => 60_000_000_000,
+            "s" => 1_000_000_000,
+            "ms" => 1_000_000,
+            "us" => 1_000,
+            "ns" => 1,
+            "" => return Err(format!("missing unit after {}", number)),
+            unit => return Err(format!("unknown unit {:?}", unit)),
+        };
+        nanos = number
+            .checked_mul(unit_nanos)
+            .and_then(|n| nanos.checked_add(n))
+            .ok_or_else(|| "duration is too long".to_string())?;
+        rest = rest[unit..].trim_start();
+    }
+    Ok(nanos)
+}
+
+fn assoc_type_to_camel(input: TokenStream, suffix: &str) -> TokenStream {
     let i = input.clone();
     let mut assoc_type = parse::<TraitItemType>(input).unwrap_or_else(|_| panic!("Could not parse trait item from:\n{}", i));
 
-    let old_ident = convert(&mut assoc_type.ident);
+    let old_ident = convert(&mut assoc_type.ident, suffix);
 
     for mut attr in &mut assoc_type.attrs {
         if let Some(pair) = attr.path.segments.first() {
@@ -36,8 +103,7 @@ pub fn snake_to_camel(input: TokenStream) -> TokenStream {
     assoc_type.into_token_stream().into()
 }
 
-#[proc_macro]
-pub fn ty_snake_to_camel(input: TokenStream) -> TokenStream {
+fn ty_to_camel(input: TokenStream, suffix: &str) -> TokenStream {
     let mut path = parse::<TypePath>(input).unwrap();
 
     // Only capitalize the final segment
@@ -46,13 +112,14 @@ pub fn ty_snake_to_camel(input: TokenStream) -> TokenStream {
                      .last_mut()
                      .unwrap()
                      .into_value()
-                     .ident);
+                     .ident,
+            suffix);
 
     path.into_token_stream().into()
 }
 
-/// Converts an ident in-place to CamelCase and returns the previous ident.
-fn convert(ident: &mut Ident) -> String {
+/// Converts an ident in-place to CamelCase, followed by `suffix`, and returns the previous ident.
+fn convert(ident: &mut Ident, suffix: &str) -> String {
     let ident_str = ident.to_string();
     let mut camel_ty = String::new();
 
@@ -83,9 +150,46 @@ fn convert(ident: &mut Ident) -> String {
         }
     }
 
-    // The Fut suffix is hardcoded right now; this macro isn't really meant to be general-purpose.
-    camel_ty.push_str("Fut");
+    // The suffix distinguishes the type from any other type derived from the same ident; these
+    // macros aren't really meant to be general-purpose.
+    camel_ty.push_str(suffix);
 
     *ident = Ident::new(&camel_ty, Span::call_site());
     ident_str
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_nanos;
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_nanos("2h"), Ok(7_200_000_000_000));
+        assert_eq!(parse_nanos("2m"), Ok(120_000_000_000));
+        assert_eq!(parse_nanos("2s"), Ok(2_000_000_000));
+        assert_eq!(parse_nanos("2ms"), Ok(2_000_000));
+        assert_eq!(parse_nanos("2us"), Ok(2_000));
+        assert_eq!(parse_nanos("2ns"), Ok(2));
+        assert_eq!(parse_nanos("1m30s"), Ok(90_000_000_000));
+        assert!(parse_nanos("2d").unwrap_err().contains("unknown unit"));
+        assert!(parse_nanos("2").unwrap_err().contains("missing unit"));
+        assert!(parse_nanos("s").unwrap_err().contains("expected a number"));
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(parse_nanos("  1s 500ms "), Ok(1_500_000_000));
+        assert!(parse_nanos("").is_err());
+        assert!(parse_nanos("   ").is_err());
+        // A unit must follow its number directly.
+        assert!(parse_nanos("1 s").is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(parse_nanos("5124095h"), Ok(5_124_095 * 3_600_000_000_000));
+        assert!(parse_nanos("5124096h").unwrap_err().contains("too long"));
+        assert!(parse_nanos("5124095h 5124095h").unwrap_err().contains("too long"));
+        assert!(parse_nanos("18446744073709551616ns").is_err());
+    }
+}
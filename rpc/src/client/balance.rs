@@ -0,0 +1,561 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a client that spreads requests across a set of servers.
+
+use crate::{
+    client::{dispatch, Client, RetryPolicy},
+    transport::Address,
+};
+use futures::{
+    future::{self, Ready},
+    prelude::*,
+};
+use log::{info, trace, warn};
+use rand::Rng;
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// How a [`Balanced`] client picks the server to send each request to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Cycle through the servers in order.
+    RoundRobin,
+    /// Send to the server with the fewest in-flight requests, i.e. requests that its client's
+    /// dispatch task has written to the wire and that have not yet received responses.
+    LeastInFlight,
+    /// Sample two servers at random, and send to the one with fewer in-flight requests, counted
+    /// the same way as for [`LeastInFlight`](Strategy::LeastInFlight).
+    PowerOfTwoChoices,
+}
+
+/// Provides the addresses of the servers that a [`Balanced`] client sends requests to.
+pub trait Resolver {
+    /// The type of the addresses, e.g. [`Address`], or a name that identifies an in-process
+    /// server.
+    type Addr;
+    /// The type of future returned by `resolve`.
+    type Future: Future<Output = io::Result<Vec<Self::Addr>>>;
+
+    /// Returns the current set of server addresses.
+    fn resolve(&mut self) -> Self::Future;
+}
+
+/// A static list of addresses, which always resolves to itself.
+impl<A: Clone> Resolver for Vec<A> {
+    type Addr = A;
+    type Future = Ready<io::Result<Vec<A>>>;
+
+    fn resolve(&mut self) -> Self::Future {
+        future::ready(Ok(self.clone()))
+    }
+}
+
+/// Spreads requests across several servers, picked according to a [`Strategy`]. Servers whose
+/// client dispatch task has shut down are ejected.
+///
+/// Requests are sent with a [`Client`] made by [`into_client`](Balanced::into_client), which can
+/// back a `tarpc::service!` stub like any other client.
+pub struct Balanced<Req, Resp, A = Address> {
+    endpoints: Arc<Mutex<Vec<Endpoint<Req, Resp, A>>>>,
+    /// The index of the next server to use with [`Strategy::RoundRobin`].
+    next: Arc<AtomicUsize>,
+    strategy: Strategy,
+}
+
+impl<Req, Resp, A> Clone for Balanced<Req, Resp, A> {
+    fn clone(&self) -> Self {
+        Balanced {
+            endpoints: self.endpoints.clone(),
+            next: self.next.clone(),
+            strategy: self.strategy,
+        }
+    }
+}
+
+impl<Req, Resp, A: fmt::Display> fmt::Debug for Balanced<Req, Resp, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endpoints = self.endpoints.lock().unwrap();
+        let addrs: Vec<_> = endpoints.iter().map(|endpoint| endpoint.addr.to_string()).collect();
+        f.debug_struct("Balanced")
+            .field("strategy", &self.strategy)
+            .field("servers", &addrs)
+            .finish()
+    }
+}
+
+struct Endpoint<Req, Resp, A> {
+    /// The resolved address of the server.
+    addr: A,
+    channel: dispatch::Channel<Req, Resp>,
+}
+
+impl<Req, Resp, A> Balanced<Req, Resp, A>
+where
+    Req: Send,
+    Resp: Send,
+    A: Clone + PartialEq + fmt::Display,
+{
+    /// Resolves server addresses with `resolver` and creates a client for each with `connect`.
+    /// Servers that can't be connected to are skipped, but it is an error if none can be.
+    ///
+    /// Only the connections of the clients that `connect` returns are used, so give the client
+    /// made by [`into_client`](Balanced::into_client) any interceptors instead.
+    pub async fn new<R, F, Fut>(
+        strategy: Strategy,
+        mut resolver: R,
+        mut connect: F,
+    ) -> io::Result<Self>
+    where
+        R: Resolver<Addr = A>,
+        F: FnMut(A) -> Fut,
+        Fut: Future<Output = io::Result<Client<Req, Resp>>>,
+    {
+        let balanced = Balanced {
+            endpoints: Arc::new(Mutex::new(vec![])),
+            next: Arc::new(AtomicUsize::new(0)),
+            strategy,
+        };
+        await!(balanced.refresh(&mut resolver, &mut connect))?;
+        Ok(balanced)
+    }
+
+    /// Resolves server addresses with `resolver` again. Servers that are no longer resolved are
+    /// removed, and clients are created with `connect` for servers that are newly resolved or
+    /// were previously ejected.
+    pub async fn refresh<'a, R, F, Fut>(
+        &'a self,
+        resolver: &'a mut R,
+        connect: &'a mut F,
+    ) -> io::Result<()>
+    where
+        R: Resolver<Addr = A>,
+        F: FnMut(A) -> Fut,
+        Fut: Future<Output = io::Result<Client<Req, Resp>>>,
+    {
+        let addrs = await!(resolver.resolve())?;
+        let unconnected: Vec<_> = {
+            let mut endpoints = self.endpoints.lock().unwrap();
+            endpoints.retain(|endpoint| {
+                !endpoint.channel.is_closed() && addrs.contains(&endpoint.addr)
+            });
+            addrs
+                .into_iter()
+                .filter(|addr| !endpoints.iter().any(|endpoint| endpoint.addr == *addr))
+                .collect()
+        };
+
+        for addr in unconnected {
+            let channel = match await!(connect(addr.clone())) {
+                Ok(client) => match client.channel.into_dispatch() {
+                    Some(channel) => channel,
+                    None => {
+                        warn!("[{}] Could not add server, because its client is balanced.", addr);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!("[{}] Could not connect to server: {}", addr, e);
+                    continue;
+                }
+            };
+            let mut endpoints = self.endpoints.lock().unwrap();
+            // A concurrent refresh may have connected to the server while this one was
+            // connecting.
+            if endpoints.iter().any(|endpoint| endpoint.addr == addr) {
+                trace!("[{}] Server was already added.", addr);
+                continue;
+            }
+            info!("[{}] Added server.", addr);
+            endpoints.push(Endpoint { addr, channel });
+        }
+
+        if self.endpoints.lock().unwrap().is_empty() {
+            return Err(no_servers());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of servers requests are currently spread across.
+    pub fn len(&self) -> usize {
+        self.endpoints.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no servers to send requests to.
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.lock().unwrap().is_empty()
+    }
+
+    /// Returns a client that sends each request to the server picked by this balancer's
+    /// [`Strategy`], retrying requests sent via
+    /// [`call_with_retry`](Client::call_with_retry) according to `retry_policy`. The client keeps
+    /// using this balancer's servers as they are refreshed.
+    pub fn into_client(self, retry_policy: RetryPolicy) -> Client<Req, Resp>
+    where
+        Req: 'static,
+        Resp: 'static,
+        A: Send + 'static,
+    {
+        Client::balanced(Arc::new(self), retry_policy)
+    }
+}
+
+/// Picks the server that a balanced [`Client`] sends each request to.
+pub(crate) trait Pick<Req, Resp>: Send + Sync {
+    /// Ejects servers whose dispatch task has shut down, and returns the channel to one of the
+    /// remaining servers.
+    fn pick(&self) -> io::Result<dispatch::Channel<Req, Resp>>;
+
+    /// Returns the number of requests sent to any of the servers that have not yet completed.
+    fn in_flight_requests(&self) -> usize;
+
+    /// Returns true if there are no servers left to send requests to.
+    fn is_closed(&self) -> bool;
+}
+
+impl<Req, Resp, A> Pick<Req, Resp> for Balanced<Req, Resp, A>
+where
+    Req: Send,
+    Resp: Send,
+    A: fmt::Display + Send,
+{
+    fn pick(&self) -> io::Result<dispatch::Channel<Req, Resp>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.retain(|endpoint| {
+            if endpoint.channel.is_closed() {
+                info!("[{}] Ejecting server, because its client is closed.", endpoint.addr);
+                false
+            } else {
+                true
+            }
+        });
+
+        let len = endpoints.len();
+        if len == 0 {
+            return Err(no_servers());
+        }
+        let i = match self.strategy {
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+            Strategy::LeastInFlight => (0..len)
+                .min_by_key(|&i| endpoints[i].channel.in_flight_count())
+                .unwrap(),
+            Strategy::PowerOfTwoChoices => {
+                let rng = &mut rand::thread_rng();
+                let (a, b) = (rng.gen_range(0, len), rng.gen_range(0, len));
+                if endpoints[a].channel.in_flight_count() <= endpoints[b].channel.in_flight_count()
+                {
+                    a
+                } else {
+                    b
+                }
+            }
+        };
+        trace!("[{}] Picked server.", endpoints[i].addr);
+        Ok(endpoints[i].channel.clone())
+    }
+
+    fn in_flight_requests(&self) -> usize {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints
+            .iter()
+            .map(|endpoint| endpoint.channel.in_flight_requests())
+            .sum()
+    }
+
+    fn is_closed(&self) -> bool {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.iter().all(|endpoint| endpoint.channel.is_closed())
+    }
+}
+
+fn no_servers() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "No servers are available.".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Balanced, Strategy};
+    use crate::{
+        client::{self, Client, RetryPolicy},
+        context,
+        server::{self, Handler, Reply, Server},
+        transport,
+    };
+    use futures::{
+        compat::{Future01CompatExt, TokioDefaultSpawner},
+        future::abortable,
+        prelude::*,
+        stream,
+    };
+    use std::{
+        fmt, io,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+    use tokio_timer::Delay;
+
+    #[test]
+    fn round_robin() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(1), addr(2), addr(3)];
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, addrs, connect))?;
+            let mut client = balanced.into_client(RetryPolicy::default());
+            let mut responses = vec![];
+            for _ in 0..4 {
+                responses.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec!["1", "2", "3", "1"]);
+    }
+
+    #[test]
+    fn least_in_flight() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(1), addr(2)];
+            let balanced = await!(Balanced::new(Strategy::LeastInFlight, addrs, connect))?;
+            let mut client = balanced.clone().into_client(RetryPolicy::default());
+            // Occupies the first server, which is otherwise picked first.
+            let abort = hang(&client);
+            let mut responses = vec![];
+            for _ in 0..3 {
+                await!(wait_until(|| in_flight_counts(&balanced) == vec![1, 0]));
+                responses.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            abort.abort();
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec!["2", "2", "2"]);
+    }
+
+    #[test]
+    fn power_of_two_choices() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(1), addr(2)];
+            let balanced = await!(Balanced::new(Strategy::PowerOfTwoChoices, addrs, connect))?;
+            let mut client = balanced.clone().into_client(RetryPolicy::default());
+            let abort = hang(&client);
+            await!(wait_until(|| in_flight_counts(&balanced).iter().sum::<usize>() == 1));
+            let busy = in_flight_counts(&balanced);
+            let idle_port = if busy[0] == 0 { "1" } else { "2" };
+            let mut idle = 0;
+            for _ in 0..100 {
+                await!(wait_until(|| in_flight_counts(&balanced) == busy));
+                if await!(client.call(context::current(), "hi".into()))? == idle_port {
+                    idle += 1;
+                }
+            }
+            abort.abort();
+            Ok::<_, io::Error>(idle)
+        });
+        // The busy server is only picked when it's sampled twice, i.e. a quarter of the time.
+        let idle = responses.unwrap();
+        assert!(idle > 50, "Idle server picked {} of 100 times", idle);
+    }
+
+    #[test]
+    fn refresh() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(1), addr(2)];
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, addrs, connect))?;
+            let mut client = balanced.clone().into_client(RetryPolicy::default());
+            let mut resolver = vec![addr(2), addr(3)];
+            await!(balanced.refresh(&mut resolver, &mut connect))?;
+            assert_eq!(balanced.len(), 2);
+            let mut responses = vec![];
+            for _ in 0..2 {
+                responses.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn concurrent_refresh() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let len = run_future(async {
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, vec![addr(1)], connect))?;
+            let (mut resolver1, mut resolver2) = (vec![addr(1), addr(2)], vec![addr(1), addr(2)]);
+            // Both refreshes find the new server before either finishes connecting to it.
+            let slow_connect = |addr: SocketAddr| {
+                async move {
+                    await!(Delay::new(Instant::now() + Duration::from_millis(10)).compat())
+                        .unwrap();
+                    await!(connect(addr))
+                }
+            };
+            let (mut connect1, mut connect2) = (slow_connect.clone(), slow_connect.clone());
+            let (refreshed1, refreshed2) = await!(balanced
+                .refresh(&mut resolver1, &mut connect1)
+                .join(balanced.refresh(&mut resolver2, &mut connect2)));
+            refreshed1?;
+            refreshed2?;
+            Ok::<_, io::Error>(balanced.len())
+        });
+        assert_eq!(len.unwrap(), 2);
+    }
+
+    #[test]
+    fn eject_closed() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(0), addr(1)];
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, addrs, connect))?;
+            let mut client = balanced.clone().into_client(RetryPolicy::default());
+            await!(wait_until(|| balanced.endpoints.lock().unwrap()[0].channel.is_closed()));
+            assert_eq!(balanced.len(), 2);
+            let mut responses = vec![];
+            for _ in 0..2 {
+                responses.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            assert_eq!(balanced.len(), 1);
+            assert!(!client.is_closed());
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec!["1", "1"]);
+    }
+
+    #[test]
+    fn in_process_servers() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let names = vec!["a", "b"];
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, names, connect))?;
+            let mut client = balanced.into_client(RetryPolicy::default());
+            let mut responses = vec![];
+            for _ in 0..2 {
+                responses.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn call_stream() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let responses = run_future(async {
+            let addrs = vec![addr(1), addr(2)];
+            let balanced = await!(Balanced::new(Strategy::RoundRobin, addrs, connect))?;
+            let mut client = balanced.into_client(RetryPolicy::default());
+            let mut responses = vec![];
+            for _ in 0..2 {
+                let stream = await!(client.call_stream(context::current(), "twice".into()))?;
+                responses.push(await!(stream.try_collect::<Vec<_>>())?);
+            }
+            Ok::<_, io::Error>(responses)
+        });
+        assert_eq!(responses.unwrap(), vec![vec!["1", "1"], vec!["2", "2"]]);
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    /// Connects to an in-process server that answers each request with `addr`'s port, or with
+    /// `addr` itself if it isn't a socket address. It answers requests to "twice" with a stream
+    /// of that answer twice, and never answers requests to "hang". The server for port 0 is
+    /// unreachable.
+    fn connect<A>(addr: A) -> impl Future<Output = io::Result<Client<String, String>>>
+    where
+        A: fmt::Display,
+    {
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let addr = addr.to_string();
+        let name = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.port().to_string(),
+            Err(_) => addr,
+        };
+        if name == "0" {
+            drop(server_channel);
+        } else {
+            let server = Server::<String, String>::new(server::Config::default())
+                .incoming(stream::once(future::ready(Ok(server_channel))))
+                .respond_with(move |_ctx, request: String| match &*request {
+                    "hang" => future::empty().boxed(),
+                    "twice" => {
+                        let responses = stream::iter(vec![Ok(name.clone()), Ok(name.clone())]);
+                        future::ready(Ok(Reply::Stream(responses.boxed()))).boxed()
+                    }
+                    _ => future::ready(Ok(Reply::Unary(name.clone()))).boxed(),
+                });
+            crate::spawn(server).unwrap();
+        }
+        Client::new(client::Config::default(), client_channel)
+    }
+
+    /// Sends a request that is never answered, returning a handle that cancels it.
+    fn hang(client: &Client<String, String>) -> future::AbortHandle {
+        let mut client = client.clone();
+        let (call, abort) = abortable(async move {
+            let _ = await!(client.call(context::current(), "hang".into()));
+        });
+        crate::spawn(call.map(|_| ())).unwrap();
+        abort
+    }
+
+    /// Returns the number of requests in flight to each server.
+    fn in_flight_counts<A>(balanced: &Balanced<String, String, A>) -> Vec<usize> {
+        balanced
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|endpoint| endpoint.channel.in_flight_count())
+            .collect()
+    }
+
+    /// Waits until `condition` holds.
+    async fn wait_until<F: Fn() -> bool>(condition: F) {
+        while !condition() {
+            await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+        }
+    }
+
+    fn run_future<F>(f: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        tokio::run(
+            f.map(|result| tx.send(result).unwrap_or_else(|_| unreachable!()))
+                .boxed()
+                .unit_error()
+                .compat(),
+        );
+        futures::executor::block_on(rx).unwrap()
+    }
+}
@@ -6,53 +6,79 @@
 
 use crate::{
     context,
-    util::{deadline_compat, AsDuration, Compact},
-    ClientMessage, ClientMessageKind, Request, Response, Transport,
+    transport::{Address, SkippedMessage},
+    util::{
+        credit::{self, Credit},
+        deadline_compat, Compact,
+    },
+    ClientMessage, ClientMessageKind, Code, Handshake, Request, Response, ServerError,
+    ServerMessage, ServerMessageHeader, Transport,
 };
 use fnv::FnvHashMap;
 use futures::{
     Poll,
     channel::{mpsc, oneshot},
+    compat::{Compat01As03, Future01CompatExt},
+    future::{abortable, AbortHandle},
     prelude::*,
     ready,
-    stream::Fuse,
+    stream::{BoxStream, Fuse},
     task::LocalWaker,
 };
 use humantime::format_rfc3339;
-use log::{debug, error, info, trace};
-use pin_utils::unsafe_pinned;
+use log::{debug, error, info, trace, warn};
+use pin_utils::{pin_mut, unsafe_pinned, unsafe_unpinned};
 use std::{
+    collections::VecDeque,
     io,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::Instant,
 };
-use trace::SpanId;
+use tokio_timer::{timeout, Delay};
 
-use super::Config;
+use super::{Config, InFlightPolicy, ReconnectConfig};
 
 /// Handles communication from the client to request dispatch.
 #[derive(Debug)]
 pub(crate) struct Channel<Req, Resp> {
     to_dispatch: mpsc::Sender<DispatchRequest<Req, Resp>>,
+    /// Channel to send the inputs of client-streaming requests to the dispatcher.
+    to_dispatch_inputs: mpsc::Sender<DispatchInput<Req>>,
     /// Channel to send a cancel message to the dispatcher.
     cancellation: RequestCancellation,
+    /// Channel to send the credit that streaming requests grant the server to the dispatcher.
+    credits: ResponseCredits,
     /// The ID to use for the next request to stage.
     next_request_id: Arc<AtomicU64>,
-    server_addr: SocketAddr,
+    /// The number of requests sent through this channel (or its clones) that have not yet
+    /// completed.
+    in_flight_requests: Arc<AtomicUsize>,
+    /// The number of requests that the dispatch task has written to the wire and that have not
+    /// yet received responses.
+    in_flight_count: Arc<AtomicUsize>,
+    /// The number of responses buffered for each streaming request, which is the credit the
+    /// server is granted for them; see [`Config::pending_request_buffer`].
+    response_buffer: usize,
+    server_addr: Address,
 }
 
 impl<Req, Resp> Clone for Channel<Req, Resp> {
     fn clone(&self) -> Self {
         Self {
             to_dispatch: self.to_dispatch.clone(),
+            to_dispatch_inputs: self.to_dispatch_inputs.clone(),
             cancellation: self.cancellation.clone(),
+            credits: self.credits.clone(),
             next_request_id: self.next_request_id.clone(),
-            server_addr: self.server_addr,
+            in_flight_requests: self.in_flight_requests.clone(),
+            in_flight_count: self.in_flight_count.clone(),
+            response_buffer: self.response_buffer,
+            server_addr: self.server_addr.clone(),
         }
     }
 }
@@ -61,15 +87,39 @@ impl<Req, Resp> Channel<Req, Resp> {
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves when the request is sent (not when the response is received).
     pub(crate) async fn send(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> io::Result<DispatchResponse<Resp>> {
+        await!(self.send_request(ctx, request, None))
+    }
+
+    /// Like [`send`](Channel::send), but follows the request with a stream of inputs.
+    pub(crate) async fn send_with_inputs(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+        inputs: BoxStream<'static, Req>,
+    ) -> io::Result<DispatchResponse<Resp>>
+    where
+        Req: Send + 'static,
+    {
+        let (on_sent, sent) = oneshot::channel();
+        let mut response = await!(self.send_request(ctx, request, Some(on_sent)))?;
+        response.input_forwarder = Some(self.forward_inputs(response.request_id, sent, inputs)?);
+        Ok(response)
+    }
+
+    async fn send_request(
         &mut self,
         mut ctx: context::Context,
         request: Req,
+        on_sent: Option<oneshot::Sender<Credit>>,
     ) -> io::Result<DispatchResponse<Resp>> {
         // Convert the context to the call context.
-        ctx.trace_context.parent_id = Some(ctx.trace_context.span_id);
-        ctx.trace_context.span_id = SpanId::random(&mut rand::thread_rng());
+        ctx.trace_context = ctx.trace_context.new_child();
 
-        let timeout = ctx.deadline.as_duration();
+        let timeout = ctx.timeout();
         let deadline = Instant::now() + timeout;
         trace!(
             "[{}/{}] Queuing request with deadline {} (timeout {:?}).",
@@ -81,23 +131,170 @@ impl<Req, Resp> Channel<Req, Resp> {
 
         let (response_completion, response) = oneshot::channel();
         let cancellation = self.cancellation.clone();
-        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
-        await!(self.to_dispatch.send(DispatchRequest {
-            ctx,
-            request_id,
+        let request_id = await!(self.enqueue(
+            ctx.clone(),
             request,
-            response_completion,
-        })).map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+            ResponseCompletion::Unary(response_completion),
+            on_sent
+        ))?;
         Ok(DispatchResponse {
             response: deadline_compat::Deadline::new(response, deadline),
             complete: false,
             request_id,
             cancellation,
+            input_forwarder: None,
+            in_flight_requests: self.in_flight_requests.clone(),
+            ctx,
+            server_addr: self.server_addr.clone(),
+        })
+    }
+
+    /// Sends a request to the dispatch task to forward to the server, returning a [`Stream`] of
+    /// the responses to it.
+    pub(crate) async fn send_stream(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> io::Result<ResponseStream<Resp>> {
+        await!(self.send_stream_request(ctx, request, None))
+    }
+
+    /// Like [`send_stream`](Channel::send_stream), but follows the request with a stream of
+    /// inputs.
+    pub(crate) async fn send_stream_with_inputs(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+        inputs: BoxStream<'static, Req>,
+    ) -> io::Result<ResponseStream<Resp>>
+    where
+        Req: Send + 'static,
+    {
+        let (on_sent, sent) = oneshot::channel();
+        let mut responses = await!(self.send_stream_request(ctx, request, Some(on_sent)))?;
+        responses.input_forwarder = Some(self.forward_inputs(responses.request_id, sent, inputs)?);
+        Ok(responses)
+    }
+
+    async fn send_stream_request(
+        &mut self,
+        mut ctx: context::Context,
+        request: Req,
+        on_sent: Option<oneshot::Sender<Credit>>,
+    ) -> io::Result<ResponseStream<Resp>> {
+        ctx.trace_context = ctx.trace_context.new_child();
+
+        let timeout = ctx.timeout();
+        trace!(
+            "[{}/{}] Queuing streaming request with deadline {} (timeout {:?}).",
+            ctx.trace_id(),
+            self.server_addr,
+            format_rfc3339(ctx.deadline),
+            timeout,
+        );
+
+        let (response_completion, responses) = mpsc::channel(self.response_buffer);
+        let cancellation = self.cancellation.clone();
+        let request_id = await!(self.enqueue(
+            ctx.clone(),
+            request,
+            ResponseCompletion::Stream(response_completion),
+            on_sent
+        ))?;
+        Ok(ResponseStream {
+            responses,
+            deadline: Delay::new(Instant::now() + timeout).compat(),
+            complete: false,
+            request_id,
+            cancellation,
+            credits: self.credits.clone(),
+            consumed: 0,
+            credit_batch: (self.response_buffer as u64 / 2).max(1),
+            input_forwarder: None,
+            in_flight_requests: self.in_flight_requests.clone(),
             ctx,
-            server_addr: self.server_addr,
+            server_addr: self.server_addr.clone(),
         })
     }
 
+    /// Hands a request to the dispatch task, returning the request's ID.
+    async fn enqueue(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+        response_completion: ResponseCompletion<Resp>,
+        on_sent: Option<oneshot::Sender<Credit>>,
+    ) -> io::Result<u64> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        await!(self.to_dispatch.send(DispatchRequest {
+            ctx,
+            request_id,
+            request,
+            response_completion,
+            on_sent,
+        })).map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+        self.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+        Ok(request_id)
+    }
+
+    /// Spawns a task that forwards `inputs` to the dispatch task once the request with ID
+    /// `request_id` is written to the transport, followed by the end of the inputs. Returns a
+    /// handle that stops the forwarding.
+    ///
+    /// Inputs share the dispatch task's buffer size, so a client that produces inputs faster
+    /// than the transport can write them is back-pressured. Past the first input, the stream is
+    /// only polled while the server has granted credit for more.
+    fn forward_inputs(
+        &self,
+        request_id: u64,
+        sent: oneshot::Receiver<Credit>,
+        mut inputs: BoxStream<'static, Req>,
+    ) -> io::Result<AbortHandle>
+    where
+        Req: Send + 'static,
+    {
+        let mut to_dispatch = self.to_dispatch_inputs.clone();
+        let (forward, abort_handle) = abortable(async move {
+            let mut credit = match await!(sent) {
+                Ok(credit) => credit,
+                // The request was discarded without being sent.
+                Err(oneshot::Canceled) => return,
+            };
+            // An input is only taken from the stream once the server has room for it.
+            loop {
+                if !await!(credit.acquire()) {
+                    // The request completed.
+                    return;
+                }
+                let input = match await!(inputs.next()) {
+                    Some(input) => input,
+                    None => break,
+                };
+                let input = DispatchInput {
+                    request_id,
+                    input: Some(input),
+                };
+                if await!(to_dispatch.send(input)).is_err() {
+                    return;
+                }
+            }
+            let _ = await!(to_dispatch.send(DispatchInput {
+                request_id,
+                input: None,
+            }));
+        });
+        crate::spawn(forward.map(|_| ())).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Could not spawn input forwarding task. Is shutdown: {}",
+                    e.is_shutdown()
+                ),
+            )
+        })?;
+        Ok(abort_handle)
+    }
+
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves to the response.
     pub(crate) async fn call(
@@ -108,6 +305,24 @@ impl<Req, Resp> Channel<Req, Resp> {
         let response_future = await!(self.send(context, request))?;
         await!(response_future)
     }
+
+    /// Returns the number of requests sent through this channel that have not yet completed.
+    pub(crate) fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that the dispatch task has written to the wire and that
+    /// have not yet received responses. Unlike [`in_flight_requests`](Channel::in_flight_requests),
+    /// this excludes requests buffered client-side.
+    pub(crate) fn in_flight_count(&self) -> usize {
+        self.in_flight_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the dispatch task has shut down, meaning no more requests can be sent
+    /// through this channel.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.to_dispatch.is_closed()
+    }
 }
 
 /// A server response that is completed by request dispatch when the corresponding response
@@ -119,12 +334,11 @@ pub struct DispatchResponse<Resp> {
     complete: bool,
     cancellation: RequestCancellation,
     request_id: u64,
-    server_addr: SocketAddr,
-}
-
-impl<Resp> DispatchResponse<Resp> {
-    unsafe_pinned!(server_addr: SocketAddr);
-    unsafe_pinned!(ctx: context::Context);
+    /// Stops forwarding the request's inputs when the response is dropped.
+    input_forwarder: Option<AbortHandle>,
+    /// Decremented when the response is dropped.
+    in_flight_requests: Arc<AtomicUsize>,
+    server_addr: Address,
 }
 
 impl<Resp> Future for DispatchResponse<Resp> {
@@ -137,49 +351,59 @@ impl<Resp> Future for DispatchResponse<Resp> {
 
         Poll::Ready(match resp {
             Ok(resp) => Ok(resp.message?),
-            Err(e) => Err({
-                let trace_id = *self.ctx().trace_id();
-                let server_addr = *self.server_addr();
-
-                if e.is_elapsed() {
-                    io::Error::new(
-                        io::ErrorKind::TimedOut,
-                        "Client dropped expired request.".to_string(),
-                    )
-                } else if e.is_timer() {
-                    let e = e.into_timer().unwrap();
-                    if e.is_at_capacity() {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            "Cancelling request because an expiration could not be set \
-                             due to the timer being at capacity."
-                                .to_string(),
-                        )
-                    } else if e.is_shutdown() {
-                        panic!("[{}/{}] Timer was shutdown", trace_id, server_addr)
-                    } else {
-                        panic!(
-                            "[{}/{}] Unrecognized timer error: {}",
-                            trace_id, server_addr, e
-                        )
-                    }
-                } else if e.is_inner() {
-                    // The oneshot is Canceled when the dispatch task ends.
-                    io::Error::from(io::ErrorKind::ConnectionReset)
-                } else {
-                    panic!(
-                        "[{}/{}] Unrecognized deadline error: {}",
-                        trace_id, server_addr, e
-                    )
-                }
-            }),
+            Err(e) => Err(response_error(e, &self.ctx, &self.server_addr)),
         })
     }
 }
 
+/// Converts an error waiting for a response into the error returned to the caller.
+fn response_error<E>(
+    e: timeout::Error<E>,
+    ctx: &context::Context,
+    server_addr: &Address,
+) -> io::Error {
+    let trace_id = *ctx.trace_id();
+
+    if e.is_elapsed() {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Client dropped expired request.".to_string(),
+        )
+    } else if e.is_timer() {
+        let e = e.into_timer().unwrap();
+        if e.is_at_capacity() {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "Cancelling request because an expiration could not be set \
+                 due to the timer being at capacity."
+                    .to_string(),
+            )
+        } else if e.is_shutdown() {
+            panic!("[{}/{}] Timer was shutdown", trace_id, server_addr)
+        } else {
+            panic!(
+                "[{}/{}] Unrecognized timer error: {}",
+                trace_id, server_addr, e
+            )
+        }
+    } else if e.is_inner() {
+        // The oneshot is Canceled when the dispatch task ends.
+        io::Error::from(io::ErrorKind::ConnectionReset)
+    } else {
+        panic!(
+            "[{}/{}] Unrecognized deadline error",
+            trace_id, server_addr
+        )
+    }
+}
+
 // Cancels the request when dropped, if not already complete.
 impl<Resp> Drop for DispatchResponse<Resp> {
     fn drop(&mut self) {
+        self.in_flight_requests.fetch_sub(1, Ordering::Relaxed);
+        if let Some(input_forwarder) = &self.input_forwarder {
+            input_forwarder.abort();
+        }
         if !self.complete {
             // The receiver needs to be closed to handle the edge case that the request has not
             // yet been received by the dispatch task. It is possible for the cancel message to
@@ -197,45 +421,349 @@ impl<Resp> Drop for DispatchResponse<Resp> {
     }
 }
 
+/// The responses to a streaming request, which are completed by request dispatch as they arrive
+/// off the wire.
+///
+/// The stream ends when the server ends it, or after the first error. Dropping the stream before
+/// it ends cancels the request.
+///
+/// The server sends only as many responses as the stream has room to buffer, and more as they
+/// are consumed, so a stream that isn't polled pauses the server's stream of responses.
+#[derive(Debug)]
+pub struct ResponseStream<Resp> {
+    /// Yields `None` once the server ends the stream.
+    responses: mpsc::Receiver<Option<Response<Resp>>>,
+    /// Fires when the request's deadline passes.
+    deadline: Compat01As03<Delay>,
+    ctx: context::Context,
+    complete: bool,
+    cancellation: RequestCancellation,
+    /// Grants the server credit for responses as they're consumed.
+    credits: ResponseCredits,
+    /// The number of responses consumed since credit was last granted for them.
+    consumed: u64,
+    /// The number of consumed responses to grant credit for at a time.
+    credit_batch: u64,
+    request_id: u64,
+    /// Stops forwarding the request's inputs when the stream is dropped.
+    input_forwarder: Option<AbortHandle>,
+    /// Decremented when the stream is dropped.
+    in_flight_requests: Arc<AtomicUsize>,
+    server_addr: Address,
+}
+
+impl<Resp> Stream for ResponseStream<Resp> {
+    type Item = io::Result<Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<Resp>>> {
+        if self.complete {
+            return Poll::Ready(None);
+        }
+        let response = match self.responses.poll_next_unpin(waker) {
+            Poll::Ready(response) => response,
+            Poll::Pending => {
+                let e: timeout::Error<()> = match ready!(self.deadline.poll_unpin(waker)) {
+                    Ok(()) => timeout::Error::elapsed(),
+                    Err(e) => timeout::Error::timer(e),
+                };
+                self.complete = true;
+                return Poll::Ready(Some(Err(response_error(e, &self.ctx, &self.server_addr))));
+            }
+        };
+        Poll::Ready(match response {
+            Some(Some(response)) => {
+                if response.message.is_err() {
+                    self.complete = true;
+                } else {
+                    // Batching grants spares a message per response, while leaving the server
+                    // enough credit to keep sending.
+                    self.consumed += 1;
+                    if self.consumed >= self.credit_batch {
+                        let (request_id, consumed) = (self.request_id, self.consumed);
+                        self.credits.grant(request_id, consumed);
+                        self.consumed = 0;
+                    }
+                }
+                Some(response.message.map_err(io::Error::from))
+            }
+            Some(None) => {
+                self.complete = true;
+                None
+            }
+            // Dispatch drops the sender without ending the stream when the connection breaks.
+            None => {
+                self.complete = true;
+                Some(Err(io::Error::from(io::ErrorKind::ConnectionReset)))
+            }
+        })
+    }
+}
+
+// Cancels the request when dropped, if not already complete.
+impl<Resp> Drop for ResponseStream<Resp> {
+    fn drop(&mut self) {
+        self.in_flight_requests.fetch_sub(1, Ordering::Relaxed);
+        if let Some(input_forwarder) = &self.input_forwarder {
+            input_forwarder.abort();
+        }
+        if !self.complete {
+            // Closing the receiver first ensures that dispatch discards the request if it hasn't
+            // yet been sent; see DispatchResponse's Drop impl.
+            self.responses.close();
+            self.cancellation.cancel(self.request_id);
+        }
+    }
+}
+
 /// Spawns a dispatch task on the default executor that manages the lifecycle of requests initiated
 /// by the returned [`Channel`].
 pub async fn spawn<Req, Resp, C>(
     config: Config,
     transport: C,
-    server_addr: SocketAddr,
+    server_addr: Address,
 ) -> io::Result<Channel<Req, Resp>>
 where
     Req: Send,
     Resp: Send,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>> + Send,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send,
 {
     let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
+    let (to_dispatch_inputs, pending_inputs) = mpsc::channel(config.pending_request_buffer);
     let (cancellation, canceled_requests) = cancellations();
+    let (credits, granted_credits) = response_credits();
+    let (handshake_tx, handshake_rx) = handshake_channel(&config);
+    let in_flight_count = Arc::new(AtomicUsize::new(0));
+    let response_buffer = config.pending_request_buffer;
 
+    let peer = server_addr.clone();
     crate::spawn(
         RequestDispatch {
+            handshake: HandshakeState::new(&config),
+            handshake_tx,
             config,
-            server_addr,
+            server_addr: server_addr.clone(),
             canceled_requests,
+            granted_credits,
             transport: transport.fuse(),
             in_flight_requests: FnvHashMap::default(),
             pending_requests: pending_requests.fuse(),
-        }.unwrap_or_else(move |e| error!("[{}] Connection broken: {}", server_addr, e))
+            pending_inputs: pending_inputs.fuse(),
+            requeued_requests: VecDeque::new(),
+            abandoned_requests: VecDeque::new(),
+            initial_credits: VecDeque::new(),
+            clone_request: None,
+            reconnecting: false,
+            server_shutting_down: false,
+            in_flight_count: in_flight_count.clone(),
+        }.unwrap_or_else(move |e| error!("[{}] Connection broken: {}", peer, e)),
     ).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "Could not spawn client dispatch task. Is shutdown: {}",
-                        e.is_shutdown()
-                    ),
-                )
-            })?;
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn client dispatch task. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })?;
+    if let Some(handshake_rx) = handshake_rx {
+        await!(handshake_rx).unwrap_or_else(|oneshot::Canceled| Err(handshake_unanswered()))?;
+    }
+
+    Ok(Channel {
+        to_dispatch,
+        to_dispatch_inputs,
+        cancellation,
+        credits,
+        server_addr,
+        next_request_id: Arc::new(AtomicU64::new(0)),
+        in_flight_requests: Arc::new(AtomicUsize::new(0)),
+        in_flight_count,
+        response_buffer,
+    })
+}
+
+/// Spawns a dispatch task on the default executor that manages the lifecycle of requests initiated
+/// by the returned [`Channel`]. When the transport breaks, the dispatch task replaces it by calling
+/// `connect`, so the returned [`Channel`] outlives any single transport.
+///
+/// The first transport is established the same way, backing off between failed attempts. If
+/// `reconnect_config` gives up first, the last error is returned.
+pub async fn spawn_reconnecting<Req, Resp, C, F, Fut>(
+    config: Config,
+    reconnect_config: ReconnectConfig,
+    in_flight_policy: InFlightPolicy<Req>,
+    mut connect: F,
+) -> io::Result<Channel<Req, Resp>>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<C>> + Send + 'static,
+{
+    let transport = await!(reconnect(&mut connect, &reconnect_config, None))?;
+    let server_addr = peer_addr(&transport);
+    let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
+    let (to_dispatch_inputs, pending_inputs) = mpsc::channel(config.pending_request_buffer);
+    let (cancellation, canceled_requests) = cancellations();
+    let (credits, granted_credits) = response_credits();
+
+    let (handshake_tx, handshake_rx) = handshake_channel(&config);
+    let in_flight_count = Arc::new(AtomicUsize::new(0));
+    let response_buffer = config.pending_request_buffer;
+    let dispatch = RequestDispatch {
+        handshake: HandshakeState::new(&config),
+        handshake_tx,
+        config,
+        server_addr: server_addr.clone(),
+        canceled_requests,
+        granted_credits,
+        transport: transport.fuse(),
+        in_flight_requests: FnvHashMap::default(),
+        pending_requests: pending_requests.fuse(),
+        pending_inputs: pending_inputs.fuse(),
+        requeued_requests: VecDeque::new(),
+        abandoned_requests: VecDeque::new(),
+        initial_credits: VecDeque::new(),
+        clone_request: in_flight_policy.clone_request,
+        reconnecting: true,
+        server_shutting_down: false,
+        in_flight_count: in_flight_count.clone(),
+    };
+    crate::spawn(reconnect_dispatch(dispatch, connect, reconnect_config)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn client dispatch task. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })?;
+    if let Some(handshake_rx) = handshake_rx {
+        await!(handshake_rx).unwrap_or_else(|oneshot::Canceled| Err(handshake_unanswered()))?;
+    }
 
     Ok(Channel {
         to_dispatch,
+        to_dispatch_inputs,
         cancellation,
+        credits,
         server_addr,
         next_request_id: Arc::new(AtomicU64::new(0)),
+        in_flight_requests: Arc::new(AtomicUsize::new(0)),
+        in_flight_count,
+        response_buffer,
+    })
+}
+
+/// Returns a channel notified when the server answers the handshake, if one is configured.
+fn handshake_channel(
+    config: &Config,
+) -> (
+    Option<oneshot::Sender<io::Result<()>>>,
+    Option<oneshot::Receiver<io::Result<()>>>,
+) {
+    match config.handshake {
+        Some(_) => {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some(rx))
+        }
+        None => (None, None),
+    }
+}
+
+/// Drives `dispatch` to completion, replacing its transport each time the transport breaks.
+async fn reconnect_dispatch<Req, Resp, C, F, Fut>(
+    dispatch: RequestDispatch<Req, Resp, C>,
+    mut connect: F,
+    reconnect_config: ReconnectConfig,
+) where
+    Req: Send,
+    Resp: Send,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<C>>,
+{
+    pin_mut!(dispatch);
+    loop {
+        let e = match await!(dispatch.as_mut()) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        let server_addr = dispatch.server_addr.clone();
+        error!("[{}] Connection broken: {}", server_addr, e);
+        dispatch.disconnect();
+        if dispatch.handshake == HandshakeState::Rejected {
+            // The server would reject the handshake on a new connection, too.
+            return;
+        }
+
+        match await!(reconnect(&mut connect, &reconnect_config, Some(&server_addr))) {
+            Ok(transport) => dispatch.set_transport(transport),
+            Err(e) => {
+                error!("[{}] Giving up on reconnecting: {}", server_addr, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Calls `connect` until it yields a transport, backing off exponentially between attempts.
+/// `server_addr` is the address of the server whose transport broke, or `None` when connecting
+/// for the first time.
+async fn reconnect<C, F, Fut>(
+    connect: &mut F,
+    reconnect_config: &ReconnectConfig,
+    server_addr: Option<&Address>,
+) -> io::Result<C>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<C>>,
+{
+    let server = match server_addr {
+        Some(server_addr) => format!("[{}] Reconnect", server_addr),
+        None => "Connect".to_string(),
+    };
+    let mut backoff = reconnect_config.initial_backoff;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let e = match await!(connect()) {
+            Ok(transport) => {
+                if attempts > 1 || server_addr.is_some() {
+                    info!("{} succeeded after {} attempt(s).", server, attempts);
+                }
+                return Ok(transport);
+            }
+            Err(e) => e,
+        };
+        if let Some(max_attempts) = reconnect_config.max_attempts {
+            if attempts >= max_attempts {
+                return Err(e);
+            }
+        }
+        warn!(
+            "{} attempt {} failed: {}. Retrying in {:?}.",
+            server, attempts, e, backoff
+        );
+        await!(Delay::new(Instant::now() + backoff).compat())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        backoff = backoff
+            .checked_mul(2)
+            .unwrap_or(reconnect_config.max_backoff)
+            .min(reconnect_config.max_backoff);
+    }
+}
+
+/// Returns the address of the peer on the other end of `transport`, falling back to the
+/// unspecified address if the peer could not be determined.
+fn peer_addr<C: Transport>(transport: &C) -> Address {
+    transport.peer_addr().unwrap_or_else(|e| {
+        warn!(
+            "Setting peer to unspecified because peer could not be determined: {}",
+            e
+        );
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into()
     })
 }
 
@@ -246,34 +774,125 @@ struct RequestDispatch<Req, Resp, C> {
     transport: Fuse<C>,
     /// Requests waiting to be written to the wire.
     pending_requests: Fuse<mpsc::Receiver<DispatchRequest<Req, Resp>>>,
+    /// Inputs to in-flight requests waiting to be written to the wire.
+    pending_inputs: Fuse<mpsc::Receiver<DispatchInput<Req>>>,
     /// Requests that were dropped.
     canceled_requests: CanceledRequests,
+    /// Credit granted to streaming requests as their responses are consumed.
+    granted_credits: GrantedCredits,
     /// Requests already written to the wire that haven't yet received responses.
-    in_flight_requests: FnvHashMap<u64, InFlightData<Resp>>,
+    in_flight_requests: FnvHashMap<u64, InFlightData<Req, Resp>>,
+    /// Requests that were in flight on a broken transport, waiting to be written to its
+    /// replacement.
+    requeued_requests: VecDeque<DispatchRequest<Req, Resp>>,
+    /// Requests that the client stopped waiting on before the server finished responding,
+    /// waiting for cancellations to be written.
+    abandoned_requests: VecDeque<(context::Context, u64)>,
+    /// Credit for the responses to streaming requests that were just written, waiting to be
+    /// written after them.
+    initial_credits: VecDeque<(u64, u64)>,
+    /// When set, a copy of each request is retained while it is in flight, so that it can be
+    /// requeued if the transport breaks.
+    clone_request: Option<fn(&Req) -> Req>,
+    /// Whether the transport is replaced when it breaks. If so, the transport is considered broken
+    /// as soon as its read half closes; otherwise, in-flight requests wait out their deadlines.
+    reconnecting: bool,
+    /// True once the server on the current transport has said it is shutting down, after which
+    /// no new requests are sent on the transport.
+    server_shutting_down: bool,
+    /// Updated with the number of requests in flight each time the dispatch task is polled, so
+    /// that channels can see it.
+    in_flight_count: Arc<AtomicUsize>,
     /// Configures limits to prevent unlimited resource usage.
     config: Config,
     /// The address of the server connected to.
-    server_addr: SocketAddr,
+    server_addr: Address,
+    /// The progress of the handshake on the current transport.
+    handshake: HandshakeState,
+    /// Notified when the server answers the first handshake.
+    handshake_tx: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+/// The progress of a client's handshake with the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandshakeState {
+    /// The handshake has yet to be sent.
+    Unsent,
+    /// The handshake was sent, and requests are held back until the server answers.
+    Sent,
+    /// The server accepted the handshake, or no handshake is configured.
+    Accepted,
+    /// The server rejected the handshake.
+    Rejected,
+}
+
+impl HandshakeState {
+    fn new(config: &Config) -> Self {
+        match config.handshake {
+            Some(_) => HandshakeState::Unsent,
+            None => HandshakeState::Accepted,
+        }
+    }
 }
 
 impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
 where
     Req: Send,
     Resp: Send,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>>,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>,
 {
-    unsafe_pinned!(server_addr: SocketAddr);
-    unsafe_pinned!(in_flight_requests: FnvHashMap<u64, InFlightData<Resp>>);
+    unsafe_pinned!(server_addr: Address);
+    unsafe_pinned!(in_flight_requests: FnvHashMap<u64, InFlightData<Req, Resp>>);
     unsafe_pinned!(canceled_requests: CanceledRequests);
+    unsafe_pinned!(granted_credits: GrantedCredits);
     unsafe_pinned!(pending_requests: Fuse<mpsc::Receiver<DispatchRequest<Req, Resp>>>);
+    unsafe_pinned!(pending_inputs: Fuse<mpsc::Receiver<DispatchInput<Req>>>);
+    unsafe_unpinned!(requeued_requests: VecDeque<DispatchRequest<Req, Resp>>);
+    unsafe_unpinned!(abandoned_requests: VecDeque<(context::Context, u64)>);
+    unsafe_unpinned!(initial_credits: VecDeque<(u64, u64)>);
     unsafe_pinned!(transport: Fuse<C>);
+    unsafe_unpinned!(handshake: HandshakeState);
+    unsafe_unpinned!(handshake_tx: Option<oneshot::Sender<io::Result<()>>>);
+    unsafe_unpinned!(server_shutting_down: bool);
 
     fn pump_read(self: &mut Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<()>>> {
-        Poll::Ready(match ready!(self.transport().poll_next(waker)?) {
-            Some(response) => {
+        Poll::Ready(match ready!(self.transport().poll_next(waker)) {
+            Some(Ok(ServerMessage::Response(response))) => {
                 self.complete(response);
                 Some(Ok(()))
             }
+            Some(Ok(ServerMessage::StreamItem(response))) => {
+                self.complete_stream_item(response);
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::StreamEnd { request_id })) => {
+                self.complete_stream(request_id);
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::Handshake(result))) => {
+                self.complete_handshake(result)?;
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::InputCredit { request_id, credit })) => {
+                self.grant_input_credit(request_id, credit);
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::Shutdown)) => {
+                info!(
+                    "[{}] Server is shutting down. {} requests in flight.",
+                    self.server_addr(),
+                    self.in_flight_requests().len()
+                );
+                *self.server_shutting_down() = true;
+                Some(Ok(()))
+            }
+            Some(Err(e)) => match SkippedMessage::<ServerMessageHeader>::from_io_error(&e) {
+                Some(skipped) => {
+                    self.handle_skipped(skipped);
+                    Some(Ok(()))
+                }
+                None => Some(Err(e)),
+            },
             None => {
                 trace!("[{}] read half closed", self.server_addr());
                 None
@@ -287,6 +906,22 @@ where
             Closed,
         }
 
+        match *self.handshake() {
+            HandshakeState::Unsent => {
+                while let Poll::Pending = self.transport().poll_ready(waker)? {
+                    ready!(self.transport().poll_flush(waker)?);
+                }
+                self.write_handshake()?;
+                return Poll::Ready(Some(Ok(())));
+            }
+            HandshakeState::Sent => {
+                // Nothing else can be sent before the server answers the handshake.
+                ready!(self.transport().poll_flush(waker)?);
+                return Poll::Pending;
+            }
+            HandshakeState::Accepted | HandshakeState::Rejected => {}
+        }
+
         let pending_requests_status = match self.poll_next_request(waker)? {
             Poll::Ready(Some(dispatch_request)) => {
                 self.write_request(dispatch_request)?;
@@ -296,6 +931,15 @@ where
             Poll::Pending => ReceiverStatus::NotReady,
         };
 
+        let pending_inputs_status = match self.poll_next_input(waker)? {
+            Poll::Ready(Some((context, input))) => {
+                self.write_input(context, input)?;
+                return Poll::Ready(Some(Ok(())));
+            }
+            Poll::Ready(None) => ReceiverStatus::Closed,
+            Poll::Pending => ReceiverStatus::NotReady,
+        };
+
         let canceled_requests_status = match self.poll_next_cancellation(waker)? {
             Poll::Ready(Some((context, request_id))) => {
                 self.write_cancel(context, request_id)?;
@@ -305,12 +949,34 @@ where
             Poll::Pending => ReceiverStatus::NotReady,
         };
 
-        match (pending_requests_status, canceled_requests_status) {
-            (ReceiverStatus::Closed, ReceiverStatus::Closed) => {
+        let granted_credits_status = match self.poll_next_credit(waker)? {
+            Poll::Ready(Some((context, request_id, credit))) => {
+                self.write_credit(context, request_id, credit)?;
+                return Poll::Ready(Some(Ok(())));
+            }
+            Poll::Ready(None) => ReceiverStatus::Closed,
+            Poll::Pending => ReceiverStatus::NotReady,
+        };
+
+        match (
+            pending_requests_status,
+            pending_inputs_status,
+            canceled_requests_status,
+            granted_credits_status,
+        ) {
+            (
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+            ) => {
                 ready!(self.transport().poll_flush(waker)?);
                 Poll::Ready(None)
             }
-            (ReceiverStatus::NotReady, _) | (_, ReceiverStatus::NotReady) => {
+            (ReceiverStatus::NotReady, _, _, _)
+            | (_, ReceiverStatus::NotReady, _, _)
+            | (_, _, ReceiverStatus::NotReady, _)
+            | (_, _, _, ReceiverStatus::NotReady) => {
                 // No more messages to process, so flush any messages buffered in the transport.
                 ready!(self.transport().poll_flush(waker)?);
 
@@ -326,6 +992,36 @@ where
         self: &mut Pin<&mut Self>,
         waker: &LocalWaker,
     ) -> Poll<Option<io::Result<DispatchRequest<Req, Resp>>>> {
+        if self.server_shutting_down {
+            if self.reconnecting {
+                // Hold new requests for the transport that replaces this one once its in-flight
+                // requests complete.
+                return Poll::Pending;
+            }
+            // The server would reject new requests, so fail them without sending them.
+            loop {
+                match ready!(self.pending_requests().poll_next_unpin(waker)) {
+                    Some(request) => {
+                        debug!(
+                            "[{}] Failing request because the server is shutting down.",
+                            request.ctx.trace_id()
+                        );
+                        request.response_completion.complete(Response {
+                            request_id: request.request_id,
+                            message: Err(ServerError::new(
+                                Code::Unavailable,
+                                "Server is shutting down.",
+                            )),
+                        });
+                    }
+                    None => {
+                        trace!("[{}] pending_requests closed", self.server_addr());
+                        return Poll::Ready(None);
+                    }
+                }
+            }
+        }
+
         if self.in_flight_requests().len() >= self.config.max_in_flight_requests {
             info!(
                 "At in-flight request capacity ({}/{}).",
@@ -343,6 +1039,18 @@ where
             ready!(self.transport().poll_flush(waker)?);
         }
 
+        // Requests requeued from a broken transport were issued first, so they go first.
+        while let Some(request) = self.requeued_requests().pop_front() {
+            if request.response_completion.is_canceled() {
+                trace!(
+                    "[{}] Requeued request canceled before being resent.",
+                    request.ctx.trace_id()
+                );
+                continue;
+            }
+            return Poll::Ready(Some(Ok(request)));
+        }
+
         loop {
             match ready!(self.pending_requests().poll_next_unpin(waker)) {
                 Some(request) => {
@@ -364,6 +1072,36 @@ where
         }
     }
 
+    /// Yields the next pending input to a request that is still in flight, along with the
+    /// request's context. Inputs to requests that completed or were canceled are discarded.
+    fn poll_next_input(
+        self: &mut Pin<&mut Self>,
+        waker: &LocalWaker,
+    ) -> Poll<Option<io::Result<(context::Context, DispatchInput<Req>)>>> {
+        while let Poll::Pending = self.transport().poll_ready(waker)? {
+            ready!(self.transport().poll_flush(waker)?);
+        }
+
+        loop {
+            match ready!(self.pending_inputs().poll_next_unpin(waker)) {
+                Some(input) => match self.in_flight_requests.get(&input.request_id) {
+                    Some(in_flight_data) => {
+                        return Poll::Ready(Some(Ok((in_flight_data.ctx.clone(), input))));
+                    }
+                    None => trace!(
+                        "[{}] Discarding input to request {}, which is no longer in flight.",
+                        self.server_addr,
+                        input.request_id
+                    ),
+                },
+                None => {
+                    trace!("[{}] pending_inputs closed.", self.server_addr());
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+
     /// Yields the next pending cancellation, and, if one is ready, cancels the associated request.
     fn poll_next_cancellation(
         self: &mut Pin<&mut Self>,
@@ -373,6 +1111,10 @@ where
             ready!(self.transport().poll_flush(waker)?);
         }
 
+        if let Some(abandoned_request) = self.abandoned_requests().pop_front() {
+            return Poll::Ready(Some(Ok(abandoned_request)));
+        }
+
         loop {
             match ready!(self.canceled_requests().poll_next_unpin(waker)) {
                 Some(request_id) => {
@@ -396,30 +1138,154 @@ where
         }
     }
 
+    /// Yields the next credit to grant a streaming request that is still in flight, along with the
+    /// request's context. Credit for requests that completed or were canceled is discarded.
+    fn poll_next_credit(
+        self: &mut Pin<&mut Self>,
+        waker: &LocalWaker,
+    ) -> Poll<Option<io::Result<(context::Context, u64, u64)>>> {
+        while let Poll::Pending = self.transport().poll_ready(waker)? {
+            ready!(self.transport().poll_flush(waker)?);
+        }
+
+        loop {
+            let (request_id, credit) = match self.initial_credits().pop_front() {
+                Some(grant) => grant,
+                None => match ready!(self.granted_credits().poll_next_unpin(waker)) {
+                    Some(grant) => grant,
+                    None => {
+                        trace!("[{}] granted_credits closed.", self.server_addr());
+                        return Poll::Ready(None);
+                    }
+                },
+            };
+            match self.in_flight_requests.get(&request_id) {
+                Some(in_flight_data) => {
+                    return Poll::Ready(Some(Ok((in_flight_data.ctx.clone(), request_id, credit))));
+                }
+                None => trace!(
+                    "[{}] Discarding credit for request {}, which is no longer in flight.",
+                    self.server_addr,
+                    request_id
+                ),
+            }
+        }
+    }
+
     fn write_request(
         self: &mut Pin<&mut Self>,
         dispatch_request: DispatchRequest<Req, Resp>,
     ) -> io::Result<()> {
         let request_id = dispatch_request.request_id;
+        let streams_input = dispatch_request.on_sent.is_some();
+        // A stream can't be resumed where it broke off, so streaming requests aren't requeued.
+        let retained_request = match dispatch_request.response_completion {
+            ResponseCompletion::Unary(_) if !streams_input => self
+                .clone_request
+                .map(|clone| clone(&dispatch_request.request)),
+            _ => None,
+        };
         let request = ClientMessage {
             trace_context: dispatch_request.ctx.trace_context,
             message: ClientMessageKind::Request(Request {
                 id: request_id,
                 message: dispatch_request.request,
                 deadline: dispatch_request.ctx.deadline,
+                timeout: if self.config.send_timeouts {
+                    Some(dispatch_request.ctx.timeout())
+                } else {
+                    None
+                },
+                metadata: dispatch_request.ctx.metadata.clone(),
+                streams_input,
             }),
         };
-        self.transport().start_send(request)?;
+        match self.transport().start_send(request) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                warn!(
+                    "[{}/{}] Transport rejected request: {}",
+                    dispatch_request.ctx.trace_id(),
+                    self.server_addr(),
+                    e
+                );
+                dispatch_request.response_completion.complete(Response {
+                    request_id,
+                    message: Err(ServerError::new(
+                        Code::InvalidArgument,
+                        format!("Client could not send request: {}", e),
+                    )),
+                });
+                return Ok(());
+            }
+            result => result?,
+        }
+        let input_credit = dispatch_request.on_sent.map(|on_sent| {
+            // The inputs can now follow the request, the first of them without credit.
+            let (input_credit, credit) = credit::channel(1);
+            let _ = on_sent.send(credit);
+            input_credit
+        });
+        if let ResponseCompletion::Stream(_) = dispatch_request.response_completion {
+            // The server may send the first response without credit.
+            let credit = (self.config.pending_request_buffer as u64).saturating_sub(1);
+            if credit > 0 {
+                self.initial_credits().push_back((request_id, credit));
+            }
+        }
         self.in_flight_requests().insert(
             request_id,
             InFlightData {
                 ctx: dispatch_request.ctx,
                 response_completion: dispatch_request.response_completion,
+                retained_request,
+                input_credit,
             },
         );
         Ok(())
     }
 
+    fn write_input(
+        self: &mut Pin<&mut Self>,
+        context: context::Context,
+        input: DispatchInput<Req>,
+    ) -> io::Result<()> {
+        let trace_id = *context.trace_id();
+        let request_id = input.request_id;
+        let message = match input.input {
+            Some(message) => ClientMessageKind::StreamItem {
+                request_id,
+                message,
+            },
+            None => ClientMessageKind::StreamEnd { request_id },
+        };
+        match self.transport().start_send(ClientMessage {
+            trace_context: context.trace_context,
+            message,
+        }) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                warn!(
+                    "[{}/{}] Transport rejected input: {}",
+                    trace_id,
+                    self.server_addr(),
+                    e
+                );
+                // The request can't succeed without its inputs. The server cancels the request
+                // once its deadline passes.
+                self.complete(Response {
+                    request_id,
+                    message: Err(ServerError::new(
+                        Code::InvalidArgument,
+                        format!("Client could not send input: {}", e),
+                    )),
+                });
+                return Ok(());
+            }
+            result => result?,
+        }
+        trace!("[{}/{}] Input sent.", trace_id, self.server_addr());
+        Ok(())
+    }
+
     fn write_cancel(
         self: &mut Pin<&mut Self>,
         context: context::Context,
@@ -435,6 +1301,73 @@ where
         return Ok(());
     }
 
+    fn write_credit(
+        self: &mut Pin<&mut Self>,
+        context: context::Context,
+        request_id: u64,
+        credit: u64,
+    ) -> io::Result<()> {
+        let trace_id = *context.trace_id();
+        let message = ClientMessage {
+            trace_context: context.trace_context,
+            message: ClientMessageKind::ResponseCredit { request_id, credit },
+        };
+        self.transport().start_send(message)?;
+        trace!(
+            "[{}/{}] Granted credit for {} responses.",
+            trace_id,
+            self.server_addr(),
+            credit
+        );
+        Ok(())
+    }
+
+    fn write_handshake(self: &mut Pin<&mut Self>) -> io::Result<()> {
+        let handshake: Handshake = self
+            .config
+            .handshake
+            .clone()
+            .expect("A handshake is only sent when one is configured.");
+        self.transport().start_send(ClientMessage {
+            trace_context: trace::Context::new_root(),
+            message: ClientMessageKind::Handshake(handshake),
+        })?;
+        *self.handshake() = HandshakeState::Sent;
+        trace!("[{}] Handshake sent.", self.server_addr());
+        Ok(())
+    }
+
+    /// Handles the server's answer to the handshake. A rejection breaks the connection.
+    fn complete_handshake(
+        self: &mut Pin<&mut Self>,
+        result: Result<(), ServerError>,
+    ) -> io::Result<()> {
+        if *self.handshake() != HandshakeState::Sent {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Server answered a handshake that was not sent.",
+            ));
+        }
+        match result {
+            Ok(()) => {
+                trace!("[{}] Handshake accepted.", self.server_addr());
+                *self.handshake() = HandshakeState::Accepted;
+                if let Some(handshake_tx) = self.handshake_tx().take() {
+                    let _ = handshake_tx.send(Ok(()));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                *self.handshake() = HandshakeState::Rejected;
+                let detail = e.detail.unwrap_or_default();
+                if let Some(handshake_tx) = self.handshake_tx().take() {
+                    let _ = handshake_tx.send(Err(handshake_rejected(e.kind, &detail)));
+                }
+                Err(handshake_rejected(e.kind, &detail))
+            }
+        }
+    }
+
     /// Sends a server response to the client task that initiated the associated request.
     fn complete(self: &mut Pin<&mut Self>, response: Response<Resp>) -> bool {
         if let Some(in_flight_data) = self.in_flight_requests().remove(&response.request_id) {
@@ -445,7 +1378,7 @@ where
                 in_flight_data.ctx.trace_id(),
                 self.server_addr()
             );
-            let _ = in_flight_data.response_completion.send(response);
+            in_flight_data.response_completion.complete(response);
             return true;
         }
 
@@ -458,18 +1391,207 @@ where
         // If the response completion was absent, then the request was already canceled.
         false
     }
-}
 
-impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
-where
-    Req: Send,
-    Resp: Send,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>>,
-{
-    type Output = io::Result<()>;
+    /// Lets the client-streaming request with ID `request_id` send `credit` more inputs.
+    fn grant_input_credit(self: &mut Pin<&mut Self>, request_id: u64, credit: u64) {
+        match self.in_flight_requests().get(&request_id) {
+            Some(InFlightData {
+                input_credit: Some(input_credit),
+                ..
+            }) => {
+                // If the inputs ended, the credit is no longer needed.
+                let _ = input_credit.unbounded_send(credit);
+            }
+            _ => debug!(
+                "[{}] No client-streaming request found for request_id = {}.",
+                self.server_addr(),
+                request_id
+            ),
+        }
+    }
 
-    fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
-        trace!("[{}] RequestDispatch::poll", self.server_addr());
+    /// Fails the request that a message the transport skipped, e.g. because it was too large,
+    /// responds to.
+    fn handle_skipped(self: &mut Pin<&mut Self>, skipped: &SkippedMessage<ServerMessageHeader>) {
+        let request_id = match skipped.header {
+            Some(ServerMessageHeader::Response { request_id })
+            | Some(ServerMessageHeader::StreamItem { request_id }) => request_id,
+            _ => {
+                warn!(
+                    "[{}] Transport skipped a message: {}",
+                    self.server_addr(),
+                    skipped.reason
+                );
+                return;
+            }
+        };
+        warn!(
+            "[{}] Transport skipped a response to request {}: {}",
+            self.server_addr(),
+            request_id,
+            skipped.reason
+        );
+        // Ends the request, even if the skipped message was one of a stream of responses, as
+        // the stream is incomplete without it. Any later responses are discarded.
+        let response = Response {
+            request_id,
+            message: Err(ServerError::new(
+                Code::DataLoss,
+                format!("Client could not read response: {}", skipped.reason),
+            )),
+        };
+        self.complete(response);
+    }
+
+    /// Sends one of a stream of server responses to the client task that initiated the associated
+    /// request. A unary request is completed by the first item of a stream, and the rest of the
+    /// stream is canceled.
+    fn complete_stream_item(self: &mut Pin<&mut Self>, response: Response<Resp>) {
+        let request_id = response.request_id;
+        let (is_last, is_unary) = match self.in_flight_requests.get(&request_id) {
+            Some(InFlightData {
+                response_completion: ResponseCompletion::Stream(_),
+                ..
+            }) => (response.message.is_err(), false),
+            Some(_) => (true, true),
+            None => {
+                debug!(
+                    "[{}] No in-flight request found for request_id = {}.",
+                    self.server_addr, request_id
+                );
+                return;
+            }
+        };
+        if is_last {
+            if is_unary && response.message.is_ok() {
+                // The server stops after an error, but otherwise waits for credit to send more.
+                let ctx = self.in_flight_requests[&request_id].ctx.clone();
+                self.abandoned_requests().push_back((ctx, request_id));
+            }
+            self.complete(response);
+            return;
+        }
+        let is_full = match self.in_flight_requests().get_mut(&request_id) {
+            Some(InFlightData {
+                response_completion: ResponseCompletion::Stream(responses),
+                ctx,
+                ..
+            }) => {
+                trace!("[{}] Received stream item.", ctx.trace_id());
+                match responses.try_send(Some(response)) {
+                    Err(ref e) if e.is_full() => true,
+                    // If the stream was dropped, its cancellation is on the way.
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        if is_full {
+            self.fail_overrun_stream(request_id);
+        }
+    }
+
+    /// Fails a streaming request whose server sent more responses than it was granted credit for,
+    /// and so more than the client has room to buffer, and cancels it on the server.
+    fn fail_overrun_stream(self: &mut Pin<&mut Self>, request_id: u64) {
+        if let Some(in_flight_data) = self.in_flight_requests().remove(&request_id) {
+            self.in_flight_requests().compact(0.1);
+            warn!(
+                "[{}/{}] Server sent more responses than it was granted credit for.",
+                in_flight_data.ctx.trace_id(),
+                self.server_addr()
+            );
+            in_flight_data.response_completion.complete(Response {
+                request_id,
+                message: Err(ServerError::new(
+                    Code::Internal,
+                    "Server sent more responses than the client had room for.",
+                )),
+            });
+            self.abandoned_requests()
+                .push_back((in_flight_data.ctx, request_id));
+        }
+    }
+
+    /// Ends the stream of server responses to the request with ID `request_id`.
+    fn complete_stream(self: &mut Pin<&mut Self>, request_id: u64) {
+        if let Some(in_flight_data) = self.in_flight_requests().remove(&request_id) {
+            self.in_flight_requests().compact(0.1);
+            trace!(
+                "[{}/{}] Received end of stream.",
+                in_flight_data.ctx.trace_id(),
+                self.server_addr()
+            );
+            match in_flight_data.response_completion {
+                ResponseCompletion::Stream(responses) => {
+                    // A new sender always has room for one message.
+                    let _ = responses.clone().try_send(None);
+                }
+                response_completion @ ResponseCompletion::Unary(_) => {
+                    response_completion.complete(Response {
+                        request_id,
+                        message: Err(ServerError::new(
+                            Code::DataLoss,
+                            "Server ended the stream without a response.",
+                        )),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Clears out the requests in flight on a broken transport. Requests with a retained copy
+    /// are requeued to be sent on the next transport; the rest are completed with an error when
+    /// their response completions are dropped.
+    fn disconnect(self: &mut Pin<&mut Self>) {
+        let mut in_flight_requests: Vec<_> = self.in_flight_requests().drain().collect();
+        self.in_flight_requests().compact(0.1);
+        // The new transport's server never saw the abandoned requests.
+        self.abandoned_requests().clear();
+        self.initial_credits().clear();
+        self.in_flight_count.store(0, Ordering::Relaxed);
+        // Preserve the order in which the requests were originally issued.
+        in_flight_requests.sort_by_key(|&(request_id, _)| request_id);
+
+        let server_addr = self.server_addr.clone();
+        for (request_id, in_flight_data) in in_flight_requests {
+            match in_flight_data.retained_request {
+                Some(request) => {
+                    trace!(
+                        "[{}/{}] Requeuing in-flight request.",
+                        in_flight_data.ctx.trace_id(),
+                        server_addr
+                    );
+                    self.requeued_requests().push_back(DispatchRequest {
+                        ctx: in_flight_data.ctx,
+                        request_id,
+                        request,
+                        response_completion: in_flight_data.response_completion,
+                        on_sent: None,
+                    });
+                }
+                None => {
+                    trace!(
+                        "[{}/{}] Failing in-flight request.",
+                        in_flight_data.ctx.trace_id(),
+                        server_addr
+                    );
+                }
+            }
+        }
+    }
+
+    /// Replaces a broken transport with a newly-established one.
+    fn set_transport(self: &mut Pin<&mut Self>, transport: C) {
+        *self.server_addr() = peer_addr(&transport);
+        Pin::set(self.transport(), transport.fuse());
+        *self.handshake() = HandshakeState::new(&self.config);
+        *self.server_shutting_down() = false;
+    }
+
+    /// Reads responses and writes requests until neither can make progress, or the connection
+    /// ends.
+    fn pump(self: &mut Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
         loop {
             match (self.pump_read(waker)?, self.pump_write(waker)?) {
                 (read, write @ Poll::Ready(None)) => {
@@ -482,6 +1604,9 @@ where
                     }
                     match read {
                         Poll::Ready(Some(())) => continue,
+                        Poll::Ready(None) if self.reconnecting => {
+                            return Poll::Ready(Err(server_closed()))
+                        }
                         _ => {
                             trace!(
                                 "[{}] read: {:?}, write: {:?}, (not ready)",
@@ -501,6 +1626,20 @@ where
                         write,
                     )
                 }
+                // No responses can arrive after the read half closes, so in-flight and future
+                // requests would otherwise wait out their deadlines instead of being sent on a
+                // new transport.
+                (Poll::Ready(None), _) if self.reconnecting => {
+                    return Poll::Ready(Err(server_closed()))
+                }
+                // The server won't accept new requests, so move to a new transport as soon as
+                // nothing is left in flight on this one.
+                _ if self.reconnecting
+                    && self.server_shutting_down
+                    && self.in_flight_requests().is_empty() =>
+                {
+                    return Poll::Ready(Err(server_shutting_down()))
+                }
                 (read, write) => {
                     trace!(
                         "[{}] read: {:?}, write: {:?} (not ready)",
@@ -515,6 +1654,48 @@ where
     }
 }
 
+impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
+where
+    Req: Send,
+    Resp: Send,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<()>> {
+        trace!("[{}] RequestDispatch::poll", self.server_addr());
+        let result = self.pump(waker);
+        let in_flight_count = self.in_flight_requests.len();
+        self.in_flight_count.store(in_flight_count, Ordering::Relaxed);
+        result
+    }
+}
+
+fn handshake_rejected(kind: io::ErrorKind, detail: &str) -> io::Error {
+    io::Error::new(kind, format!("Server rejected the handshake: {}", detail))
+}
+
+fn handshake_unanswered() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "Connection closed before the server answered the handshake.",
+    )
+}
+
+fn server_shutting_down() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Server is shutting down.".to_string(),
+    )
+}
+
+fn server_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "Server closed the connection.".to_string(),
+    )
+}
+
 /// A server-bound request sent from a [`Channel`] to request dispatch, which will then manage
 /// the lifecycle of the request.
 #[derive(Debug)]
@@ -522,12 +1703,65 @@ struct DispatchRequest<Req, Resp> {
     ctx: context::Context,
     request_id: u64,
     request: Req,
-    response_completion: oneshot::Sender<Response<Resp>>,
+    response_completion: ResponseCompletion<Resp>,
+    /// Notified once the request is written to the wire, if the request streams inputs, which
+    /// must not be written before it. Receives the credit the server grants for the inputs.
+    on_sent: Option<oneshot::Sender<Credit>>,
 }
 
-struct InFlightData<Resp> {
+/// One of the inputs to a client-streaming request, sent from a [`Channel`] to request dispatch.
+/// `None` ends the inputs.
+#[derive(Debug)]
+struct DispatchInput<Req> {
+    request_id: u64,
+    input: Option<Req>,
+}
+
+struct InFlightData<Req, Resp> {
     ctx: context::Context,
-    response_completion: oneshot::Sender<Response<Resp>>,
+    response_completion: ResponseCompletion<Resp>,
+    /// A copy of the request, retained if it should be requeued when the transport breaks.
+    retained_request: Option<Req>,
+    /// Grants credit to the task forwarding the request's inputs, if it streams inputs.
+    input_credit: Option<mpsc::UnboundedSender<u64>>,
+}
+
+/// Delivers the responses to a request to the client task that initiated it.
+#[derive(Debug)]
+enum ResponseCompletion<Resp> {
+    /// Completes a [`DispatchResponse`].
+    Unary(oneshot::Sender<Response<Resp>>),
+    /// Feeds a [`ResponseStream`], ending it with `None`.
+    Stream(mpsc::Sender<Option<Response<Resp>>>),
+}
+
+impl<Resp> ResponseCompletion<Resp> {
+    /// Returns true if the client task is no longer waiting for responses.
+    fn is_canceled(&self) -> bool {
+        match self {
+            ResponseCompletion::Unary(response_completion) => response_completion.is_canceled(),
+            ResponseCompletion::Stream(responses) => responses.is_closed(),
+        }
+    }
+
+    /// Completes the request with its final response. For a streaming request, a successful
+    /// response is the last item of the stream.
+    fn complete(self, response: Response<Resp>) {
+        match self {
+            ResponseCompletion::Unary(response_completion) => {
+                let _ = response_completion.send(response);
+            }
+            ResponseCompletion::Stream(responses) => {
+                // New senders always have room for one message each, so the final response
+                // isn't lost if the stream's buffer is full.
+                let is_ok = response.message.is_ok();
+                let _ = responses.clone().try_send(Some(response));
+                if is_ok {
+                    let _ = responses.clone().try_send(None);
+                }
+            }
+        }
+    }
 }
 
 /// Sends request cancellation signals.
@@ -563,23 +1797,61 @@ impl Stream for CanceledRequests {
     }
 }
 
+/// Sends the credit that streaming requests grant the server for their responses.
+#[derive(Debug, Clone)]
+struct ResponseCredits(mpsc::UnboundedSender<(u64, u64)>);
+
+/// A stream of credit grants: the ID of a streaming request, and the number of responses to it
+/// that the client has made room for.
+#[derive(Debug)]
+struct GrantedCredits(mpsc::UnboundedReceiver<(u64, u64)>);
+
+/// Returns a channel to send credit grants.
+fn response_credits() -> (ResponseCredits, GrantedCredits) {
+    // Unbounded because credit is granted when responses are consumed, which mustn't wait on
+    // dispatch. It's still bounded by the number of responses received.
+    let (tx, rx) = mpsc::unbounded();
+    (ResponseCredits(tx), GrantedCredits(rx))
+}
+
+impl ResponseCredits {
+    /// Allows the server to send `credit` more responses to the request with ID `request_id`.
+    fn grant(&mut self, request_id: u64, credit: u64) {
+        let _ = self.0.unbounded_send((request_id, credit));
+    }
+}
+
+impl Stream for GrantedCredits {
+    type Item = (u64, u64);
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<(u64, u64)>> {
+        self.0.poll_next_unpin(waker)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{CanceledRequests, Channel, RequestCancellation, RequestDispatch};
+    use super::{
+        reconnect, response_credits, CanceledRequests, Channel, HandshakeState,
+        RequestCancellation, RequestDispatch,
+    };
     use crate::{
-        client::Config,
+        client::{Config, ReconnectConfig},
         context,
         transport::{self, channel::UnboundedChannel},
-        ClientMessage, Response,
+        ClientMessage, ClientMessageKind, Code, Response, ServerError, ServerMessage,
     };
     use fnv::FnvHashMap;
     use futures::{Poll, channel::mpsc, prelude::*};
     use futures_test::task::{noop_local_waker_ref};
     use std::{
+        collections::VecDeque,
+        io,
         net::{IpAddr, Ipv4Addr, SocketAddr},
         pin::Pin,
-        sync::atomic::AtomicU64,
+        sync::atomic::{AtomicU64, AtomicUsize},
         sync::Arc,
+        time::{Duration, Instant},
     };
 
     #[test]
@@ -649,32 +1921,288 @@ mod tests {
         assert!(dispatch.poll_next_request(waker).ready().is_none());
     }
 
+    #[test]
+    fn disconnect_fails_in_flight_requests() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+
+        let resp = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        dispatch.write_request(req).unwrap();
+        dispatch.disconnect();
+        assert!(dispatch.in_flight_requests().is_empty());
+
+        let resp = tokio::runtime::current_thread::block_on_all(resp.boxed().compat());
+        assert_eq!(resp.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn disconnect_requeues_in_flight_requests() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        dispatch.clone_request = Some(String::clone);
+
+        let _resp = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        dispatch.write_request(req).unwrap();
+        dispatch.disconnect();
+        assert!(dispatch.in_flight_requests().is_empty());
+
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        assert_eq!(req.request_id, 0);
+        assert_eq!(req.request, "hi".to_string());
+    }
+
+    #[test]
+    fn write_request_sends_timeout() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        dispatch.config.send_timeouts = true;
+
+        let _resp = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        dispatch.write_request(req).unwrap();
+
+        match server_channel.poll_next_unpin(waker).ready().unwrap().message {
+            ClientMessageKind::Request(request) => {
+                let timeout = request.timeout.unwrap();
+                assert!(timeout > Duration::from_secs(0));
+                assert!(timeout <= Duration::from_secs(10));
+            }
+            message => panic!("Expected a request, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn stream_grants_credit() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        dispatch.config.pending_request_buffer = 3;
+
+        let mut responses = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send_stream(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        dispatch.write_request(req).unwrap();
+        // The first response needs no credit, so the request is granted credit for the rest of
+        // the responses the client has room for.
+        let (ctx, request_id, credit) = dispatch.poll_next_credit(waker).ready().unwrap();
+        assert_eq!((request_id, credit), (0, 2));
+        dispatch.write_credit(ctx, request_id, credit).unwrap();
+        server_channel.poll_next_unpin(waker).ready().unwrap();
+        match server_channel.poll_next_unpin(waker).ready().unwrap().message {
+            ClientMessageKind::ResponseCredit { request_id, credit } => {
+                assert_eq!((request_id, credit), (0, 2))
+            }
+            message => panic!("Expected credit, got {:?}", message),
+        }
+
+        // Consuming a response makes room for another.
+        Pin::new(&mut server_channel)
+            .start_send(ServerMessage::StreamItem(Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+            }))
+            .unwrap();
+        dispatch.pump_read(waker).ready();
+        assert!(dispatch.poll_next_credit(waker).unwrap().is_pending());
+        match responses.poll_next_unpin(waker) {
+            Poll::Ready(Some(Ok(response))) => assert_eq!(response, "hello"),
+            response => panic!("Expected a response, got {:?}", response),
+        }
+        let (_, request_id, credit) = dispatch.poll_next_credit(waker).ready().unwrap();
+        assert_eq!((request_id, credit), (0, 1));
+    }
+
+    #[test]
+    fn shutdown_fails_new_requests() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        Pin::new(&mut server_channel)
+            .start_send(ServerMessage::Shutdown)
+            .unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+        dispatch.pump_read(waker).ready();
+
+        let resp = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+        assert!(dispatch.poll_next_request(waker).unwrap().is_pending());
+        assert!(server_channel.poll_next_unpin(waker).is_pending());
+
+        let resp = tokio::runtime::current_thread::block_on_all(resp.boxed().compat());
+        let e = resp.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(ServerError::downcast_ref(&e).unwrap().code, Code::Unavailable);
+    }
+
+    #[test]
+    fn shutdown_holds_requests_for_new_transport() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        dispatch.reconnecting = true;
+        Pin::new(&mut server_channel)
+            .start_send(ServerMessage::Shutdown)
+            .unwrap();
+
+        let mut dispatch = Pin::new(&mut dispatch);
+        let waker = &noop_local_waker_ref();
+        dispatch.pump_read(waker).ready();
+
+        let _resp = tokio::runtime::current_thread::block_on_all(
+            channel
+                .send(context::current(), "hi".into())
+                .boxed()
+                .compat(),
+        ).unwrap();
+        assert!(dispatch.poll_next_request(waker).unwrap().is_pending());
+        match dispatch.pump(waker) {
+            Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionAborted => {}
+            result => panic!("Expected the transport to be abandoned, got {:?}", result),
+        }
+
+        let (client_channel, _server_channel) = transport::channel::unbounded();
+        dispatch.set_transport(client_channel);
+        let req = dispatch.poll_next_request(waker).ready().unwrap();
+        assert_eq!(req.request, "hi".to_string());
+    }
+
+    #[test]
+    fn reconnect_backs_off() {
+        let _ = env_logger::try_init();
+
+        let mut reconnect_config = ReconnectConfig::default();
+        reconnect_config.initial_backoff = Duration::from_millis(10);
+        reconnect_config.max_backoff = Duration::from_millis(20);
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).into();
+
+        // Fails three times before connecting.
+        let mut attempts = vec![];
+        let mut connect = || {
+            attempts.push(Instant::now());
+            future::ready(if attempts.len() > 3 {
+                Ok(attempts.len())
+            } else {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            })
+        };
+        let transport = tokio::runtime::current_thread::block_on_all(
+            reconnect(&mut connect, &reconnect_config, Some(&server_addr))
+                .boxed()
+                .compat(),
+        );
+        assert_eq!(transport.unwrap(), 4);
+
+        let backoffs: Vec<_> = attempts.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(backoffs[0] >= Duration::from_millis(10), "{:?}", backoffs);
+        assert!(backoffs[1] >= Duration::from_millis(20), "{:?}", backoffs);
+        assert!(backoffs[2] >= Duration::from_millis(20), "{:?}", backoffs);
+    }
+
+    #[test]
+    fn reconnect_gives_up() {
+        let _ = env_logger::try_init();
+
+        let mut reconnect_config = ReconnectConfig::default();
+        reconnect_config.initial_backoff = Duration::from_millis(1);
+        reconnect_config.max_attempts = Some(2);
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).into();
+
+        let mut attempts = 0;
+        let mut connect = || {
+            attempts += 1;
+            future::ready(Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused)))
+        };
+        let transport = tokio::runtime::current_thread::block_on_all(
+            reconnect(&mut connect, &reconnect_config, Some(&server_addr))
+                .boxed()
+                .compat(),
+        );
+        assert_eq!(transport.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts, 2);
+    }
+
     fn set_up() -> (
-        RequestDispatch<String, String, UnboundedChannel<Response<String>, ClientMessage<String>>>,
+        RequestDispatch<
+            String,
+            String,
+            UnboundedChannel<ServerMessage<String>, ClientMessage<String>>,
+        >,
         Channel<String, String>,
-        UnboundedChannel<ClientMessage<String>, Response<String>>,
+        UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
     ) {
         let _ = env_logger::try_init();
 
         let (to_dispatch, pending_requests) = mpsc::channel(1);
+        let (to_dispatch_inputs, pending_inputs) = mpsc::channel(1);
         let (cancel_tx, canceled_requests) = mpsc::unbounded();
+        let (credits, granted_credits) = response_credits();
         let (client_channel, server_channel) = transport::channel::unbounded();
 
         let dispatch = RequestDispatch::<String, String, _> {
             transport: client_channel.fuse(),
             pending_requests: pending_requests.fuse(),
+            pending_inputs: pending_inputs.fuse(),
             canceled_requests: CanceledRequests(canceled_requests),
+            granted_credits,
             in_flight_requests: FnvHashMap::default(),
+            requeued_requests: VecDeque::new(),
+            abandoned_requests: VecDeque::new(),
+            initial_credits: VecDeque::new(),
+            clone_request: None,
+            reconnecting: false,
+            server_shutting_down: false,
+            in_flight_count: Arc::new(AtomicUsize::new(0)),
             config: Config::default(),
-            server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).into(),
+            handshake: HandshakeState::Accepted,
+            handshake_tx: None,
         };
 
         let cancellation = RequestCancellation(cancel_tx);
         let channel = Channel {
             to_dispatch,
+            to_dispatch_inputs,
             cancellation,
+            credits,
             next_request_id: Arc::new(AtomicU64::new(0)),
-            server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            in_flight_requests: Arc::new(AtomicUsize::new(0)),
+            in_flight_count: Arc::new(AtomicUsize::new(0)),
+            response_buffer: 1,
+            server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into(),
         };
 
         (dispatch, channel, server_channel)
@@ -0,0 +1,87 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    client::Channel,
+    context::Context,
+    util::chain::{Chain, Remainder, Step},
+};
+use futures::{future::BoxFuture, prelude::*};
+use std::{fmt, io, sync::Arc};
+
+/// Intercepts outgoing calls made by a [`Client`](super::Client), to add cross-cutting behavior
+/// such as logging, authorization, metrics, or fault injection.
+///
+/// An interceptor receives each call's context and request along with a [`Next`] that runs the
+/// remainder of the interceptor chain and then sends the request. Interceptors can modify the
+/// context and request before passing them on, fail the call without sending it by not calling
+/// [`Next::run`], and observe or modify the response by wrapping the returned future.
+pub trait Interceptor<Req, Resp>: Send + Sync + 'static {
+    /// Intercepts a call.
+    fn intercept<'a>(
+        &'a self,
+        ctx: Context,
+        request: Req,
+        next: Next<'a, Req, Resp>,
+    ) -> BoxFuture<'a, io::Result<Resp>>;
+}
+
+impl<Req, Resp, F> Interceptor<Req, Resp> for F
+where
+    F: for<'a> Fn(Context, Req, Next<'a, Req, Resp>) -> BoxFuture<'a, io::Result<Resp>>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn intercept<'a>(
+        &'a self,
+        ctx: Context,
+        request: Req,
+        next: Next<'a, Req, Resp>,
+    ) -> BoxFuture<'a, io::Result<Resp>> {
+        self(ctx, request, next)
+    }
+}
+
+/// The remainder of an interceptor chain, ending in sending the request to the server.
+pub struct Next<'a, Req, Resp> {
+    remainder: Remainder<'a, dyn Interceptor<Req, Resp>, &'a mut Channel<Req, Resp>>,
+}
+
+impl<'a, Req, Resp> fmt::Debug for Next<'a, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Next({} interceptors remaining)", self.remainder.remaining())
+    }
+}
+
+impl<'a, Req, Resp> Next<'a, Req, Resp>
+where
+    Req: Send + 'a,
+    Resp: Send + 'a,
+{
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn Interceptor<Req, Resp>>],
+        channel: &'a mut Channel<Req, Resp>,
+    ) -> Self {
+        Next {
+            remainder: Remainder::new(interceptors, channel),
+        }
+    }
+
+    /// Passes the call to the next interceptor in the chain, or sends the request to the server
+    /// if there are no more interceptors.
+    pub fn run(self, ctx: Context, request: Req) -> BoxFuture<'a, io::Result<Resp>> {
+        match self.remainder.step() {
+            Step::Layer(interceptor, remainder) => {
+                interceptor.intercept(ctx, request, Next { remainder })
+            }
+            Step::Terminal(channel) => channel.call(ctx, request).boxed(),
+        }
+    }
+}
+
+/// The interceptors of a client, outermost first.
+pub(crate) type InterceptorChain<Req, Resp> = Chain<dyn Interceptor<Req, Resp>>;
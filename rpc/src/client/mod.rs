@@ -6,26 +6,101 @@
 
 //! Provides a client that connects to a server and sends multiplexed requests.
 
-use crate::{context::Context, ClientMessage, Response, Transport};
-use log::warn;
+use crate::{context::Context, ClientMessage, Handshake, ServerError, ServerMessage, Transport};
+use futures::{compat::Future01CompatExt, prelude::*};
+use log::{debug, warn};
+use rand::Rng;
 use std::{
-    io,
+    cmp, fmt, io,
     net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
+use tokio_timer::Delay;
 
+pub mod balance;
 mod dispatch;
+mod interceptor;
+
+pub use self::dispatch::ResponseStream;
+pub use self::interceptor::{Interceptor, Next};
+use self::interceptor::InterceptorChain;
 
 /// Sends multiplexed requests to, and receives responses from, a server.
 #[derive(Debug)]
 pub struct Client<Req, Resp> {
-    /// Channel to send requests to the dispatch task.
-    channel: dispatch::Channel<Req, Resp>,
+    /// Channel to send requests to the dispatch task, or to the dispatch task picked for each.
+    channel: Channel<Req, Resp>,
+    /// Governs retries of requests sent via [`call_with_retry`](Client::call_with_retry).
+    retry_policy: Arc<RetryPolicy>,
+    /// Intercepts each call before it is sent to the dispatch task.
+    interceptors: InterceptorChain<Req, Resp>,
 }
 
 impl<Req, Resp> Clone for Client<Req, Resp> {
     fn clone(&self) -> Self {
         Client {
             channel: self.channel.clone(),
+            retry_policy: self.retry_policy.clone(),
+            interceptors: self.interceptors.clone(),
+        }
+    }
+}
+
+/// Where a [`Client`] sends its requests.
+pub(crate) enum Channel<Req, Resp> {
+    /// The dispatch task of the client's transport.
+    Dispatch(dispatch::Channel<Req, Resp>),
+    /// The dispatch task of one of several servers, picked for each request.
+    Balanced(Arc<dyn balance::Pick<Req, Resp>>),
+}
+
+impl<Req, Resp> Channel<Req, Resp> {
+    /// Returns the channel to send a request to: the client's own, or, if the client is
+    /// balanced, the one picked for the request, which is stored in `picked`.
+    fn pick<'a>(
+        &'a mut self,
+        picked: &'a mut Option<dispatch::Channel<Req, Resp>>,
+    ) -> io::Result<&'a mut dispatch::Channel<Req, Resp>> {
+        match self {
+            Channel::Dispatch(channel) => Ok(channel),
+            Channel::Balanced(balanced) => {
+                *picked = Some(balanced.pick()?);
+                Ok(picked.as_mut().unwrap())
+            }
+        }
+    }
+
+    /// Sends a request and resolves to its response.
+    pub(crate) async fn call(&mut self, ctx: Context, request: Req) -> io::Result<Resp> {
+        let mut picked = None;
+        let channel = self.pick(&mut picked)?;
+        await!(channel.call(ctx, request))
+    }
+
+    /// Returns the channel to the client's dispatch task, unless the client is balanced.
+    fn into_dispatch(self) -> Option<dispatch::Channel<Req, Resp>> {
+        match self {
+            Channel::Dispatch(channel) => Some(channel),
+            Channel::Balanced(_) => None,
+        }
+    }
+}
+
+impl<Req, Resp> Clone for Channel<Req, Resp> {
+    fn clone(&self) -> Self {
+        match self {
+            Channel::Dispatch(channel) => Channel::Dispatch(channel.clone()),
+            Channel::Balanced(balanced) => Channel::Balanced(balanced.clone()),
+        }
+    }
+}
+
+impl<Req: fmt::Debug, Resp: fmt::Debug> fmt::Debug for Channel<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Channel::Dispatch(channel) => channel.fmt(f),
+            Channel::Balanced(_) => write!(f, "Balanced"),
         }
     }
 }
@@ -40,8 +115,19 @@ pub struct Config {
     pub max_in_flight_requests: usize,
     /// The number of requests that can be buffered client-side before being sent.
     /// `pending_requests_buffer` controls the size of the channel clients use
-    /// to communicate with the request dispatch task.
+    /// to communicate with the request dispatch task. It also bounds the responses buffered for
+    /// each streaming request: the server sends no more responses than fit, and pauses the
+    /// request's stream of responses until they are consumed.
     pub pending_request_buffer: usize,
+    /// Governs retries of requests sent via [`Client::call_with_retry`].
+    pub retry_policy: RetryPolicy,
+    /// Whether to send each request's remaining time budget along with its deadline. Servers
+    /// measure the budget against their own clock, so that clock skew between client and server
+    /// doesn't shorten or lengthen requests' deadlines.
+    pub send_timeouts: bool,
+    /// If set, the client sends this handshake when it connects, and fails to start if the
+    /// server rejects it. Requests are held back until the server accepts the handshake.
+    pub handshake: Option<Handshake>,
 }
 
 impl Default for Config {
@@ -49,36 +135,254 @@ impl Default for Config {
         Config {
             max_in_flight_requests: 1_000,
             pending_request_buffer: 100,
+            retry_policy: RetryPolicy::default(),
+            send_timeouts: false,
+            handshake: None,
+        }
+    }
+}
+
+/// Settings that control how a client retries idempotent requests that failed transiently.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The kinds of errors that are considered transient. A request that fails with any other
+    /// kind of error is not retried.
+    pub retry_on: Vec<io::ErrorKind>,
+    /// The maximum number of times a request is attempted, including the first attempt.
+    pub max_attempts: usize,
+    /// How long to wait after the first failed attempt before trying again.
+    pub initial_backoff: Duration,
+    /// The backoff doubles after every failed attempt, up to `max_backoff`.
+    pub max_backoff: Duration,
+    /// The fraction of each backoff, between 0 and 1, that is randomized so that many clients
+    /// throttled at the same time don't all retry at the same time.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retry_on: vec![io::ErrorKind::WouldBlock],
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns true if a request that failed with `e` on its `attempt`th attempt should be tried
+    /// again.
+    fn should_retry(&self, e: &io::Error, attempt: usize) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&e.kind())
+    }
+
+    /// Returns the backoff after `backoff`: twice as long, up to `max_backoff`.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff
+            .checked_mul(2)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Returns `backoff` reduced by a random fraction of at most `jitter`.
+    fn jittered(&self, backoff: Duration) -> Duration {
+        let jitter = self.jitter.max(0.).min(1.);
+        let nanos = backoff.as_secs() as f64 * 1e9 + f64::from(backoff.subsec_nanos());
+        let nanos = nanos * (1. - jitter * rand::thread_rng().gen::<f64>());
+        Duration::new((nanos / 1e9) as u64, (nanos % 1e9) as u32)
+    }
+}
+
+/// Settings that control how a reconnecting client re-establishes a broken transport.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    /// How long to wait after the first failed reconnection attempt before trying again. The
+    /// first attempt is made immediately after the transport breaks, or when the client is
+    /// created.
+    pub initial_backoff: Duration,
+    /// The backoff doubles after every failed attempt, up to `max_backoff`.
+    pub max_backoff: Duration,
+    /// The number of consecutive failed attempts after which the client gives up. Once the client
+    /// gives up, it behaves like a client whose connection broke. `None` retries forever.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
         }
     }
 }
 
+/// Determines the fate of requests that were written to the transport but had not yet received
+/// a response when a reconnecting client's transport broke.
+pub struct InFlightPolicy<Req> {
+    /// Copies each request while it's in flight, if in-flight requests are requeued.
+    pub(crate) clone_request: Option<fn(&Req) -> Req>,
+}
+
+impl<Req> InFlightPolicy<Req> {
+    /// Complete in-flight requests with a [`ConnectionReset`](io::ErrorKind::ConnectionReset)
+    /// error.
+    pub fn fail() -> Self {
+        InFlightPolicy {
+            clone_request: None,
+        }
+    }
+}
+
+impl<Req: Clone> InFlightPolicy<Req> {
+    /// Send in-flight requests again, with their original deadlines, once a new transport is
+    /// established. Only appropriate when all requests are safe to process more than once.
+    ///
+    /// A copy of each request is retained until it receives a response.
+    pub fn requeue() -> Self {
+        InFlightPolicy {
+            clone_request: Some(Req::clone as fn(&Req) -> Req),
+        }
+    }
+}
+
+impl<Req> Default for InFlightPolicy<Req> {
+    fn default() -> Self {
+        InFlightPolicy::fail()
+    }
+}
+
+impl<Req> Clone for InFlightPolicy<Req> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Req> Copy for InFlightPolicy<Req> {}
+
+impl<Req> fmt::Debug for InFlightPolicy<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let policy = match self.clone_request {
+            Some(_) => "Requeue",
+            None => "Fail",
+        };
+        write!(f, "InFlightPolicy::{}", policy)
+    }
+}
+
 impl<Req, Resp> Client<Req, Resp>
 where
     Req: Send,
     Resp: Send,
 {
     /// Creates a new Client by wrapping a [`Transport`] and spawning a dispatch task
-    /// that manages the lifecycle of requests.
+    /// that manages the lifecycle of requests. If a [handshake](Config::handshake) is
+    /// configured, resolves once the server accepts it, or fails if the server rejects it.
     ///
     /// Must only be called from on an executor.
     pub async fn new<T>(config: Config, transport: T) -> io::Result<Self>
     where
-        T: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>> + Send,
+        T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send,
     {
         let server_addr = transport.peer_addr().unwrap_or_else(|e| {
             warn!(
                 "Setting peer to unspecified because peer could not be determined: {}",
                 e
             );
-            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into()
         });
 
+        let retry_policy = Arc::new(config.retry_policy.clone());
         Ok(Client {
-            channel: await!(dispatch::spawn(config, transport, server_addr))?,
+            channel: Channel::Dispatch(await!(dispatch::spawn(config, transport, server_addr))?),
+            retry_policy,
+            interceptors: InterceptorChain::default(),
         })
     }
 
+    /// Creates a new Client that establishes its transport with `connect`. Whenever the
+    /// transport breaks, the dispatch task calls `connect` again, backing off as configured by
+    /// `reconnect_config`, so that the client (and all its clones) remain usable across
+    /// transport failures. Establishing the first transport backs off the same way; if the
+    /// client gives up first, it fails with the last error.
+    ///
+    /// Requests issued while reconnecting are buffered, up to
+    /// [`pending_request_buffer`](Config::pending_request_buffer), and are sent once a new
+    /// transport is established. They are still subject to their deadlines. Requests in flight
+    /// when the transport breaks are failed or requeued according to `in_flight_policy`.
+    ///
+    /// Must only be called from on an executor.
+    pub async fn new_reconnecting<T, F, Fut>(
+        config: Config,
+        reconnect_config: ReconnectConfig,
+        in_flight_policy: InFlightPolicy<Req>,
+        connect: F,
+    ) -> io::Result<Self>
+    where
+        Req: 'static,
+        Resp: 'static,
+        T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        let retry_policy = Arc::new(config.retry_policy.clone());
+        Ok(Client {
+            channel: Channel::Dispatch(await!(dispatch::spawn_reconnecting(
+                config,
+                reconnect_config,
+                in_flight_policy,
+                connect
+            ))?),
+            retry_policy,
+            interceptors: InterceptorChain::default(),
+        })
+    }
+
+    /// Creates a client that sends each request to the server picked by `balanced`.
+    fn balanced(balanced: Arc<dyn balance::Pick<Req, Resp>>, retry_policy: RetryPolicy) -> Self {
+        Client {
+            channel: Channel::Balanced(balanced),
+            retry_policy: Arc::new(retry_policy),
+            interceptors: InterceptorChain::default(),
+        }
+    }
+
+    /// Adds `interceptor` to the chain of interceptors that every call made by this client passes
+    /// through. Calls pass through interceptors in the order they were added, and responses pass
+    /// back through them in reverse. Clones of this client made afterward share its interceptors;
+    /// clones made before do not.
+    pub fn intercept<I>(&mut self, interceptor: I)
+    where
+        I: Interceptor<Req, Resp>,
+    {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    /// Returns the number of requests sent through this client (or its clones) that have not yet
+    /// completed.
+    pub fn in_flight_requests(&self) -> usize {
+        match &self.channel {
+            Channel::Dispatch(channel) => channel.in_flight_requests(),
+            Channel::Balanced(balanced) => balanced.in_flight_requests(),
+        }
+    }
+
+    /// Returns true if the client's dispatch task has shut down, e.g. because the connection
+    /// broke. A closed client fails all requests with
+    /// [`ConnectionReset`](io::ErrorKind::ConnectionReset). A
+    /// [balanced](balance::Balanced::into_client) client is closed once the clients of all its
+    /// servers are.
+    pub fn is_closed(&self) -> bool {
+        match &self.channel {
+            Channel::Dispatch(channel) => channel.is_closed(),
+            Channel::Balanced(balanced) => balanced.is_closed(),
+        }
+    }
+
     /// Initiates a request, sending it to the dispatch task.
     ///
     /// Returns a [`Future`] that resolves to this client and the future response
@@ -86,6 +390,194 @@ where
     ///
     /// [`Future`]: futures::Future
     pub async fn call(&mut self, ctx: Context, request: Req) -> io::Result<Resp> {
-        await!(self.channel.call(ctx, request))
+        let interceptors = self.interceptors.get();
+        await!(Next::new(&interceptors, &mut self.channel).run(ctx, request))
+    }
+
+    /// Initiates a request whose reply is a stream of responses, such as a call to a
+    /// server-streaming rpc. Resolves to the stream once the request is enqueued; the stream ends
+    /// when the server ends it, after the first error, or once the context's deadline passes.
+    /// Dropping the stream early cancels the request.
+    ///
+    /// Interceptors only see unary calls, so, rather than bypass them, streaming calls fail if
+    /// the client has any.
+    pub async fn call_stream(
+        &mut self,
+        ctx: Context,
+        request: Req,
+    ) -> io::Result<ResponseStream<Resp>> {
+        self.check_no_interceptors()?;
+        let mut picked = None;
+        let channel = self.channel.pick(&mut picked)?;
+        await!(channel.send_stream(ctx, request))
+    }
+
+    /// Initiates a request followed by a stream of inputs, such as a call to a client-streaming
+    /// rpc. The inputs are sent as `inputs` yields them, until it ends or the request completes.
+    /// `inputs` is only polled while the server has room for more of them, as granted by its
+    /// [`pending_request_buffer`](crate::server::Config::pending_request_buffer), and while at
+    /// most [`pending_request_buffer`](Config::pending_request_buffer) inputs are buffered
+    /// client-side.
+    ///
+    /// Dropping the returned future cancels the request and stops sending its inputs. Like
+    /// [`call_stream`](Client::call_stream), fails if the client has interceptors.
+    pub async fn call_with_inputs<S>(
+        &mut self,
+        ctx: Context,
+        request: Req,
+        inputs: S,
+    ) -> io::Result<Resp>
+    where
+        Req: 'static,
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        self.check_no_interceptors()?;
+        let mut picked = None;
+        let channel = self.channel.pick(&mut picked)?;
+        let response = await!(channel.send_with_inputs(ctx, request, inputs.boxed()))?;
+        await!(response)
+    }
+
+    /// Initiates a request followed by a stream of inputs, whose reply is a stream of responses,
+    /// such as a call to a bidirectional-streaming rpc. See
+    /// [`call_with_inputs`](Client::call_with_inputs) and [`call_stream`](Client::call_stream).
+    pub async fn call_stream_with_inputs<S>(
+        &mut self,
+        ctx: Context,
+        request: Req,
+        inputs: S,
+    ) -> io::Result<ResponseStream<Resp>>
+    where
+        Req: 'static,
+        S: Stream<Item = Req> + Send + 'static,
+    {
+        self.check_no_interceptors()?;
+        let mut picked = None;
+        let channel = self.channel.pick(&mut picked)?;
+        await!(channel.send_stream_with_inputs(ctx, request, inputs.boxed()))
+    }
+
+    /// Fails streaming calls, which interceptors can't see, if the client has interceptors.
+    fn check_no_interceptors(&self) -> io::Result<()> {
+        if self.interceptors.is_empty() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Streaming calls can't pass through the client's interceptors. Make them with a \
+             client that has no interceptors, such as a clone made before any were added.",
+        ))
+    }
+
+    /// Initiates a request, retrying it according to the client's [`RetryPolicy`] if it fails
+    /// transiently. `make_request` is called once per attempt, so the request should be
+    /// idempotent: the server may process it more than once.
+    ///
+    /// A server error's [`retry_after`](ServerError::retry_after) hint is waited out if it's
+    /// longer than the backoff. Retries never extend past the context's deadline. If the next
+    /// backoff would end after the deadline, the last error is returned instead.
+    pub async fn call_with_retry<F>(
+        &mut self,
+        ctx: Context,
+        mut make_request: F,
+    ) -> io::Result<Resp>
+    where
+        F: FnMut() -> Req,
+    {
+        let retry_policy = self.retry_policy.clone();
+        let mut backoff = retry_policy.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let e = match await!(self.call(ctx.clone(), make_request())) {
+                Ok(resp) => return Ok(resp),
+                Err(e) => e,
+            };
+            if !retry_policy.should_retry(&e, attempt) {
+                return Err(e);
+            }
+
+            // The server's hint, e.g. from a server that throttled the request, overrides the
+            // backoff if it's longer.
+            let retry_after = ServerError::downcast_ref(&e).and_then(|e| e.retry_after);
+            let delay = cmp::max(retry_policy.jittered(backoff), retry_after.unwrap_or_default());
+            if delay >= ctx.timeout() {
+                debug!(
+                    "[{}] Not retrying, because backoff of {:?} would exceed the deadline.",
+                    ctx.trace_id(),
+                    delay
+                );
+                return Err(e);
+            }
+            debug!(
+                "[{}] Attempt {} failed: {}. Retrying in {:?}.",
+                ctx.trace_id(),
+                attempt,
+                e,
+                delay
+            );
+            await!(Delay::new(Instant::now() + delay).compat())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+            attempt += 1;
+            backoff = retry_policy.next_backoff(backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::{io, time::Duration};
+
+    #[test]
+    fn should_retry() {
+        let mut retry_policy = RetryPolicy::default();
+        retry_policy.retry_on = vec![io::ErrorKind::WouldBlock, io::ErrorKind::ConnectionReset];
+        retry_policy.max_attempts = 3;
+
+        let transient = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(retry_policy.should_retry(&transient, 1));
+        assert!(retry_policy.should_retry(&transient, 2));
+        assert!(!retry_policy.should_retry(&transient, 3));
+
+        let permanent = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(!retry_policy.should_retry(&permanent, 1));
+    }
+
+    #[test]
+    fn next_backoff() {
+        let mut retry_policy = RetryPolicy::default();
+        retry_policy.max_backoff = Duration::from_millis(150);
+        let backoff = retry_policy.next_backoff(Duration::from_millis(50));
+        assert_eq!(backoff, Duration::from_millis(100));
+        let backoff = retry_policy.next_backoff(backoff);
+        assert_eq!(backoff, Duration::from_millis(150));
+
+        // Doubling a long backoff would overflow.
+        retry_policy.max_backoff = Duration::new(u64::max_value(), 0);
+        let backoff = Duration::new(u64::max_value() / 2 + 1, 0);
+        assert_eq!(retry_policy.next_backoff(backoff), retry_policy.max_backoff);
+    }
+
+    #[test]
+    fn jittered() {
+        let backoff = Duration::from_millis(100);
+        let mut retry_policy = RetryPolicy::default();
+
+        retry_policy.jitter = 0.;
+        assert_eq!(retry_policy.jittered(backoff), backoff);
+
+        retry_policy.jitter = 0.5;
+        for _ in 0..100 {
+            let jittered = retry_policy.jittered(backoff);
+            assert!(jittered <= backoff, "{:?}", jittered);
+            assert!(jittered >= backoff / 2, "{:?}", jittered);
+        }
+
+        // Jitter is clamped to the whole backoff.
+        retry_policy.jitter = 2.;
+        for _ in 0..100 {
+            assert!(retry_policy.jittered(backoff) <= backoff);
+        }
     }
 }
@@ -4,18 +4,36 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-//! Provides a request context that carries a deadline and trace context. This context is sent from
-//! client to server and is used by the server to enforce response deadlines.
+//! Provides a request context that carries a deadline, trace context, and metadata. This context
+//! is sent from client to server and is used by the server to enforce response deadlines.
 
-use std::time::{Duration, SystemTime};
+use crate::util::AsDuration;
+use futures::{
+    prelude::*,
+    task::{LocalWaker, Poll, SpawnError},
+};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime},
+};
 use trace::{self, TraceId};
 
+thread_local! {
+    /// The context of the request whose future is being polled on this thread, if any.
+    static CURRENT: RefCell<Option<Context>> = RefCell::new(None);
+}
+
 /// A request context that carries request-scoped information like deadlines and trace information.
 /// It is sent from client to server and is used by the server to enforce response deadlines.
 ///
 /// The context should not be stored directly in a server implementation, because the context will
 /// be different for each request in scope.
-#[derive(Clone, Copy, Debug)]
+///
+/// Because it owns its [`metadata`](Context::metadata), `Context` is `Clone` but not `Copy`, as it
+/// was in tarpc 0.13: code that uses a context after passing it by value must clone it first.
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct Context {
     /// When the client expects the request to be complete by. The server should cancel the request
@@ -26,14 +44,90 @@ pub struct Context {
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// Request-scoped key/value pairs, such as auth tokens, tenant IDs, or feature flags. Metadata
+    /// is sent to the server along with the request, and, because handlers' calls to [`current`]
+    /// return a context with the request's metadata, it propagates to any downstream requests
+    /// made with that context.
+    pub metadata: BTreeMap<String, String>,
+    /// The monotonic equivalent of `deadline`, if the deadline was received as a relative
+    /// timeout. Only used while `deadline` is unchanged, so that code assigning a new `deadline`
+    /// doesn't need to know about it.
+    monotonic_deadline: Option<(SystemTime, Instant)>,
 }
 
 /// Returns the context for the current request, or a default Context if no request is active.
-// TODO: populate Context with request-scoped data, with default fallbacks.
+///
+/// A request is active while its handler's future, or a future spawned with [`spawn`] by the
+/// handler, is being polled. The returned context has the request's deadline and a new span in
+/// the request's trace, whose parent is the request's span.
 pub fn current() -> Context {
-    Context {
-        deadline: SystemTime::now() + Duration::from_secs(10),
-        trace_context: trace::Context::new_root(),
+    match CURRENT.with(|current| current.borrow().clone()) {
+        Some(ctx) => Context {
+            trace_context: ctx.trace_context.new_child(),
+            ..ctx
+        },
+        None => Context {
+            deadline: SystemTime::now() + Duration::from_secs(10),
+            trace_context: trace::Context::new_root(),
+            metadata: BTreeMap::new(),
+            monotonic_deadline: None,
+        },
+    }
+}
+
+/// Spawns `future` on the executor passed to [`init`](crate::init), such that the current
+/// request, if any, remains active while `future` is polled.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
+    match CURRENT.with(|current| current.borrow().clone()) {
+        Some(ctx) => crate::spawn(WithContext::new(ctx, future)),
+        None => crate::spawn(future),
+    }
+}
+
+/// A future that makes a context the [`current`] context while its inner future is polled.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct WithContext<F> {
+    /// Moved into `CURRENT` while `future` is polled, so `None` only during a poll.
+    ctx: Option<Context>,
+    future: F,
+}
+
+impl<F> WithContext<F> {
+    /// Returns a future that makes `ctx` the current context while `future` is polled.
+    pub fn new(ctx: Context, future: F) -> Self {
+        WithContext {
+            ctx: Some(ctx),
+            future,
+        }
+    }
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<F::Output> {
+        // Safe because `future` is never moved out of, and `ctx` isn't pinned.
+        let this = unsafe { Pin::get_mut_unchecked(self) };
+        let previous = CURRENT.with(|current| current.replace(this.ctx.take()));
+        // Moves the context back and restores the previous one, even if the inner future panics.
+        let _restore = Restore {
+            ctx: &mut this.ctx,
+            previous,
+        };
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(waker)
+    }
+}
+
+struct Restore<'a> {
+    ctx: &'a mut Option<Context>,
+    previous: Option<Context>,
+}
+
+impl<'a> Drop for Restore<'a> {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        *self.ctx = CURRENT.with(|current| current.replace(previous));
     }
 }
 
@@ -42,4 +136,65 @@ impl Context {
     pub fn trace_id(&self) -> &TraceId {
         &self.trace_context.trace_id
     }
+
+    /// Returns the time remaining until the deadline, or a duration of 0 if the deadline has
+    /// passed.
+    ///
+    /// If the deadline was received as a relative timeout, the time remaining is measured with
+    /// the monotonic clock, so that it is unaffected by adjustments to the system clock.
+    pub fn timeout(&self) -> Duration {
+        match self.monotonic_deadline {
+            Some((deadline, instant)) if deadline == self.deadline => {
+                let now = Instant::now();
+                if instant > now {
+                    instant - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            }
+            _ => self.deadline.as_duration(),
+        }
+    }
+
+    /// Moves the deadline earlier, if needed, so that it's at most `timeout` from now.
+    ///
+    /// A request handler that caps its own context this way should also cap its reply with
+    /// [`Capped`](crate::server::Capped), as the server otherwise allows the request until the
+    /// client's deadline.
+    pub fn limit_timeout(&mut self, timeout: Duration) {
+        if timeout < self.timeout() {
+            let deadline = SystemTime::now() + timeout;
+            self.deadline = deadline;
+            self.monotonic_deadline = Some((deadline, Instant::now() + timeout));
+        }
+    }
+
+    /// Creates a context with a deadline `timeout` from now, measured on this machine's clocks.
+    pub(crate) fn with_timeout(
+        timeout: Duration,
+        trace_context: trace::Context,
+        metadata: BTreeMap<String, String>,
+    ) -> Context {
+        let deadline = SystemTime::now() + timeout;
+        Context {
+            deadline,
+            trace_context,
+            metadata,
+            monotonic_deadline: Some((deadline, Instant::now() + timeout)),
+        }
+    }
+
+    /// Creates a context with the given absolute deadline.
+    pub(crate) fn with_deadline(
+        deadline: SystemTime,
+        trace_context: trace::Context,
+        metadata: BTreeMap<String, String>,
+    ) -> Context {
+        Context {
+            deadline,
+            trace_context,
+            metadata,
+            monotonic_deadline: None,
+        }
+    }
 }
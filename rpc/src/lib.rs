@@ -36,10 +36,21 @@
 //!        * When the client reaches the in-flight request max, messages are buffered up to a
 //!          configurable maximum, beyond which the requests are back-pressured.
 //!    * Server connections.
-//!        * Total and per-IP limits.
+//!        * Total and per-source limits, where a source is an authenticated identity or an IP.
 //!        * When an incoming connection is accepted, if already at maximum, the connection is
 //!          dropped.
 //! * Transport agnostic.
+//! * Callbacks from servers to their clients over the clients' connections, with
+//!   [duplex transports](transport::duplex).
+//! * An optional [handshake](Handshake) that rejects clients speaking a different protocol
+//!   version or built from a different definition of the service.
+//!
+//! # Wire protocol
+//!
+//! Clients send [`ClientMessage`]s and servers send [`ServerMessage`]s. The protocol is versioned
+//! by [`PROTOCOL_VERSION`]. It is not compatible with tarpc 0.13, whose servers sent bare
+//! [`Response`]s: version 1 wrapped responses in [`ServerMessage`] so that servers can also
+//! answer handshakes.
 
 pub mod client;
 pub mod context;
@@ -49,8 +60,18 @@ pub(crate) mod util;
 
 pub use crate::{client::Client, server::Server, transport::Transport};
 
+use fnv::FnvHasher;
 use futures::{Future, task::{Spawn, SpawnExt, SpawnError}};
-use std::{cell::RefCell, io, sync::Once, time::SystemTime};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    hash::Hasher,
+    io,
+    sync::Once,
+    time::{Duration, SystemTime},
+};
 
 /// A message from a client to a server.
 #[derive(Debug)]
@@ -90,6 +111,35 @@ pub enum ClientMessageKind<T> {
         /// The ID of the request to cancel.
         request_id: u64,
     },
+    /// One of a stream of inputs to a request that was sent with
+    /// [`streams_input`](Request::streams_input) set. The request's handler takes the inputs from
+    /// its [`Extras`](server::Extras) as [`Inputs`](server::Inputs).
+    StreamItem {
+        /// The ID of the request the input belongs to.
+        request_id: u64,
+        /// The input.
+        message: T,
+    },
+    /// Half-closes the stream of inputs to a request: the client sends no more inputs, but is
+    /// still waiting for the request's responses.
+    StreamEnd {
+        /// The ID of the request whose inputs are complete.
+        request_id: u64,
+    },
+    /// Describes the service the client expects to talk to. When configured to, a client sends
+    /// a handshake before any requests, and waits for the server to accept it; see
+    /// [`ServerMessage::Handshake`].
+    Handshake(Handshake),
+    /// Allows the server to send `credit` more [`StreamItem`](ServerMessage::StreamItem)s in
+    /// response to a request, which the client has room to buffer. The server may send the first
+    /// item of each request without credit, and pauses the request's stream of responses when it
+    /// runs out.
+    ResponseCredit {
+        /// The ID of the request whose responses the credit is for.
+        request_id: u64,
+        /// The number of responses the server may send.
+        credit: u64,
+    },
 }
 
 /// A request from a client to a server.
@@ -108,13 +158,33 @@ pub struct Request<T> {
     /// if it is not complete by this time.
     #[cfg_attr(
         feature = "serde",
-        serde(serialize_with = "util::serde::serialize_epoch_secs")
+        serde(serialize_with = "util::serde::serialize_epoch_nanos")
     )]
     #[cfg_attr(
         feature = "serde",
-        serde(deserialize_with = "util::serde::deserialize_epoch_secs")
+        serde(deserialize_with = "util::serde::deserialize_epoch_nanos")
     )]
     pub deadline: SystemTime,
+    /// The time remaining until `deadline` when the request was sent, if the client sends
+    /// relative timeouts. When present, the server measures the deadline from when it receives
+    /// the request, using its own clock, instead of trusting `deadline`, so that clock skew
+    /// between client and server doesn't affect the request's budget.
+    ///
+    /// The field is part of the encoding of every request, even when the client doesn't send
+    /// timeouts; see [`PROTOCOL_VERSION`]. Self-describing formats may omit it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timeout: Option<Duration>,
+    /// Request-scoped key/value pairs set by the client. See
+    /// [`Context::metadata`](context::Context::metadata).
+    ///
+    /// The field is part of the encoding of every request, even when the map is empty; see
+    /// [`PROTOCOL_VERSION`]. Self-describing formats may omit it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metadata: BTreeMap<String, String>,
+    /// Whether the client follows the request with a stream of inputs, in
+    /// [`StreamItem`](ClientMessageKind::StreamItem) messages ending with a
+    /// [`StreamEnd`](ClientMessageKind::StreamEnd).
+    pub streams_input: bool,
 }
 
 /// A response from a server to a client.
@@ -132,7 +202,13 @@ pub struct Response<T> {
 }
 
 /// An error response from a server to a client.
-#[derive(Debug, PartialEq, Eq)]
+///
+/// Request handlers can fail with a `ServerError` of their own, wrapped in an [`io::Error`], to
+/// choose the code, retry hint and details that the client receives.
+///
+/// Fields may be added in later releases, so construct a `ServerError` with [`ServerError::new`]
+/// and its `with_*` methods rather than a struct literal.
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(
     feature = "serde",
     derive(serde::Serialize, serde::Deserialize)
@@ -147,23 +223,496 @@ pub struct ServerError {
         feature = "serde",
         serde(deserialize_with = "util::serde::deserialize_io_error_kind_from_u32")
     )]
-    /// The type of error that occurred to fail the request.
+    /// The type of error that occurred to fail the request, as seen by the client's
+    /// [`io::Error`].
     pub kind: io::ErrorKind,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "util::serde::serialize_code_as_u32")
+    )]
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "util::serde::deserialize_code_from_u32")
+    )]
+    /// The canonical code of the error.
+    pub code: Code,
     /// A message describing more detail about the error that occurred.
     pub detail: Option<String>,
+    /// How long the client should wait before trying the request again, e.g. when the server is
+    /// throttling requests.
+    pub retry_after: Option<Duration>,
+    /// Further information about the error, keyed by what it describes, e.g. by the name of the
+    /// type encoded in the value.
+    pub details: BTreeMap<String, Vec<u8>>,
+}
+
+impl ServerError {
+    /// Returns an error with the given code and detail, of the kind that corresponds to the code.
+    pub fn new(code: Code, detail: impl Into<String>) -> Self {
+        ServerError {
+            kind: code.kind(),
+            code,
+            detail: Some(detail.into()),
+            retry_after: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the error with a hint to retry the request after `retry_after`.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Returns the error with `value` added to its details under `key`.
+    pub fn with_detail(mut self, key: impl Into<String>, value: Vec<u8>) -> Self {
+        self.details.insert(key.into(), value);
+        self
+    }
+
+    /// Returns the server error that `e` was converted from, if any.
+    pub fn downcast_ref(e: &io::Error) -> Option<&ServerError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        if ServerError::downcast_ref(&e).is_some() {
+            return *e.into_inner().unwrap().downcast().unwrap();
+        }
+        ServerError {
+            kind: e.kind(),
+            code: e.kind().into(),
+            detail: Some(e.description().into()),
+            retry_after: None,
+            details: BTreeMap::new(),
+        }
+    }
 }
 
 impl From<ServerError> for io::Error {
     fn from(e: ServerError) -> io::Error {
-        io::Error::new(e.kind, e.detail.unwrap_or_default())
+        io::Error::new(e.kind, e)
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{}", detail),
+            None => write!(f, "{}", self.code),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn description(&self) -> &str {
+        match &self.detail {
+            Some(detail) => detail,
+            None => self.code.as_str(),
+        }
+    }
+}
+
+/// The canonical code of a [`ServerError`], which tells the client how the request failed
+/// independently of the transport and of the [`io::ErrorKind`] it's reported as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Code {
+    /// The request was cancelled, typically by the client.
+    Cancelled,
+    /// The cause of the error is unknown, e.g. because it came from a peer that speaks a newer
+    /// protocol.
+    Unknown,
+    /// The request was invalid, regardless of the state of the server.
+    InvalidArgument,
+    /// The request didn't complete before its deadline.
+    DeadlineExceeded,
+    /// Something the request refers to wasn't found.
+    NotFound,
+    /// Something the request tried to create already exists.
+    AlreadyExists,
+    /// The client isn't allowed to make the request.
+    PermissionDenied,
+    /// A resource, such as the server's capacity for in-flight requests, is exhausted.
+    ResourceExhausted,
+    /// The server isn't in the state the request requires, e.g. it serves a different service.
+    FailedPrecondition,
+    /// The request was aborted, e.g. because of a conflict with a concurrent request.
+    Aborted,
+    /// The request referred to something past the end of a valid range.
+    OutOfRange,
+    /// The server doesn't implement the request.
+    Unimplemented,
+    /// The server failed in a way that it didn't expect.
+    Internal,
+    /// The server is unavailable, e.g. because it's shutting down. Trying the request again later
+    /// may succeed.
+    Unavailable,
+    /// Data was lost or corrupted.
+    DataLoss,
+    /// The client didn't provide valid credentials.
+    Unauthenticated,
+}
+
+impl Code {
+    /// Returns the kind of [`io::Error`] that errors with this code are reported as by default.
+    pub fn kind(self) -> io::ErrorKind {
+        match self {
+            Code::Cancelled => io::ErrorKind::Interrupted,
+            Code::InvalidArgument | Code::OutOfRange => io::ErrorKind::InvalidInput,
+            Code::DeadlineExceeded => io::ErrorKind::TimedOut,
+            Code::NotFound => io::ErrorKind::NotFound,
+            Code::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Code::PermissionDenied | Code::Unauthenticated => io::ErrorKind::PermissionDenied,
+            Code::ResourceExhausted => io::ErrorKind::WouldBlock,
+            Code::FailedPrecondition | Code::DataLoss => io::ErrorKind::InvalidData,
+            Code::Unavailable => io::ErrorKind::ConnectionAborted,
+            Code::Unknown | Code::Aborted | Code::Unimplemented | Code::Internal => {
+                io::ErrorKind::Other
+            }
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Code::Cancelled => "cancelled",
+            Code::Unknown => "unknown",
+            Code::InvalidArgument => "invalid argument",
+            Code::DeadlineExceeded => "deadline exceeded",
+            Code::NotFound => "not found",
+            Code::AlreadyExists => "already exists",
+            Code::PermissionDenied => "permission denied",
+            Code::ResourceExhausted => "resource exhausted",
+            Code::FailedPrecondition => "failed precondition",
+            Code::Aborted => "aborted",
+            Code::OutOfRange => "out of range",
+            Code::Unimplemented => "unimplemented",
+            Code::Internal => "internal",
+            Code::Unavailable => "unavailable",
+            Code::DataLoss => "data loss",
+            Code::Unauthenticated => "unauthenticated",
+        }
+    }
+}
+
+impl From<io::ErrorKind> for Code {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => Code::NotFound,
+            io::ErrorKind::PermissionDenied => Code::PermissionDenied,
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => Code::Unavailable,
+            io::ErrorKind::AlreadyExists => Code::AlreadyExists,
+            io::ErrorKind::WouldBlock => Code::ResourceExhausted,
+            io::ErrorKind::InvalidInput => Code::InvalidArgument,
+            io::ErrorKind::InvalidData => Code::FailedPrecondition,
+            io::ErrorKind::TimedOut => Code::DeadlineExceeded,
+            io::ErrorKind::Interrupted => Code::Cancelled,
+            io::ErrorKind::UnexpectedEof => Code::OutOfRange,
+            _ => Code::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A message from a server to a client.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[non_exhaustive]
+pub enum ServerMessage<T> {
+    /// A response to a request.
+    Response(Response<T>),
+    /// One of a stream of responses to a request. The server sends no more items after an
+    /// error, and ends every stream with [`StreamEnd`](ServerMessage::StreamEnd).
+    StreamItem(Response<T>),
+    /// Marks the end of the stream of responses to a request.
+    StreamEnd {
+        /// The ID of the request the stream responds to.
+        request_id: u64,
+    },
+    /// The server's answer to a client's [`Handshake`]: `Ok` if the server serves the service
+    /// the client expects, or an error describing the mismatch. A server that rejects a
+    /// handshake closes the connection after sending its answer.
+    Handshake(Result<(), ServerError>),
+    /// Tells the client that the server has begun shutting down: it finishes the requests already
+    /// in flight, but rejects new ones. A client that can reconnect should send its new requests
+    /// elsewhere. See [`server::Shutdown`].
+    Shutdown,
+    /// Allows the client to send `credit` more [`StreamItem`](ClientMessageKind::StreamItem)s to
+    /// a request, which the server has room to buffer. The client may send the first input to
+    /// each request without credit, and stops taking inputs from the request's stream of inputs
+    /// when it runs out.
+    InputCredit {
+        /// The ID of the request whose inputs the credit is for.
+        request_id: u64,
+        /// The number of inputs the client may send.
+        credit: u64,
+    },
+}
+
+impl<T> From<Response<T>> for ServerMessage<T> {
+    fn from(response: Response<T>) -> Self {
+        ServerMessage::Response(response)
     }
 }
 
+/// The start of a [`ClientMessage`], which identifies the request the message belongs to. See
+/// [`transport::Message`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[non_exhaustive]
+pub struct ClientMessageHeader {
+    /// The trace context of the message.
+    pub trace_context: trace::Context,
+    /// What kind of message it is.
+    #[cfg_attr(feature = "serde", serde(rename = "message"))]
+    pub kind: ClientMessageHeaderKind,
+}
+
+/// The start of a [`ClientMessageKind`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+#[non_exhaustive]
+pub enum ClientMessageHeaderKind {
+    /// The start of a [`Request`](ClientMessageKind::Request).
+    Request {
+        /// The ID of the request.
+        id: u64,
+    },
+    /// The start of a [`Cancel`](ClientMessageKind::Cancel).
+    Cancel {
+        /// The ID of the request to cancel.
+        request_id: u64,
+    },
+    /// The start of a [`StreamItem`](ClientMessageKind::StreamItem).
+    StreamItem {
+        /// The ID of the request the input belongs to.
+        request_id: u64,
+    },
+    /// The start of a [`StreamEnd`](ClientMessageKind::StreamEnd).
+    StreamEnd {
+        /// The ID of the request whose inputs are complete.
+        request_id: u64,
+    },
+    /// The start of a [`Handshake`](ClientMessageKind::Handshake).
+    Handshake,
+    /// The start of a [`ResponseCredit`](ClientMessageKind::ResponseCredit).
+    ResponseCredit {
+        /// The ID of the request whose responses the credit is for.
+        request_id: u64,
+    },
+}
+
+impl ClientMessageHeader {
+    /// Returns the ID of the request the message belongs to, unless it's a handshake.
+    pub fn request_id(&self) -> Option<u64> {
+        match self.kind {
+            ClientMessageHeaderKind::Request { id } => Some(id),
+            ClientMessageHeaderKind::Cancel { request_id }
+            | ClientMessageHeaderKind::StreamItem { request_id }
+            | ClientMessageHeaderKind::StreamEnd { request_id }
+            | ClientMessageHeaderKind::ResponseCredit { request_id } => Some(request_id),
+            ClientMessageHeaderKind::Handshake => None,
+        }
+    }
+}
+
+impl<T> transport::Message for ClientMessage<T> {
+    type Header = ClientMessageHeader;
+}
+
+/// The start of a [`ServerMessage`], which identifies the request the message responds to. See
+/// [`transport::Message`].
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+#[non_exhaustive]
+pub enum ServerMessageHeader {
+    /// The start of a [`Response`](ServerMessage::Response).
+    Response {
+        /// The ID of the request being responded to.
+        request_id: u64,
+    },
+    /// The start of a [`StreamItem`](ServerMessage::StreamItem).
+    StreamItem {
+        /// The ID of the request being responded to.
+        request_id: u64,
+    },
+    /// The start of a [`StreamEnd`](ServerMessage::StreamEnd).
+    StreamEnd {
+        /// The ID of the request the stream responds to.
+        request_id: u64,
+    },
+    /// The start of a [`Handshake`](ServerMessage::Handshake).
+    Handshake,
+    /// The start of a [`Shutdown`](ServerMessage::Shutdown).
+    Shutdown,
+    /// The start of an [`InputCredit`](ServerMessage::InputCredit).
+    InputCredit {
+        /// The ID of the request whose inputs the credit is for.
+        request_id: u64,
+    },
+}
+
+impl ServerMessageHeader {
+    /// Returns the ID of the request the message responds to, unless it's a handshake or a
+    /// shutdown notice.
+    pub fn request_id(&self) -> Option<u64> {
+        match *self {
+            ServerMessageHeader::Response { request_id }
+            | ServerMessageHeader::StreamItem { request_id }
+            | ServerMessageHeader::StreamEnd { request_id }
+            | ServerMessageHeader::InputCredit { request_id } => Some(request_id),
+            ServerMessageHeader::Handshake | ServerMessageHeader::Shutdown => None,
+        }
+    }
+}
+
+impl<T> transport::Message for ServerMessage<T> {
+    type Header = ServerMessageHeader;
+}
+
+/// The version of the protocol spoken by this crate's clients and servers. It changes whenever
+/// [`ClientMessage`] or [`ServerMessage`] change incompatibly.
+///
+/// Clients and servers that speak different versions can't understand each other's messages
+/// over formats that encode fields by position, such as bincode. Configure a [`Handshake`] to
+/// detect the mismatch when they connect.
+///
+/// # Version history
+///
+/// - Unversioned: the protocol of tarpc 0.13, whose servers sent bare [`Response`]s.
+/// - 1: servers wrap responses in [`ServerMessage`], which can also carry a [`Handshake`].
+/// - 2: [`Request`] gained [`timeout`](Request::timeout) and [`metadata`](Request::metadata).
+/// - 3: [`ServerMessage`] gained [`StreamItem`](ServerMessage::StreamItem) and
+///   [`StreamEnd`](ServerMessage::StreamEnd), for server-streaming rpcs.
+/// - 4: [`ClientMessageKind`] gained [`StreamItem`](ClientMessageKind::StreamItem) and
+///   [`StreamEnd`](ClientMessageKind::StreamEnd), and [`Request`] gained
+///   [`streams_input`](Request::streams_input), for client-streaming rpcs.
+/// - 5: [`ServerMessage`] gained [`Shutdown`](ServerMessage::Shutdown), which servers send when
+///   they begin shutting down.
+/// - 6: [`ClientMessageKind`] gained [`ResponseCredit`](ClientMessageKind::ResponseCredit),
+///   with which clients pace the responses to server-streaming rpcs.
+/// - 7: [`ServerMessage`] gained [`InputCredit`](ServerMessage::InputCredit), with which servers
+///   pace the inputs to client-streaming rpcs.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Describes a service, so that a client and server built from different definitions of the
+/// service can detect the mismatch when they connect, rather than failing to deserialize
+/// messages mid-stream.
+///
+/// Services defined with `tarpc::service!` generate a `handshake()` fn that returns their
+/// handshake. Set it in both the [client's](client::Config::handshake) and the
+/// [server's](server::Config::handshake) config to enable the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[non_exhaustive]
+pub struct Handshake {
+    /// The version of the protocol spoken by the sender; see [`PROTOCOL_VERSION`].
+    pub protocol_version: u32,
+    /// The name of the service.
+    pub service_name: String,
+    /// A hash of the service's schema, e.g. the signatures of its rpcs.
+    pub schema_fingerprint: u64,
+}
+
+impl Handshake {
+    /// Returns a handshake for the service named `service_name`, whose schema is described by
+    /// `schema`. Any change to `schema` other than to whitespace changes the handshake's
+    /// fingerprint. Whitespace is normalized, so that a schema produced by `stringify!` has the
+    /// same fingerprint however the compiler spaces its tokens.
+    pub fn new(service_name: impl Into<String>, schema: &str) -> Self {
+        let mut hasher = FnvHasher::default();
+        hasher.write(normalize_whitespace(schema).as_bytes());
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            service_name: service_name.into(),
+            schema_fingerprint: hasher.finish(),
+        }
+    }
+
+    /// Renames the service, e.g. to keep its name stable when the module defining it is renamed.
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = service_name.into();
+        self
+    }
+
+    /// Checks that a server with this handshake can serve a client that sent `client`.
+    pub fn check(&self, client: &Handshake) -> Result<(), ServerError> {
+        let detail = if client.protocol_version != self.protocol_version {
+            format!(
+                "Client speaks protocol version {}, but server speaks version {}.",
+                client.protocol_version, self.protocol_version
+            )
+        } else if client.service_name != self.service_name {
+            format!(
+                "Client expects service {:?}, but server serves {:?}.",
+                client.service_name, self.service_name
+            )
+        } else if client.schema_fingerprint != self.schema_fingerprint {
+            format!(
+                "Client and server have different definitions of service {:?} \
+                 (schema fingerprints {:016x} and {:016x}).",
+                self.service_name, client.schema_fingerprint, self.schema_fingerprint
+            )
+        } else {
+            return Ok(());
+        };
+        Err(ServerError::new(Code::FailedPrecondition, detail))
+    }
+}
+
+/// Removes whitespace from `schema`, except for a single space between two words, which would
+/// otherwise run together.
+fn normalize_whitespace(schema: &str) -> String {
+    fn is_word(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    let mut normalized = String::with_capacity(schema.len());
+    let mut pending_space = false;
+    for c in schema.chars() {
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if pending_space && is_word(c) && normalized.chars().next_back().map_or(false, is_word) {
+            normalized.push(' ');
+        }
+        pending_space = false;
+        normalized.push(c);
+    }
+    normalized
+}
+
 impl<T> Request<T> {
     /// Returns the deadline for this request.
     pub fn deadline(&self) -> &SystemTime {
         &self.deadline
     }
+
+    /// Returns the time remaining until the deadline when the request was sent, if the client
+    /// sent it.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
 }
 
 static INIT: Once = Once::new();
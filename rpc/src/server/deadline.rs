@@ -0,0 +1,128 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context::{Context, WithContext},
+    server::Reply,
+    util::deadline_compat::Deadline,
+};
+use futures::{
+    compat::{Compat01As03, Future01CompatExt},
+    future::Either,
+    prelude::*,
+    ready,
+    stream::BoxStream,
+    task::{LocalWaker, Poll},
+};
+use std::{io, pin::Pin, time::Instant};
+use tokio_timer::{timeout, Delay};
+
+/// A request handler's reply, limited to the deadline of a context whose timeout the handler
+/// capped with [`Context::limit_timeout`], e.g. because the client allows the request more time
+/// than the handler should take. Services defined with `tarpc::service!` cap the rpcs declared
+/// with a `#[timeout]` this way.
+///
+/// The reply fails with a [`TimedOut`](io::ErrorKind::TimedOut) error, which the server sends as
+/// [`DeadlineExceeded`](crate::Code::DeadlineExceeded), if it isn't ready by the deadline. A
+/// stream of responses ends with the same error if it doesn't end by then. The capped context is
+/// [current](crate::context::current) while the reply is polled.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct Capped<F> {
+    reply: Either<F, Deadline<WithContext<F>>>,
+    /// When the cap elapses, if the reply is capped.
+    deadline: Option<Instant>,
+}
+
+impl<F> Capped<F> {
+    /// Limits `reply` to the deadline of `cap`, the handler's capped context, or leaves it as is
+    /// if `cap` is `None`.
+    pub fn new(reply: F, cap: Option<Context>) -> Self {
+        match cap {
+            Some(cap) => {
+                let deadline = Instant::now() + cap.timeout();
+                Capped {
+                    reply: Either::Right(Deadline::new(WithContext::new(cap, reply), deadline)),
+                    deadline: Some(deadline),
+                }
+            }
+            None => Capped {
+                reply: Either::Left(reply),
+                deadline: None,
+            },
+        }
+    }
+}
+
+impl<F, Resp> Future for Capped<F>
+where
+    F: Future<Output = io::Result<Reply<Resp>>>,
+    Resp: Send + 'static,
+{
+    type Output = io::Result<Reply<Resp>>;
+
+    fn poll(self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<io::Result<Reply<Resp>>> {
+        // Safe because `reply` is never moved out of, and `deadline` isn't pinned.
+        let this = unsafe { Pin::get_mut_unchecked(self) };
+        let result = match &mut this.reply {
+            Either::Left(reply) => return unsafe { Pin::new_unchecked(reply) }.poll(waker),
+            Either::Right(reply) => ready!(unsafe { Pin::new_unchecked(reply) }.poll(waker)),
+        };
+        Poll::Ready(match result {
+            Ok(Reply::Unary(response)) => Ok(Reply::Unary(response)),
+            Ok(Reply::Stream(responses)) => {
+                let responses = CappedStream {
+                    responses,
+                    delay: Some(Delay::new(this.deadline.unwrap()).compat()),
+                };
+                Ok(Reply::Stream(responses.boxed()))
+            }
+            Err(e) => Err(timeout_error(e)),
+        })
+    }
+}
+
+/// A stream of responses that ends with an error once its deadline passes.
+struct CappedStream<Resp> {
+    responses: BoxStream<'static, io::Result<Resp>>,
+    /// `None` once the deadline passed.
+    delay: Option<Compat01As03<Delay>>,
+}
+
+impl<Resp> Stream for CappedStream<Resp> {
+    type Item = io::Result<Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<Resp>>> {
+        let this = &mut *self;
+        let delay = match &mut this.delay {
+            Some(delay) => delay,
+            None => return Poll::Ready(None),
+        };
+        if let Poll::Ready(response) = this.responses.poll_next_unpin(waker) {
+            return Poll::Ready(response);
+        }
+        let elapsed = ready!(delay.poll_unpin(waker));
+        this.delay = None;
+        Poll::Ready(Some(Err(match elapsed {
+            Ok(()) => timeout_error(timeout::Error::elapsed()),
+            Err(e) => timeout_error(timeout::Error::timer(e)),
+        })))
+    }
+}
+
+/// Converts the error of a capped reply into the error that the reply fails with.
+fn timeout_error(e: timeout::Error<io::Error>) -> io::Error {
+    if e.is_elapsed() {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Request handler did not respond within its time limit.",
+        )
+    } else if e.is_inner() {
+        e.into_inner().unwrap()
+    } else {
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context::Context,
+    server::{Inputs, Reply},
+    transport::Callbacks,
+};
+use futures::prelude::*;
+use std::{fmt, io};
+
+/// What a request comes with besides its context and message, for request handlers that take it;
+/// see [`respond_with_extras`](super::Handler::respond_with_extras).
+#[non_exhaustive]
+pub struct Extras<Req> {
+    /// The inputs that the client streams to a client-streaming request, or `None` if the request
+    /// doesn't stream inputs.
+    pub inputs: Option<Inputs<Req>>,
+    /// A client for requests to the peer that the request came from, if the peer serves requests
+    /// over the same connection, as it does over an
+    /// [accepted](crate::transport::duplex::accept) duplex transport.
+    pub callbacks: Option<Callbacks>,
+}
+
+impl<Req> Extras<Req> {
+    /// Returns the extras, with the inputs, if any, passed through `f`, e.g. to unwrap them for a
+    /// handler of a narrower request type.
+    pub fn map_inputs<U, F>(self, f: F) -> Extras<U>
+    where
+        F: FnOnce(Inputs<Req>) -> Inputs<U>,
+    {
+        Extras {
+            inputs: self.inputs.map(f),
+            callbacks: self.callbacks,
+        }
+    }
+}
+
+impl<Req> Default for Extras<Req> {
+    fn default() -> Self {
+        Extras {
+            inputs: None,
+            callbacks: None,
+        }
+    }
+}
+
+impl<Req> fmt::Debug for Extras<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extras")
+            .field("inputs", &self.inputs)
+            .field("callbacks", &self.callbacks)
+            .finish()
+    }
+}
+
+/// Handles the requests that a server receives.
+///
+/// Implemented for fns that take a request's context and message, as passed to
+/// [`respond_with`](super::Handler::respond_with), and by [`WithExtras`] for fns that also take
+/// the request's [`Extras`].
+pub trait RequestHandler<Req, Resp> {
+    /// The response that the handler resolves to, either a [`Reply`] or a plain response.
+    type Response: Into<Reply<Resp>>;
+    /// The future that the handler returns.
+    type Future: Future<Output = io::Result<Self::Response>> + Send + 'static;
+
+    /// Handles `request`.
+    fn handle(&mut self, ctx: Context, request: Req, extras: Extras<Req>) -> Self::Future;
+}
+
+impl<Req, Resp, F, Fut, R> RequestHandler<Req, Resp> for F
+where
+    F: FnMut(Context, Req) -> Fut,
+    Fut: Future<Output = io::Result<R>> + Send + 'static,
+    R: Into<Reply<Resp>>,
+{
+    type Response = R;
+    type Future = Fut;
+
+    fn handle(&mut self, ctx: Context, request: Req, _: Extras<Req>) -> Fut {
+        (*self)(ctx, request)
+    }
+}
+
+/// A request handler that takes each request's [`Extras`], as well as its context and message.
+#[derive(Clone, Debug)]
+pub struct WithExtras<F>(pub(crate) F);
+
+impl<Req, Resp, F, Fut, R> RequestHandler<Req, Resp> for WithExtras<F>
+where
+    F: FnMut(Context, Req, Extras<Req>) -> Fut,
+    Fut: Future<Output = io::Result<R>> + Send + 'static,
+    R: Into<Reply<Resp>>,
+{
+    type Response = R;
+    type Future = Fut;
+
+    fn handle(&mut self, ctx: Context, request: Req, extras: Extras<Req>) -> Fut {
+        (self.0)(ctx, request, extras)
+    }
+}
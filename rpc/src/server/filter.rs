@@ -5,9 +5,10 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
-    server::{Channel, Config},
+    server::{middleware::MiddlewareStack, Channel, Config},
+    transport::{Address, Identity, Peer},
     util::Compact,
-    ClientMessage, Response, Transport,
+    ClientMessage, ServerMessage, Transport,
 };
 use fnv::FnvHashMap;
 use futures::{channel::mpsc, prelude::*, ready, stream::Fuse, task::{LocalWaker, Poll}};
@@ -17,7 +18,7 @@ use std::{
     collections::hash_map::Entry,
     io,
     marker::PhantomData,
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
     ops::Try,
     option::NoneError,
     pin::Pin,
@@ -26,18 +27,39 @@ use std::{
 /// Drops connections under configurable conditions:
 ///
 /// 1. If the max number of connections is reached.
-/// 2. If the max number of connections for a single IP is reached.
+/// 2. If the max number of connections from a single source is reached. A peer's source is its
+///    identity, if the transport authenticates peers, or otherwise its IP address. Peers with
+///    neither, such as those connected over unauthenticated Unix domain sockets, are all on the
+///    local host, so they share a single source.
 #[derive(Debug)]
 pub struct ConnectionFilter<S, Req, Resp> {
     listener: Fuse<S>,
-    closed_connections: mpsc::UnboundedSender<SocketAddr>,
-    closed_connections_rx: mpsc::UnboundedReceiver<SocketAddr>,
+    closed_connections: mpsc::UnboundedSender<Peer>,
+    closed_connections_rx: mpsc::UnboundedReceiver<Peer>,
     config: Config,
-    connections_per_ip: FnvHashMap<IpAddr, usize>,
+    connections_per_source: FnvHashMap<Source, usize>,
     open_connections: usize,
     ghost: PhantomData<(Req, Resp)>,
 }
 
+/// Where a connection comes from, for the purpose of limiting connections per source.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Identity(Identity),
+    Ip(IpAddr),
+    LocalHost,
+}
+
+impl<'a> From<&'a Peer> for Source {
+    fn from(peer: &'a Peer) -> Self {
+        match (&peer.identity, &peer.addr) {
+            (Some(identity), _) => Source::Identity(identity.clone()),
+            (None, Address::Inet(addr)) => Source::Ip(addr.ip()),
+            (None, _) => Source::LocalHost,
+        }
+    }
+}
+
 enum NewConnection<Req, Resp, C> {
     Filtered,
     Accepted(Channel<Req, Resp, C>),
@@ -66,15 +88,15 @@ impl<Req, Resp, C> Try for NewConnection<Req, Resp, C> {
 impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
     unsafe_pinned!(open_connections: usize);
     unsafe_pinned!(config: Config);
-    unsafe_pinned!(connections_per_ip: FnvHashMap<IpAddr, usize>);
-    unsafe_pinned!(closed_connections_rx: mpsc::UnboundedReceiver<SocketAddr>);
+    unsafe_pinned!(connections_per_source: FnvHashMap<Source, usize>);
+    unsafe_pinned!(closed_connections_rx: mpsc::UnboundedReceiver<Peer>);
     unsafe_pinned!(listener: Fuse<S>);
 
     /// Sheds new connections to stay under configured limits.
     pub fn filter<C>(listener: S, config: Config) -> Self
     where
         S: Stream<Item = Result<C, io::Error>>,
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         let (closed_connections, closed_connections_rx) = mpsc::unbounded();
 
@@ -83,7 +105,7 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
             closed_connections,
             closed_connections_rx,
             config,
-            connections_per_ip: FnvHashMap::default(),
+            connections_per_source: FnvHashMap::default(),
             open_connections: 0,
             ghost: PhantomData,
         }
@@ -91,12 +113,12 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
 
     fn handle_new_connection<C>(self: &mut Pin<&mut Self>, stream: C) -> NewConnection<Req, Resp, C>
     where
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
-        let peer = match stream.peer_addr() {
+        let peer = match stream.peer() {
             Ok(peer) => peer,
             Err(e) => {
-                warn!("Could not get peer_addr of new connection: {}", e);
+                warn!("Could not get peer of new connection: {}", e);
                 return NewConnection::Filtered;
             }
         };
@@ -114,53 +136,54 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
         }
 
         let config = self.config.clone();
-        let open_connections_for_ip = self.increment_connections_for_ip(&peer)?;
+        let open_connections_for_source = self.increment_connections_for_source(&peer)?;
         *self.open_connections() += 1;
 
         debug!(
-            "[{}] Opening channel ({}/{} connections for IP, {} total).",
+            "[{}] Opening channel ({}/{} connections from source, {} total).",
             peer,
-            open_connections_for_ip,
-            config.max_connections_per_ip,
+            open_connections_for_source,
+            config.max_connections_per_source,
             self.open_connections(),
         );
 
         NewConnection::Accepted(Channel {
-            client_addr: peer,
+            peer,
             closed_connections: self.closed_connections.clone(),
             transport: stream.fuse(),
             config,
+            middleware: MiddlewareStack::default(),
             ghost: PhantomData,
         })
     }
 
-    fn handle_closed_connection(self: &mut Pin<&mut Self>, addr: &SocketAddr) {
+    fn handle_closed_connection(self: &mut Pin<&mut Self>, peer: &Peer) {
         *self.open_connections() -= 1;
         debug!(
             "[{}] Closing channel. {} open connections remaining.",
-            addr, self.open_connections
+            peer, self.open_connections
         );
-        self.decrement_connections_for_ip(&addr);
-        self.connections_per_ip().compact(0.1);
+        self.decrement_connections_for_source(&peer);
+        self.connections_per_source().compact(0.1);
     }
 
-    fn increment_connections_for_ip(self: &mut Pin<&mut Self>, peer: &SocketAddr) -> Option<usize> {
-        let max_connections_per_ip = self.config().max_connections_per_ip;
+    fn increment_connections_for_source(self: &mut Pin<&mut Self>, peer: &Peer) -> Option<usize> {
+        let max_connections_per_source = self.config().max_connections_per_source;
         let mut occupied;
-        let mut connections_per_ip = self.connections_per_ip();
-        let occupied = match connections_per_ip.entry(peer.ip()) {
+        let mut connections_per_source = self.connections_per_source();
+        let occupied = match connections_per_source.entry(Source::from(peer)) {
             Entry::Vacant(vacant) => vacant.insert(0),
             Entry::Occupied(o) => {
-                if *o.get() < max_connections_per_ip {
+                if *o.get() < max_connections_per_source {
                     // Store the reference outside the block to extend the lifetime.
                     occupied = o;
                     occupied.get_mut()
                 } else {
                     info!(
-                        "[{}] Opened max connections from IP ({}/{}).",
+                        "[{}] Opened max connections from source ({}/{}).",
                         peer,
                         o.get(),
-                        max_connections_per_ip
+                        max_connections_per_source
                     );
                     return None;
                 }
@@ -170,10 +193,10 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
         Some(*occupied)
     }
 
-    fn decrement_connections_for_ip(self: &mut Pin<&mut Self>, addr: &SocketAddr) {
-        let should_compact = match self.connections_per_ip().entry(addr.ip()) {
+    fn decrement_connections_for_source(self: &mut Pin<&mut Self>, peer: &Peer) {
+        let should_compact = match self.connections_per_source().entry(Source::from(peer)) {
             Entry::Vacant(_) => {
-                error!("[{}] Got vacant entry when closing connection.", addr);
+                error!("[{}] Got vacant entry when closing connection.", peer);
                 return;
             }
             Entry::Occupied(mut occupied) => {
@@ -187,7 +210,7 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
             }
         };
         if should_compact {
-            self.connections_per_ip().compact(0.1);
+            self.connections_per_source().compact(0.1);
         }
     }
 
@@ -197,7 +220,7 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
     ) -> Poll<Option<io::Result<NewConnection<Req, Resp, C>>>>
     where
         S: Stream<Item = Result<C, io::Error>>,
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         match ready!(self.listener().poll_next_unpin(cx)?) {
             Some(codec) => Poll::Ready(Some(Ok(self.handle_new_connection(codec)))),
@@ -210,8 +233,8 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
         cx: &LocalWaker,
     ) -> Poll<io::Result<()>> {
         match ready!(self.closed_connections_rx().poll_next_unpin(cx)) {
-            Some(addr) => {
-                self.handle_closed_connection(&addr);
+            Some(peer) => {
+                self.handle_closed_connection(&peer);
                 Poll::Ready(Ok(()))
             }
             None => unreachable!("Holding a copy of closed_connections and didn't close it."),
@@ -222,7 +245,7 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
 impl<S, Req, Resp, T> Stream for ConnectionFilter<S, Req, Resp>
 where
     S: Stream<Item = Result<T, io::Error>>,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {
     type Item = io::Result<Channel<Req, Resp, T>>;
 
@@ -255,3 +278,128 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionFilter;
+    use crate::{
+        server::Config,
+        transport::{self, channel::UnboundedChannel, Address, Identity, Peer},
+        ClientMessage, ServerMessage,
+    };
+    use futures::{executor::block_on, prelude::*, stream, task::{LocalWaker, Poll}};
+    use pin_utils::unsafe_pinned;
+    use std::{io, pin::Pin};
+
+    /// An in-process transport that claims to be connected to `peer`.
+    struct PeerChannel {
+        peer: Peer,
+        inner: UnboundedChannel<ClientMessage<()>, ServerMessage<()>>,
+    }
+
+    impl PeerChannel {
+        unsafe_pinned!(inner: UnboundedChannel<ClientMessage<()>, ServerMessage<()>>);
+
+        fn new(addr: Address, identity: Option<Identity>) -> io::Result<Self> {
+            Ok(PeerChannel {
+                peer: Peer::new(addr, identity),
+                inner: transport::channel::unbounded().1,
+            })
+        }
+    }
+
+    impl Stream for PeerChannel {
+        type Item = io::Result<ClientMessage<()>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<Self::Item>> {
+            self.inner().poll_next(cx)
+        }
+    }
+
+    impl Sink for PeerChannel {
+        type SinkItem = ServerMessage<()>;
+        type SinkError = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.inner().poll_ready(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: ServerMessage<()>) -> io::Result<()> {
+            self.inner().start_send(item)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.inner().poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.inner().poll_close(cx)
+        }
+    }
+
+    impl transport::Transport for PeerChannel {
+        type Item = ClientMessage<()>;
+        type SinkItem = ServerMessage<()>;
+
+        fn peer_addr(&self) -> io::Result<Address> {
+            Ok(self.peer.addr.clone())
+        }
+
+        fn local_addr(&self) -> io::Result<Address> {
+            Ok(Address::InProcess)
+        }
+
+        fn peer(&self) -> io::Result<Peer> {
+            Ok(self.peer.clone())
+        }
+    }
+
+    #[test]
+    fn max_connections_per_source() {
+        let unix = |uid| Some(Identity::Unix { uid, gid: 0 });
+        let certificate = |der: &[u8]| Some(Identity::Certificate(der.to_vec()));
+        let unnamed = || Address::Unix(None);
+        let inet = |addr: &str| Address::Inet(addr.parse().unwrap());
+        let connections = vec![
+            // Authenticated peers are limited per identity, whatever their address.
+            PeerChannel::new(unnamed(), unix(1)),
+            PeerChannel::new(unnamed(), unix(1)),
+            PeerChannel::new(unnamed(), unix(2)),
+            PeerChannel::new(inet("10.0.0.1:1"), certificate(b"a")),
+            PeerChannel::new(inet("10.0.0.2:1"), certificate(b"a")),
+            PeerChannel::new(inet("10.0.0.1:2"), certificate(b"b")),
+            // Unauthenticated Unix peers share a source...
+            PeerChannel::new(unnamed(), None),
+            PeerChannel::new(unnamed(), None),
+            // ...and unauthenticated network peers are limited per IP.
+            PeerChannel::new(inet("10.0.0.1:3"), None),
+            PeerChannel::new(inet("10.0.0.1:4"), None),
+            PeerChannel::new(inet("10.0.0.2:2"), None),
+        ];
+        let mut config = Config::default();
+        config.max_connections_per_source = 1;
+        let mut filter =
+            ConnectionFilter::<_, (), ()>::filter(stream::iter(connections), config);
+
+        // Hold on to the accepted channels, so that their sources stay at the limit.
+        let channels: Vec<_> = (0..7)
+            .map(|_| block_on(filter.next()).unwrap().unwrap())
+            .collect();
+        let peers: Vec<_> = channels
+            .iter()
+            .map(|channel| channel.peer().clone())
+            .collect();
+        assert_eq!(
+            peers,
+            vec![
+                Peer::new(unnamed(), unix(1)),
+                Peer::new(unnamed(), unix(2)),
+                Peer::new(inet("10.0.0.1:1"), certificate(b"a")),
+                Peer::new(inet("10.0.0.1:2"), certificate(b"b")),
+                Peer::new(unnamed(), None),
+                Peer::new(inet("10.0.0.1:3"), None),
+                Peer::new(inet("10.0.0.2:2"), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    ready,
+    stream::BoxStream,
+    task::{LocalWaker, Poll},
+};
+use std::{fmt, io, pin::Pin};
+
+/// The stream of inputs that a client sends to a client-streaming request.
+///
+/// The stream ends when the client half-closes it. If the connection breaks or the request is
+/// canceled first, the stream yields a [`ConnectionReset`](io::ErrorKind::ConnectionReset)
+/// error. Handlers take their request's inputs from its [`Extras`](super::Extras), as passed to
+/// the request handlers given to [`respond_with_extras`](super::Handler::respond_with_extras).
+///
+/// The server buffers up to [`pending_request_buffer`](super::Config::pending_request_buffer)
+/// inputs that the handler hasn't read yet, and grants the client credit to send more as the
+/// handler reads them. A client that gets ahead of the handler waits for the credit.
+pub struct Inputs<T> {
+    inputs: BoxStream<'static, io::Result<T>>,
+}
+
+impl<T> Inputs<T> {
+    /// Returns the inputs passed through `f`, e.g. to unwrap them from a service's request type.
+    pub fn try_map<U, F>(self, mut f: F) -> Inputs<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: FnMut(T) -> io::Result<U> + Send + 'static,
+    {
+        Inputs {
+            inputs: self
+                .inputs
+                .map(move |input| input.and_then(&mut f))
+                .boxed(),
+        }
+    }
+}
+
+impl<T> Stream for Inputs<T> {
+    type Item = io::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<T>>> {
+        self.inputs.poll_next_unpin(waker)
+    }
+}
+
+impl<T> fmt::Debug for Inputs<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Inputs")
+    }
+}
+
+/// Returns a sender of inputs to the request with ID `request_id`, which ends the inputs by
+/// sending `None`, and the inputs it sends to. At most `buffer` inputs are buffered; credit for
+/// more is sent to `credits` as the inputs are read.
+pub(crate) fn channel<Req: Send + 'static>(
+    request_id: u64,
+    buffer: usize,
+    credits: mpsc::UnboundedSender<(u64, u64)>,
+) -> (mpsc::Sender<Option<Req>>, Inputs<Req>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let inputs = InputReceiver {
+        inputs: rx,
+        complete: false,
+        request_id,
+        credits,
+        consumed: 0,
+        credit_batch: (buffer as u64 / 2).max(1),
+    };
+    (tx, Inputs { inputs: inputs.boxed() })
+}
+
+/// Receives a request's inputs from the connection's client handler.
+struct InputReceiver<Req> {
+    /// Yields `None` once the client half-closes the inputs.
+    inputs: mpsc::Receiver<Option<Req>>,
+    complete: bool,
+    request_id: u64,
+    /// Grants the client credit for inputs as they're read.
+    credits: mpsc::UnboundedSender<(u64, u64)>,
+    /// The number of inputs read since credit was last granted for them.
+    consumed: u64,
+    /// The number of read inputs to grant credit for at a time.
+    credit_batch: u64,
+}
+
+impl<Req> Stream for InputReceiver<Req> {
+    type Item = io::Result<Req>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &LocalWaker) -> Poll<Option<io::Result<Req>>> {
+        if self.complete {
+            return Poll::Ready(None);
+        }
+        Poll::Ready(match ready!(self.inputs.poll_next_unpin(waker)) {
+            Some(Some(input)) => {
+                // Credit is granted for half the buffer at a time, rather than with a message
+                // per input.
+                self.consumed += 1;
+                if self.consumed >= self.credit_batch {
+                    let _ = self.credits.unbounded_send((self.request_id, self.consumed));
+                    self.consumed = 0;
+                }
+                Some(Ok(input))
+            }
+            Some(None) => {
+                self.complete = true;
+                None
+            }
+            // The client handler drops the sender without ending the inputs when the connection
+            // breaks or the request is canceled.
+            None => {
+                self.complete = true;
+                Some(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "Request ended before the client finished sending inputs.",
+                )))
+            }
+        })
+    }
+}
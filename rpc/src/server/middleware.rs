@@ -0,0 +1,151 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context::Context,
+    server::{Channel, Reply},
+    transport::Peer,
+    util::chain::{Chain, Remainder, Step},
+};
+use futures::{
+    future::BoxFuture,
+    prelude::*,
+    task::{LocalWaker, Poll},
+};
+use pin_utils::unsafe_pinned;
+use std::{fmt, io, pin::Pin, sync::Arc};
+
+/// The future returned by request handlers wrapped in middleware.
+pub type ResponseFuture<Resp> = BoxFuture<'static, io::Result<Reply<Resp>>>;
+
+/// Wraps a server's request handler to add cross-cutting behavior, such as authorization,
+/// logging, or metrics, to every request.
+///
+/// Middleware receives each request before the request handler, along with a [`Next`] that runs
+/// the remainder of the middleware stack and then the request handler. Middleware can modify the
+/// context and request before passing them on, reject the request by not calling
+/// [`Next::run`], and observe or modify the reply by wrapping the returned future. Use
+/// [`Reply::map`] to modify every response of a reply, whether it is unary or streamed.
+pub trait Middleware<Req, Resp>: Send + Sync + 'static {
+    /// Handles a request from `peer`.
+    fn handle(
+        &self,
+        ctx: Context,
+        peer: Peer,
+        request: Req,
+        next: Next<'_, Req, Resp>,
+    ) -> ResponseFuture<Resp>;
+}
+
+impl<Req, Resp, F> Middleware<Req, Resp> for F
+where
+    F: for<'a> Fn(Context, Peer, Req, Next<'a, Req, Resp>) -> ResponseFuture<Resp>
+        + Send
+        + Sync
+        + 'static,
+{
+    fn handle(
+        &self,
+        ctx: Context,
+        peer: Peer,
+        request: Req,
+        next: Next<'_, Req, Resp>,
+    ) -> ResponseFuture<Resp> {
+        self(ctx, peer, request, next)
+    }
+}
+
+/// The remainder of a middleware stack, ending in the request handler.
+pub struct Next<'a, Req, Resp> {
+    remainder: Remainder<
+        'a,
+        dyn Middleware<Req, Resp>,
+        &'a mut dyn FnMut(Context, Req) -> ResponseFuture<Resp>,
+    >,
+    peer: Peer,
+}
+
+impl<'a, Req, Resp> fmt::Debug for Next<'a, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Next({} middleware remaining)", self.remainder.remaining())
+    }
+}
+
+impl<'a, Req, Resp> Next<'a, Req, Resp> {
+    pub(crate) fn new(
+        middleware: &'a [Arc<dyn Middleware<Req, Resp>>],
+        peer: Peer,
+        handler: &'a mut dyn FnMut(Context, Req) -> ResponseFuture<Resp>,
+    ) -> Self {
+        Next {
+            remainder: Remainder::new(middleware, handler),
+            peer,
+        }
+    }
+
+    /// Passes the request to the next middleware in the stack, or to the request handler if
+    /// there is no more middleware.
+    pub fn run(self, ctx: Context, request: Req) -> ResponseFuture<Resp> {
+        match self.remainder.step() {
+            Step::Layer(middleware, remainder) => {
+                let peer = self.peer.clone();
+                middleware.handle(ctx, peer, request, Next { remainder, peer: self.peer })
+            }
+            Step::Terminal(handler) => handler(ctx, request),
+        }
+    }
+}
+
+/// The middleware wrapping the request handler of a channel, outermost first.
+pub(crate) type MiddlewareStack<Req, Resp> = Chain<dyn Middleware<Req, Resp>>;
+
+/// A stream of channels whose request handlers are wrapped in middleware. See
+/// [`Handler::layer`](super::Handler::layer).
+pub struct Layered<S, Req, Resp> {
+    incoming: S,
+    middleware: Arc<dyn Middleware<Req, Resp>>,
+}
+
+impl<S, Req, Resp> Layered<S, Req, Resp> {
+    unsafe_pinned!(incoming: S);
+
+    pub(crate) fn new(incoming: S, middleware: Arc<dyn Middleware<Req, Resp>>) -> Self {
+        Layered {
+            incoming,
+            middleware,
+        }
+    }
+}
+
+impl<S: fmt::Debug, Req, Resp> fmt::Debug for Layered<S, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Layered")
+            .field("incoming", &self.incoming)
+            .finish()
+    }
+}
+
+impl<S, Req, Resp, T> Stream for Layered<S, Req, Resp>
+where
+    S: Stream<Item = io::Result<Channel<Req, Resp, T>>>,
+{
+    type Item = io::Result<Channel<Req, Resp, T>>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &LocalWaker,
+    ) -> Poll<Option<io::Result<Channel<Req, Resp, T>>>> {
+        let middleware = self.middleware.clone();
+        self.incoming().poll_next(cx).map(|channel| {
+            channel.map(|channel| {
+                channel.map(|mut channel| {
+                    channel.middleware.push(middleware);
+                    channel
+                })
+            })
+        })
+    }
+}
@@ -7,16 +7,18 @@
 //! Provides a server that concurrently handles many connections sending multiplexed requests.
 
 use crate::{
-    context::Context, util::deadline_compat, util::AsDuration, util::Compact, ClientMessage,
-    ClientMessageKind, Request, Response, ServerError, Transport,
+    context::{self, Context}, transport::{Address, Callbacks, Peer, SkippedMessage},
+    util::{credit::{self, Credit}, deadline_compat, Compact}, ClientMessage, ClientMessageHeader,
+    ClientMessageHeaderKind, ClientMessageKind, Code, Handshake, Request, Response, ServerError,
+    ServerMessage, Transport,
 };
 use fnv::FnvHashMap;
 use futures::{
     channel::mpsc,
-    future::{abortable, AbortHandle},
+    future::{self, abortable, AbortHandle, Either},
     prelude::*,
     ready,
-    stream::Fuse,
+    stream::{BoxStream, Fuse},
     task::{LocalWaker, Poll},
     try_ready,
 };
@@ -24,17 +26,32 @@ use humantime::format_rfc3339;
 use log::{debug, error, info, trace, warn};
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
 use std::{
-    error::Error as StdError,
+    collections::BTreeMap,
     io,
     marker::PhantomData,
-    net::SocketAddr,
     pin::Pin,
-    time::{Instant, SystemTime},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 use tokio_timer::timeout;
 use trace::{self, TraceId};
 
+mod deadline;
+mod extras;
 mod filter;
+mod inputs;
+mod middleware;
+mod reply;
+mod shutdown;
+
+pub use self::deadline::Capped;
+pub use self::extras::{Extras, RequestHandler, WithExtras};
+pub use self::inputs::Inputs;
+pub use self::middleware::{Layered, Middleware, Next, ResponseFuture};
+pub use self::reply::Reply;
+pub use self::shutdown::Shutdown;
+use self::middleware::MiddlewareStack;
+use self::shutdown::{Phase, ShutdownSignal};
 
 /// Manages clients, serving multiplexed requests over each connection.
 #[derive(Debug)]
@@ -50,27 +67,43 @@ pub struct Config {
     /// The maximum number of clients that can be connected to the server at once. When at the
     /// limit, existing connections are honored and new connections are rejected.
     pub max_connections: usize,
-    /// The maximum number of clients per IP address that can be connected to the server at once.
-    /// When an IP is at the limit, existing connections are honored and new connections on that IP
-    /// address are rejected.
-    pub max_connections_per_ip: usize,
+    /// The maximum number of clients per source that can be connected to the server at once. A
+    /// client's source is its identity, if the transport authenticates clients, or otherwise its
+    /// IP address; clients with neither share a single source. When a source is at the limit,
+    /// existing connections are honored and new connections from that source are rejected.
+    pub max_connections_per_source: usize,
     /// The maximum number of requests that can be in flight for each client. When a client is at
     /// the in-flight request limit, existing requests are fulfilled and new requests are rejected.
     /// Rejected requests are sent a response error.
     pub max_in_flight_requests_per_connection: usize,
+    /// If set, requests rejected because their client is at the in-flight request limit are
+    /// told to retry after this long.
+    pub throttle_retry_after: Option<Duration>,
     /// The number of responses per client that can be buffered server-side before being sent.
     /// `pending_response_buffer` controls the buffer size of the channel that a server's
     /// response tasks use to send responses to the client handler task.
     pub pending_response_buffer: usize,
+    /// The number of inputs to each client-streaming request that can be buffered server-side
+    /// before the request's handler reads them. The server grants the client credit for this
+    /// many inputs, and for more as the handler reads them, so that a client is paused, rather
+    /// than failed, when it gets ahead of the handler.
+    pub pending_request_buffer: usize,
+    /// If set, the server answers clients' handshakes by checking them against this handshake,
+    /// and closes the connection of any client whose handshake doesn't match. If unset, every
+    /// handshake is accepted. Clients that don't send a handshake are served either way.
+    pub handshake: Option<Handshake>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             max_connections: 1_000_000,
-            max_connections_per_ip: 1_000,
+            max_connections_per_source: 1_000,
             max_in_flight_requests_per_connection: 1_000,
+            throttle_retry_after: None,
             pending_response_buffer: 100,
+            pending_request_buffer: 100,
+            handshake: None,
         }
     }
 }
@@ -98,7 +131,7 @@ impl<Req, Resp> Server<Req, Resp> {
         Req: Send,
         Resp: Send,
         S: Stream<Item = io::Result<T>>,
-        T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         self::filter::ConnectionFilter::filter(listener, self.config.clone())
     }
@@ -109,30 +142,44 @@ impl<Req, Resp> Server<Req, Resp> {
 pub struct Running<S, F> {
     incoming: S,
     request_handler: F,
+    shutdown: Shutdown,
+    shutdown_signal: ShutdownSignal,
 }
 
 impl<S, F> Running<S, F> {
     unsafe_pinned!(incoming: S);
     unsafe_unpinned!(request_handler: F);
+    unsafe_unpinned!(shutdown_signal: ShutdownSignal);
+
+    /// Returns a handle that gracefully shuts down the server.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
 }
 
-impl<S, T, Req, Resp, F, Fut> Future for Running<S, F>
+impl<S, T, Req, Resp, F> Future for Running<S, F>
 where
     S: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send + 'static,
-    F: FnMut(Context, Req) -> Fut + Send + 'static + Clone,
-    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send + 'static,
+    F: RequestHandler<Req, Resp> + Send + 'static + Clone,
 {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<()> {
+        if self.shutdown_signal().poll_phase(cx) != Phase::Serving {
+            info!("Server shutting down; no longer accepting connections.");
+            return Poll::Ready(());
+        }
         while let Some(channel) = ready!(self.incoming().poll_next(cx)) {
             match channel {
                 Ok(channel) => {
-                    let peer = channel.client_addr;
-                    if let Err(e) = crate::spawn(channel.respond_with(self.request_handler().clone()))
+                    let peer = channel.peer.clone();
+                    let shutdown_signal = self.shutdown.subscribe();
+                    let request_handler = self.request_handler().clone();
+                    if let Err(e) =
+                        crate::spawn(channel.serve(request_handler, shutdown_signal))
                     {
                         warn!("[{}] Failed to spawn connection handler: {:?}", peer, e);
                     }
@@ -153,17 +200,48 @@ where
     Self: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send,
     Resp: Send,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {
+    /// Wraps the request handler of every channel in `middleware`. Middleware layered first is
+    /// outermost, i.e. it sees requests first and responses last.
+    fn layer<M>(self, middleware: M) -> Layered<Self, Req, Resp>
+    where
+        M: Middleware<Req, Resp>,
+    {
+        Layered::new(self, Arc::new(middleware))
+    }
+
     /// Responds to all requests with `request_handler`.
-    fn respond_with<F, Fut>(self, request_handler: F) -> Running<Self, F>
+    fn respond_with<F, Fut, R>(self, request_handler: F) -> Running<Self, F>
     where
         F: FnMut(Context, Req) -> Fut + Send + 'static + Clone,
-        Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+        Fut: Future<Output = io::Result<R>> + Send + 'static,
+        R: Into<Reply<Resp>>,
     {
+        let shutdown = Shutdown::new();
         Running {
             incoming: self,
             request_handler,
+            shutdown_signal: shutdown.subscribe(),
+            shutdown,
+        }
+    }
+
+    /// Responds to all requests with `request_handler`, which also takes each request's
+    /// [`Extras`], such as the inputs of a client-streaming request. Services defined with
+    /// `tarpc::service!` are served this way.
+    fn respond_with_extras<F, Fut, R>(self, request_handler: F) -> Running<Self, WithExtras<F>>
+    where
+        F: FnMut(Context, Req, Extras<Req>) -> Fut + Send + 'static + Clone,
+        Fut: Future<Output = io::Result<R>> + Send + 'static,
+        R: Into<Reply<Resp>>,
+    {
+        let shutdown = Shutdown::new();
+        Running {
+            incoming: self,
+            request_handler: WithExtras(request_handler),
+            shutdown_signal: shutdown.subscribe(),
+            shutdown,
         }
     }
 }
@@ -173,7 +251,7 @@ where
     S: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send,
     Resp: Send,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {}
 
 /// Responds to all requests with `request_handler`.
@@ -183,87 +261,135 @@ pub struct Channel<Req, Resp, T> {
     /// Writes responses to the wire and reads requests off the wire.
     transport: Fuse<T>,
     /// Signals the connection is closed when `Channel` is dropped.
-    closed_connections: mpsc::UnboundedSender<SocketAddr>,
+    closed_connections: mpsc::UnboundedSender<Peer>,
     /// Channel limits to prevent unlimited resource usage.
     config: Config,
-    /// The address of the server connected to.
-    client_addr: SocketAddr,
+    /// The client connected to.
+    peer: Peer,
+    /// Wraps the request handler.
+    middleware: MiddlewareStack<Req, Resp>,
     /// Types the request and response.
     ghost: PhantomData<(Req, Resp)>,
 }
 
 impl<Req, Resp, T> Drop for Channel<Req, Resp, T> {
     fn drop(&mut self) {
-        trace!("[{}] Closing channel.", self.client_addr);
+        trace!("[{}] Closing channel.", self.peer);
 
         // Even in a bounded channel, each connection would have a guaranteed slot, so using
         // an unbounded sender is actually no different. And, the bound is on the maximum number
         // of open connections.
         if self
             .closed_connections
-            .unbounded_send(self.client_addr)
+            .unbounded_send(self.peer.clone())
             .is_err()
         {
             warn!(
                 "[{}] Failed to send closed connection message.",
-                self.client_addr
+                self.peer
             );
         }
     }
 }
 
 impl<Req, Resp, T> Channel<Req, Resp, T> {
-    unsafe_pinned!(transport: Fuse<T>);
+    // Written out by hand because `unsafe_pinned!` would name the projection `transport`, which
+    // is taken by the public accessor. Safe for the same reasons: the field is structurally
+    // pinned, and is never moved out of the channel.
+    fn transport_pin<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut Fuse<T>> {
+        unsafe { Pin::map_unchecked_mut(self.as_mut(), |channel| &mut channel.transport) }
+    }
 }
 
 impl<Req, Resp, T> Channel<Req, Resp, T>
 where
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     Req: Send,
     Resp: Send,
 {
-    pub(crate) fn start_send(self: &mut Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
-        self.transport().start_send(response)
+    pub(crate) fn start_send(
+        self: &mut Pin<&mut Self>,
+        message: ServerMessage<Resp>,
+    ) -> io::Result<()> {
+        self.transport_pin().start_send(message)
     }
 
     pub(crate) fn poll_ready(
         self: &mut Pin<&mut Self>,
         cx: &LocalWaker,
     ) -> Poll<io::Result<()>> {
-        self.transport().poll_ready(cx)
+        self.transport_pin().poll_ready(cx)
     }
 
     pub(crate) fn poll_flush(
         self: &mut Pin<&mut Self>,
         cx: &LocalWaker,
     ) -> Poll<io::Result<()>> {
-        self.transport().poll_flush(cx)
+        self.transport_pin().poll_flush(cx)
     }
 
     pub(crate) fn poll_next(
         self: &mut Pin<&mut Self>,
         cx: &LocalWaker,
     ) -> Poll<Option<io::Result<ClientMessage<Req>>>> {
-        self.transport().poll_next(cx)
+        self.transport_pin().poll_next(cx)
     }
 
     /// Returns the address of the client connected to the channel.
-    pub fn client_addr(&self) -> &SocketAddr {
-        &self.client_addr
+    pub fn client_addr(&self) -> &Address {
+        &self.peer.addr
+    }
+
+    /// Returns the client connected to the channel, including its identity if the transport
+    /// authenticates clients.
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Returns the transport underlying the channel, e.g. to inspect the client's verified
+    /// identity on a secure transport before responding to requests.
+    pub fn transport(&self) -> &T {
+        self.transport.get_ref()
     }
 
     /// Respond to requests coming over the channel with `f`. Returns a future that drives the
     /// responses and resolves when the connection is closed.
-    pub fn respond_with<F, Fut>(self, f: F) -> impl Future<Output = ()>
+    pub fn respond_with<F, Fut, R>(self, f: F) -> impl Future<Output = ()>
     where
         F: FnMut(Context, Req) -> Fut + Send + 'static,
-        Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+        Fut: Future<Output = io::Result<R>> + Send + 'static,
+        R: Into<Reply<Resp>>,
+        Req: 'static,
+        Resp: 'static,
+    {
+        self.serve(f, ShutdownSignal::never())
+    }
+
+    /// Respond to requests coming over the channel with `f`, which also takes each request's
+    /// [`Extras`]. Returns a future that drives the responses and resolves when the connection is
+    /// closed.
+    pub fn respond_with_extras<F, Fut, R>(self, f: F) -> impl Future<Output = ()>
+    where
+        F: FnMut(Context, Req, Extras<Req>) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<R>> + Send + 'static,
+        R: Into<Reply<Resp>>,
+        Req: 'static,
+        Resp: 'static,
+    {
+        self.serve(WithExtras(f), ShutdownSignal::never())
+    }
+
+    fn serve<F>(self, f: F, shutdown_signal: ShutdownSignal) -> impl Future<Output = ()>
+    where
+        F: RequestHandler<Req, Resp> + Send + 'static,
         Req: 'static,
         Resp: 'static,
     {
         let (responses_tx, responses) = mpsc::channel(self.config.pending_response_buffer);
         let responses = responses.fuse();
-        let peer = self.client_addr;
+        let (input_credits_tx, input_credits) = mpsc::unbounded();
+        let peer = self.peer.clone();
+        let callbacks = self.transport().callbacks();
 
         ClientHandler {
             channel: self,
@@ -271,6 +397,14 @@ where
             pending_responses: responses,
             responses_tx,
             in_flight_requests: FnvHashMap::default(),
+            request_inputs: FnvHashMap::default(),
+            input_credits_tx,
+            input_credits,
+            callbacks,
+            shutdown_signal,
+            shutting_down: false,
+            shutdown_announced: false,
+            handshake_rejected: false,
         }.unwrap_or_else(move |e| {
             info!("[{}] ClientHandler errored out: {}", peer, e);
         })
@@ -281,32 +415,62 @@ where
 struct ClientHandler<Req, Resp, T, F> {
     channel: Channel<Req, Resp, T>,
     /// Responses waiting to be written to the wire.
-    pending_responses: Fuse<mpsc::Receiver<(Context, Response<Resp>)>>,
+    pending_responses: Fuse<mpsc::Receiver<(Context, ServerMessage<Resp>)>>,
     /// Handed out to request handlers to fan in responses.
-    responses_tx: mpsc::Sender<(Context, Response<Resp>)>,
+    responses_tx: mpsc::Sender<(Context, ServerMessage<Resp>)>,
     /// Number of requests currently being responded to.
-    in_flight_requests: FnvHashMap<u64, AbortHandle>,
+    in_flight_requests: FnvHashMap<u64, InFlightRequest>,
+    /// Senders of the inputs of in-flight client-streaming requests.
+    request_inputs: FnvHashMap<u64, mpsc::Sender<Option<Req>>>,
+    /// Handed out to the inputs of client-streaming requests to grant credit as they're read.
+    input_credits_tx: mpsc::UnboundedSender<(u64, u64)>,
+    /// Credit granted to client-streaming requests, waiting to be written to the wire.
+    input_credits: mpsc::UnboundedReceiver<(u64, u64)>,
+    /// A client for requests to the client, if it serves requests over the same connection.
+    callbacks: Option<Callbacks>,
+    /// Notified when the server shuts down.
+    shutdown_signal: ShutdownSignal,
+    /// True once the server has begun shutting down, after which new requests are rejected.
+    shutting_down: bool,
+    /// True once the client has been told that the server is shutting down.
+    shutdown_announced: bool,
+    /// True once the client's handshake has been rejected, after which no more messages are read.
+    handshake_rejected: bool,
     /// Request handler.
     f: F,
 }
 
+/// A request whose handler is running, or whose responses are still being sent.
+#[derive(Debug)]
+struct InFlightRequest {
+    /// Aborts the task that runs the handler and sends its responses.
+    abort_handle: AbortHandle,
+    /// Passes on the credit that the client grants the request's stream of responses.
+    response_credit: mpsc::UnboundedSender<u64>,
+}
+
 impl<Req, Resp, T, F> ClientHandler<Req, Resp, T, F> {
     unsafe_pinned!(channel: Channel<Req, Resp, T>);
-    unsafe_pinned!(in_flight_requests: FnvHashMap<u64, AbortHandle>);
-    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<(Context, Response<Resp>)>>);
-    unsafe_pinned!(responses_tx: mpsc::Sender<(Context, Response<Resp>)>);
+    unsafe_pinned!(in_flight_requests: FnvHashMap<u64, InFlightRequest>);
+    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<(Context, ServerMessage<Resp>)>>);
+    unsafe_pinned!(responses_tx: mpsc::Sender<(Context, ServerMessage<Resp>)>);
+    unsafe_unpinned!(request_inputs: FnvHashMap<u64, mpsc::Sender<Option<Req>>>);
+    unsafe_unpinned!(input_credits: mpsc::UnboundedReceiver<(u64, u64)>);
+    unsafe_unpinned!(shutdown_signal: ShutdownSignal);
+    unsafe_unpinned!(shutting_down: bool);
+    unsafe_unpinned!(shutdown_announced: bool);
+    unsafe_unpinned!(handshake_rejected: bool);
     // For this to be safe, field f must be private, and code in this module must never
     // construct PinMut<F>.
     unsafe_unpinned!(f: F);
 }
 
-impl<Req, Resp, T, F, Fut> ClientHandler<Req, Resp, T, F>
+impl<Req, Resp, T, F> ClientHandler<Req, Resp, T, F>
 where
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
-    F: FnMut(Context, Req) -> Fut + Send + 'static,
-    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
+    F: RequestHandler<Req, Resp> + Send + 'static,
 {
     /// If at max in-flight requests, check that there's room to immediately write a throttled
     /// response.
@@ -317,7 +481,7 @@ where
         if self.in_flight_requests.len()
             >= self.channel.config.max_in_flight_requests_per_connection
         {
-            let peer = self.channel().client_addr;
+            let peer = self.channel.peer.clone();
 
             while let Poll::Pending = self.channel().poll_ready(cx)? {
                 info!(
@@ -332,10 +496,14 @@ where
     }
 
     fn pump_read(self: &mut Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<io::Result<()>>> {
+        if self.handshake_rejected {
+            // Stop reading, so that the connection closes once the rejection is flushed.
+            return Poll::Ready(None);
+        }
         ready!(self.poll_ready_if_throttling(cx)?);
 
-        Poll::Ready(match ready!(self.channel().poll_next(cx)?) {
-            Some(message) => {
+        Poll::Ready(match ready!(self.channel().poll_next(cx)) {
+            Some(Ok(message)) => {
                 match message.message {
                     ClientMessageKind::Request(request) => {
                         self.handle_request(message.trace_context, request)?;
@@ -343,11 +511,33 @@ where
                     ClientMessageKind::Cancel { request_id } => {
                         self.cancel_request(&message.trace_context, request_id);
                     }
+                    ClientMessageKind::StreamItem {
+                        request_id,
+                        message: input,
+                    } => {
+                        self.handle_input(&message.trace_context, request_id, Some(input));
+                    }
+                    ClientMessageKind::StreamEnd { request_id } => {
+                        self.handle_input(&message.trace_context, request_id, None);
+                    }
+                    ClientMessageKind::Handshake(handshake) => {
+                        self.handle_handshake(&handshake)?;
+                    }
+                    ClientMessageKind::ResponseCredit { request_id, credit } => {
+                        self.grant_response_credit(&message.trace_context, request_id, credit);
+                    }
                 }
                 Some(Ok(()))
             }
+            Some(Err(e)) => match SkippedMessage::<ClientMessageHeader>::from_io_error(&e) {
+                Some(skipped) => {
+                    self.handle_skipped(skipped);
+                    Some(Ok(()))
+                }
+                None => Some(Err(e)),
+            },
             None => {
-                trace!("[{}] Read half closed", self.channel.client_addr);
+                trace!("[{}] Read half closed", self.channel.peer);
                 None
             }
         })
@@ -358,9 +548,55 @@ where
         cx: &LocalWaker,
         read_half_closed: bool,
     ) -> Poll<Option<io::Result<()>>> {
+        if self.shutting_down && !self.shutdown_announced && !self.handshake_rejected {
+            // Tell the client before sending anything else, so that it can send its new requests
+            // elsewhere rather than have them rejected.
+            while let Poll::Pending = self.channel().poll_ready(cx)? {
+                ready!(self.channel().poll_flush(cx)?);
+            }
+            debug!("[{}] Notifying client of shutdown.", self.channel.peer);
+            self.channel().start_send(ServerMessage::Shutdown)?;
+            *self.shutdown_announced() = true;
+            return Poll::Ready(Some(Ok(())));
+        }
+
+        if let Poll::Ready(Some((request_id, credit))) = self.poll_next_input_credit(cx)? {
+            self.channel()
+                .start_send(ServerMessage::InputCredit { request_id, credit })?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
         match self.poll_next_response(cx)? {
-            Poll::Ready(Some((_, response))) => {
-                self.channel().start_send(response)?;
+            Poll::Ready(Some((ctx, message))) => {
+                let rejected = match message {
+                    ServerMessage::Response(Response { request_id, .. }) => {
+                        Some((request_id, ServerMessage::Response as fn(_) -> _))
+                    }
+                    ServerMessage::StreamItem(Response { request_id, .. }) => {
+                        Some((request_id, ServerMessage::StreamItem as fn(_) -> _))
+                    }
+                    _ => None,
+                };
+                match (self.channel().start_send(message), rejected) {
+                    (Err(ref e), Some((request_id, kind)))
+                        if e.kind() == io::ErrorKind::InvalidInput =>
+                    {
+                        warn!(
+                            "[{}/{}] Transport rejected response: {}",
+                            ctx.trace_id(),
+                            self.channel.peer,
+                            e
+                        );
+                        self.channel().start_send(kind(Response {
+                            request_id,
+                            message: Err(ServerError::new(
+                                Code::Internal,
+                                format!("Server could not send response: {}", e),
+                            )),
+                        }))?;
+                    }
+                    (result, _) => result?,
+                }
                 Poll::Ready(Some(Ok(())))
             }
             Poll::Ready(None) => {
@@ -384,21 +620,60 @@ where
         }
     }
 
+    /// Yields the next credit to grant a client-streaming request whose inputs are still being
+    /// received. Credit for other requests is discarded.
+    fn poll_next_input_credit(
+        self: &mut Pin<&mut Self>,
+        cx: &LocalWaker,
+    ) -> Poll<Option<io::Result<(u64, u64)>>> {
+        while let Poll::Pending = self.channel().poll_ready(cx)? {
+            ready!(self.channel().poll_flush(cx)?);
+        }
+
+        loop {
+            match ready!(self.input_credits().poll_next_unpin(cx)) {
+                Some((request_id, credit)) => {
+                    if self.request_inputs.contains_key(&request_id) {
+                        return Poll::Ready(Some(Ok((request_id, credit))));
+                    }
+                    trace!(
+                        "[{}] Discarding credit for request {}, whose inputs are complete.",
+                        self.channel.peer,
+                        request_id
+                    );
+                }
+                // This branch won't happen, since the ClientHandler is holding a Sender.
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+
     fn poll_next_response(
         self: &mut Pin<&mut Self>,
         cx: &LocalWaker,
-    ) -> Poll<Option<io::Result<(Context, Response<Resp>)>>> {
+    ) -> Poll<Option<io::Result<(Context, ServerMessage<Resp>)>>> {
         // Ensure there's room to write a response.
         while let Poll::Pending = self.channel().poll_ready(cx)? {
             ready!(self.channel().poll_flush(cx)?);
         }
 
-        let peer = self.channel().client_addr;
+        let peer = self.channel.peer.clone();
 
         match ready!(self.pending_responses().poll_next(cx)) {
-            Some((ctx, response)) => {
-                if let Some(_) = self.in_flight_requests().remove(&response.request_id) {
-                    self.in_flight_requests().compact(0.1);
+            Some((ctx, message)) => {
+                // A request stays in flight until the last of its responses is sent.
+                let completed = match message {
+                    ServerMessage::Response(Response { request_id, .. })
+                    | ServerMessage::StreamEnd { request_id } => Some(request_id),
+                    _ => None,
+                };
+                if let Some(request_id) = completed {
+                    if let Some(_) = self.in_flight_requests().remove(&request_id) {
+                        self.in_flight_requests().compact(0.1);
+                    }
+                    if let Some(_) = self.request_inputs().remove(&request_id) {
+                        self.request_inputs().compact(0.1);
+                    }
                 }
                 trace!(
                     "[{}/{}] Staging response. In-flight requests = {}.",
@@ -406,7 +681,7 @@ where
                     peer,
                     self.in_flight_requests().len(),
                 );
-                return Poll::Ready(Some(Ok((ctx, response))));
+                return Poll::Ready(Some(Ok((ctx, message))));
             }
             None => {
                 // This branch likely won't happen, since the ClientHandler is holding a Sender.
@@ -422,10 +697,20 @@ where
         request: Request<Req>,
     ) -> io::Result<()> {
         let request_id = request.id;
-        let peer = self.channel().client_addr;
-        let ctx = Context {
-            deadline: request.deadline,
-            trace_context,
+        let peer = self.channel.peer.clone();
+        let ctx = match request.timeout {
+            Some(timeout) => Context::with_timeout(timeout, trace_context, request.metadata),
+            None => Context::with_deadline(request.deadline, trace_context, request.metadata),
+        };
+        let (inputs_tx, request_inputs) = if request.streams_input {
+            let (inputs_tx, request_inputs) = inputs::channel(
+                request_id,
+                self.channel.config.pending_request_buffer,
+                self.input_credits_tx.clone(),
+            );
+            (Some(inputs_tx), Some(request_inputs))
+        } else {
+            (None, None)
         };
         let request = request.message;
 
@@ -440,18 +725,32 @@ where
                 self.channel().config.max_in_flight_requests_per_connection
             );
 
-            self.channel().start_send(Response {
+            let mut error =
+                ServerError::new(Code::ResourceExhausted, "Server throttled the request.");
+            error.retry_after = self.channel().config.throttle_retry_after;
+            self.channel().start_send(ServerMessage::Response(Response {
                 request_id,
-                message: Err(ServerError {
-                    kind: io::ErrorKind::WouldBlock,
-                    detail: Some("Server throttled the request.".into()),
-                }),
-            })?;
+                message: Err(error),
+            }))?;
+            return Ok(());
+        }
+
+        if self.shutting_down {
+            debug!(
+                "[{}/{}] Rejecting request because the server is shutting down.",
+                ctx.trace_id(),
+                peer,
+            );
+
+            self.channel().start_send(ServerMessage::Response(Response {
+                request_id,
+                message: Err(ServerError::new(Code::Unavailable, "Server is shutting down.")),
+            }))?;
             return Ok(());
         }
 
         let deadline = ctx.deadline;
-        let timeout = deadline.as_duration();
+        let timeout = ctx.timeout();
         trace!(
             "[{}/{}] Received request with deadline {} (timeout {:?}).",
             ctx.trace_id(),
@@ -460,18 +759,57 @@ where
             timeout,
         );
         let mut response_tx = self.responses_tx().clone();
+        // The first response needs no credit, so that unary calls never wait for any.
+        let (response_credit, credit) = credit::channel(1);
 
         let trace_id = *ctx.trace_id();
-        let response = self.f()(ctx.clone(), request);
-        let response = deadline_compat::Deadline::new(response, Instant::now() + timeout).then(
+        let callbacks = self.callbacks.clone();
+        let reply = if self.channel.middleware.is_empty() {
+            let extras = Extras {
+                inputs: request_inputs,
+                callbacks,
+            };
+            let f = self.f();
+            Either::Left(f.handle(ctx.clone(), request, extras).map_ok(Into::into))
+        } else {
+            let middleware = self.channel.middleware.get();
+            let f = self.f();
+            let mut inputs = request_inputs;
+            let mut handler = |ctx: Context, request: Req| {
+                let extras = Extras {
+                    inputs: inputs.take(),
+                    callbacks: callbacks.clone(),
+                };
+                f.handle(ctx, request, extras).map_ok(Into::into).boxed()
+            };
+            Either::Right(
+                Next::new(&middleware, peer.clone(), &mut handler).run(ctx.clone(), request),
+            )
+        };
+        let reply = context::WithContext::new(ctx.clone(), reply);
+        let deadline_instant = Instant::now() + timeout;
+        let response = deadline_compat::Deadline::new(reply, deadline_instant).then(
             async move |result| {
-                let response = Response {
-                    request_id,
-                    message: match result {
-                        Ok(message) => Ok(message),
-                        Err(e) => Err(make_server_error(e, trace_id, peer, deadline)),
-                    },
+                let message = match result {
+                    Ok(Reply::Unary(message)) => Ok(message),
+                    Ok(Reply::Stream(responses)) => {
+                        await!(send_stream(
+                            ctx,
+                            request_id,
+                            responses,
+                            credit,
+                            deadline_instant,
+                            peer,
+                            response_tx
+                        ));
+                        return;
+                    }
+                    Err(e) => Err(make_server_error(e, trace_id, &peer, deadline)),
                 };
+                let response = ServerMessage::Response(Response {
+                    request_id,
+                    message,
+                });
                 trace!("[{}/{}] Sending response.", trace_id, peer);
                 await!(response_tx.send((ctx, response)).unwrap_or_else(|_| ()));
             },
@@ -487,22 +825,217 @@ where
                     ),
                 )
             })?;
-        self.in_flight_requests().insert(request_id, abort_handle);
+        self.in_flight_requests().insert(
+            request_id,
+            InFlightRequest {
+                abort_handle,
+                response_credit,
+            },
+        );
+        if let Some(inputs_tx) = inputs_tx {
+            self.request_inputs().insert(request_id, inputs_tx);
+            // The client may send the first input without credit.
+            let credit = (self.channel.config.pending_request_buffer as u64).saturating_sub(1);
+            if credit > 0 {
+                let _ = self.input_credits_tx.unbounded_send((request_id, credit));
+            }
+        }
         Ok(())
     }
 
+    /// Passes an input, or the end of the inputs if `input` is `None`, to the handler of the
+    /// request with ID `request_id`. If the client sent more inputs than it was granted credit
+    /// for, the request is canceled and fails.
+    fn handle_input(
+        self: &mut Pin<&mut Self>,
+        trace_context: &trace::Context,
+        request_id: u64,
+        input: Option<Req>,
+    ) {
+        let end = input.is_none();
+        let result = match self.request_inputs().get_mut(&request_id) {
+            // A new sender always has room for one message, so the end of the inputs is never
+            // held back by inputs the handler hasn't read.
+            Some(inputs) if end => inputs.clone().try_send(None),
+            Some(inputs) => inputs.try_send(input),
+            None => {
+                // It's possible the request was already completed or canceled.
+                trace!(
+                    "[{}/{}] Discarding input to request {}, which is not in flight.",
+                    trace_context.trace_id,
+                    self.channel.peer,
+                    request_id
+                );
+                return;
+            }
+        };
+        match result {
+            Err(ref e) if e.is_full() => {
+                warn!(
+                    "[{}/{}] Client sent more inputs to request {} than it was granted credit for.",
+                    trace_context.trace_id, self.channel.peer, request_id
+                );
+                self.cancel_request(trace_context, request_id);
+                let ctx = Context::with_deadline(
+                    SystemTime::now(),
+                    trace_context.clone(),
+                    BTreeMap::new(),
+                );
+                let response = ServerMessage::Response(Response {
+                    request_id,
+                    message: Err(ServerError::new(
+                        Code::Internal,
+                        "Client sent more inputs than the server had room for.",
+                    )),
+                });
+                // A new sender always has room for one message, so this can't fail for lack of
+                // space.
+                let _ = self.responses_tx().clone().try_send((ctx, response));
+            }
+            // The handler dropped its inputs.
+            Err(_) => {
+                self.request_inputs().remove(&request_id);
+            }
+            Ok(()) => {
+                if end {
+                    self.request_inputs().remove(&request_id);
+                }
+            }
+        }
+    }
+
+    /// Fails the request that a message the transport skipped, e.g. because it was too large,
+    /// belongs to.
+    fn handle_skipped(self: &mut Pin<&mut Self>, skipped: &SkippedMessage<ClientMessageHeader>) {
+        let peer = self.channel.peer.clone();
+        let header = match &skipped.header {
+            Some(header) => header,
+            None => {
+                warn!("[{}] Transport skipped an unidentified message: {}", peer, skipped.reason);
+                return;
+            }
+        };
+        let request_id = match header.kind {
+            ClientMessageHeaderKind::Request { id } => id,
+            ClientMessageHeaderKind::StreamItem { request_id } => {
+                // The handler can't be trusted to respond correctly to incomplete inputs.
+                self.cancel_request(&header.trace_context, request_id);
+                request_id
+            }
+            _ => {
+                warn!(
+                    "[{}/{}] Transport skipped a message: {}",
+                    header.trace_context.trace_id, peer, skipped.reason
+                );
+                return;
+            }
+        };
+        warn!(
+            "[{}/{}] Transport skipped a message of request {}: {}",
+            header.trace_context.trace_id, peer, request_id, skipped.reason
+        );
+        let ctx = Context::with_deadline(
+            SystemTime::now(),
+            header.trace_context.clone(),
+            BTreeMap::new(),
+        );
+        let response = ServerMessage::Response(Response {
+            request_id,
+            message: Err(ServerError::new(
+                Code::InvalidArgument,
+                format!("Server could not read request: {}", skipped.reason),
+            )),
+        });
+        // A new sender always has room for one message, so this can't fail for lack of space,
+        // and the response is written in turn with handlers' responses.
+        let _ = self.responses_tx().clone().try_send((ctx, response));
+    }
+
+    /// Answers a client's handshake. The connection is closed after a rejection is sent.
+    fn handle_handshake(self: &mut Pin<&mut Self>, handshake: &Handshake) -> io::Result<()> {
+        let result = match self.channel.config.handshake {
+            Some(ref server_handshake) => server_handshake.check(handshake),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) => trace!("[{}] Accepted handshake.", self.channel.peer),
+            Err(ref e) => {
+                info!(
+                    "[{}] Rejected handshake: {}",
+                    self.channel.peer,
+                    e.detail.as_ref().map(String::as_str).unwrap_or_default()
+                );
+                *self.handshake_rejected() = true;
+            }
+        }
+        self.channel().start_send(ServerMessage::Handshake(result))
+    }
+
+    /// Returns true if the server is shutting down. Once the shutdown grace period elapses,
+    /// aborts all requests still in flight.
+    fn poll_shutdown(self: &mut Pin<&mut Self>, cx: &LocalWaker) -> bool {
+        match self.shutdown_signal().poll_phase(cx) {
+            Phase::Serving => false,
+            Phase::Draining => {
+                *self.shutting_down() = true;
+                true
+            }
+            Phase::Expired => {
+                *self.shutting_down() = true;
+                if !self.in_flight_requests().is_empty() {
+                    info!(
+                        "[{}] Shutdown grace period elapsed. Aborting {} in-flight requests.",
+                        self.channel.peer,
+                        self.in_flight_requests().len(),
+                    );
+                    for (_, in_flight_request) in self.in_flight_requests().drain() {
+                        in_flight_request.abort_handle.abort();
+                    }
+                    self.in_flight_requests().compact(0.1);
+                    self.request_inputs().clear();
+                    self.request_inputs().compact(0.1);
+                }
+                true
+            }
+        }
+    }
+
+    /// Lets the request with ID `request_id` send `credit` more of its stream of responses.
+    fn grant_response_credit(
+        self: &mut Pin<&mut Self>,
+        trace_context: &trace::Context,
+        request_id: u64,
+        credit: u64,
+    ) {
+        match self.in_flight_requests().get(&request_id) {
+            Some(in_flight_request) => {
+                let _ = in_flight_request.response_credit.unbounded_send(credit);
+            }
+            // It's possible the request was already completed or canceled.
+            None => trace!(
+                "[{}/{}] Discarding credit for request {}, which is not in flight.",
+                trace_context.trace_id,
+                self.channel.peer,
+                request_id
+            ),
+        }
+    }
+
     fn cancel_request(self: &mut Pin<&mut Self>, trace_context: &trace::Context, request_id: u64) {
         // It's possible the request was already completed, so it's fine
         // if this is None.
-        if let Some(cancel_handle) = self.in_flight_requests().remove(&request_id) {
+        if let Some(in_flight_request) = self.in_flight_requests().remove(&request_id) {
             self.in_flight_requests().compact(0.1);
+            if let Some(_) = self.request_inputs().remove(&request_id) {
+                self.request_inputs().compact(0.1);
+            }
 
-            cancel_handle.abort();
+            in_flight_request.abort_handle.abort();
             let remaining = self.in_flight_requests().len();
             trace!(
                 "[{}/{}] Request canceled. In-flight requests = {}",
                 trace_context.trace_id,
-                self.channel.client_addr,
+                self.channel.peer,
                 remaining,
             );
         } else {
@@ -510,35 +1043,43 @@ where
                 "[{}/{}] Received cancellation, but response handler \
                  is already complete.",
                 trace_context.trace_id,
-                self.channel.client_addr
+                self.channel.peer
             );
         }
     }
 }
 
-impl<Req, Resp, T, F, Fut> Future for ClientHandler<Req, Resp, T, F>
+impl<Req, Resp, T, F> Future for ClientHandler<Req, Resp, T, F>
 where
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
-    F: FnMut(Context, Req) -> Fut + Send + 'static,
-    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
+    F: RequestHandler<Req, Resp> + Send + 'static,
 {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
-        trace!("[{}] ClientHandler::poll", self.channel.client_addr);
+        trace!("[{}] ClientHandler::poll", self.channel.peer);
         loop {
+            let shutting_down = self.poll_shutdown(cx);
             let read = self.pump_read(cx)?;
-            match (read, self.pump_write(cx, read == Poll::Ready(None))?) {
+            let read_half_closed = read == Poll::Ready(None);
+            match (read, self.pump_write(cx, read_half_closed || shutting_down)?) {
                 (Poll::Ready(None), Poll::Ready(None)) => {
-                    info!("[{}] Client disconnected.", self.channel.client_addr);
+                    info!("[{}] Client disconnected.", self.channel.peer);
+                    return Poll::Ready(Ok(()));
+                }
+                (_, Poll::Ready(None)) if shutting_down => {
+                    info!(
+                        "[{}] Shutdown: no requests in flight. Closing connection.",
+                        self.channel.peer
+                    );
                     return Poll::Ready(Ok(()));
                 }
                 (read @ Poll::Ready(Some(())), write) | (read, write @ Poll::Ready(Some(()))) => {
                     trace!(
                         "[{}] read: {:?}, write: {:?}.",
-                        self.channel.client_addr,
+                        self.channel.peer,
                         read,
                         write
                     )
@@ -546,7 +1087,7 @@ where
                 (read, write) => {
                     trace!(
                         "[{}] read: {:?}, write: {:?} (not ready).",
-                        self.channel.client_addr,
+                        self.channel.peer,
                         read,
                         write,
                     );
@@ -557,10 +1098,62 @@ where
     }
 }
 
+/// Sends each response in a streamed reply to the client, followed by the end of the stream.
+/// The stream is cut short if it yields an error or outlives the request's deadline.
+///
+/// Each response uses up a unit of the credit the client grants the request. The stream isn't
+/// polled while the credit is used up, so that it's paused until the client catches up; the
+/// deadline still applies while it waits.
+async fn send_stream<Resp>(
+    ctx: Context,
+    request_id: u64,
+    mut responses: BoxStream<'static, io::Result<Resp>>,
+    mut credit: Credit,
+    deadline: Instant,
+    peer: Peer,
+    mut response_tx: mpsc::Sender<(Context, ServerMessage<Resp>)>,
+) {
+    let trace_id = *ctx.trace_id();
+    loop {
+        let mut acquired = false;
+        let next = future::poll_fn(|waker| {
+            if !acquired {
+                if !ready!(credit.poll_acquire(waker)) {
+                    // The request is no longer in flight, so the client isn't waiting for
+                    // responses.
+                    return Poll::Ready(Ok(None));
+                }
+                acquired = true;
+            }
+            Poll::Ready(match ready!(responses.poll_next_unpin(waker)) {
+                Some(Ok(response)) => Ok(Some(response)),
+                Some(Err(e)) => Err(e),
+                None => Ok(None),
+            })
+        });
+        let next = context::WithContext::new(ctx.clone(), next);
+        let (message, last) = match await!(deadline_compat::Deadline::new(next, deadline)) {
+            Ok(Some(response)) => (Ok(response), false),
+            Ok(None) => break,
+            Err(e) => (Err(make_server_error(e, trace_id, &peer, ctx.deadline)), true),
+        };
+        trace!("[{}/{}] Sending stream item.", trace_id, peer);
+        let item = ServerMessage::StreamItem(Response {
+            request_id,
+            message,
+        });
+        if await!(response_tx.send((ctx.clone(), item))).is_err() || last {
+            break;
+        }
+    }
+    trace!("[{}/{}] Ending stream.", trace_id, peer);
+    await!(response_tx.send((ctx, ServerMessage::StreamEnd { request_id })).unwrap_or_else(|_| ()));
+}
+
 fn make_server_error(
     e: timeout::Error<io::Error>,
     trace_id: TraceId,
-    peer: SocketAddr,
+    peer: &Peer,
     deadline: SystemTime,
 ) -> ServerError {
     if e.is_elapsed() {
@@ -571,35 +1164,29 @@ fn make_server_error(
             format_rfc3339(deadline)
         );
         // No point in responding, since the client will have dropped the request.
-        ServerError {
-            kind: io::ErrorKind::TimedOut,
-            detail: Some(format!(
+        ServerError::new(
+            Code::DeadlineExceeded,
+            format!(
                 "Response did not complete before deadline of {}s.",
                 format_rfc3339(deadline)
-            )),
-        }
+            ),
+        )
     } else if e.is_timer() {
         error!(
             "[{}/{}] Response failed because of an issue with a timer: {}",
             trace_id, peer, e
         );
 
-        ServerError {
-            kind: io::ErrorKind::Other,
-            detail: Some(format!("{}", e)),
-        }
+        ServerError::new(Code::Internal, format!("{}", e))
     } else if e.is_inner() {
-        let e = e.into_inner().unwrap();
-        ServerError {
-            kind: e.kind(),
-            detail: Some(e.description().into()),
-        }
+        // Handlers choose the code of the error by failing with a server error of their own.
+        ServerError::from(e.into_inner().unwrap())
     } else {
         error!("[{}/{}] Unexpected response failure: {}", trace_id, peer, e);
 
-        ServerError {
-            kind: io::ErrorKind::Other,
-            detail: Some(format!("Server unexpectedly failed to respond: {}", e)),
-        }
+        ServerError::new(
+            Code::Internal,
+            format!("Server unexpectedly failed to respond: {}", e),
+        )
     }
 }
@@ -0,0 +1,51 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{prelude::*, stream::BoxStream};
+use std::{fmt, io};
+
+/// A request handler's reply to a request: either a single response, or a stream of responses.
+///
+/// Request handlers can resolve to a `Reply`, or to a plain response, which is converted into a
+/// [`Unary`](Reply::Unary) reply.
+pub enum Reply<Resp> {
+    /// A single response.
+    Unary(Resp),
+    /// A stream of responses, each sent to the client as soon as it is ready. The stream ends
+    /// early if it yields an error, and is dropped if the client cancels the request or the
+    /// request's deadline passes.
+    Stream(BoxStream<'static, io::Result<Resp>>),
+}
+
+impl<Resp> Reply<Resp> {
+    /// Returns a reply whose responses are this reply's responses passed through `f`.
+    pub fn map<U, F>(self, mut f: F) -> Reply<U>
+    where
+        Resp: Send + 'static,
+        U: Send + 'static,
+        F: FnMut(Resp) -> U + Send + 'static,
+    {
+        match self {
+            Reply::Unary(response) => Reply::Unary(f(response)),
+            Reply::Stream(responses) => Reply::Stream(responses.map_ok(f).boxed()),
+        }
+    }
+}
+
+impl<Resp> From<Resp> for Reply<Resp> {
+    fn from(response: Resp) -> Self {
+        Reply::Unary(response)
+    }
+}
+
+impl<Resp: fmt::Debug> fmt::Debug for Reply<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reply::Unary(response) => f.debug_tuple("Unary").field(response).finish(),
+            Reply::Stream(_) => f.debug_tuple("Stream").finish(),
+        }
+    }
+}
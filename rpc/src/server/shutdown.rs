@@ -0,0 +1,160 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{
+    channel::{mpsc, oneshot},
+    compat::{Compat01As03, Future01CompatExt},
+    prelude::*,
+    task::{LocalWaker, Poll},
+};
+use log::{error, info};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+/// A handle that gracefully shuts down a running server.
+///
+/// When shutdown is initiated, the server stops accepting connections, and tells each connected
+/// client that it accepts no new requests by sending it a
+/// [`ServerMessage::Shutdown`](crate::ServerMessage::Shutdown). Requests the client sends anyway
+/// are rejected with [`Code::Unavailable`](crate::Code::Unavailable). Requests already in flight
+/// are given until the end of the grace period to complete, after which they are aborted. Each
+/// connection is closed once it has no requests in flight.
+#[derive(Clone, Debug)]
+pub struct Shutdown(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    /// The end of the grace period, set once shutdown is initiated.
+    deadline: Option<Instant>,
+    /// Notified of the end of the grace period when shutdown is initiated.
+    subscribers: Vec<oneshot::Sender<Instant>>,
+    /// Cloned by each subscriber, and dropped when shutdown is initiated, so that `drained` ends
+    /// once all subscribers are dropped.
+    keep_alive: Option<mpsc::UnboundedSender<()>>,
+    /// Ends when the server and all its connections have shut down.
+    drained: Option<mpsc::UnboundedReceiver<()>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> Self {
+        let (keep_alive, drained) = mpsc::unbounded();
+        Shutdown(Arc::new(Mutex::new(State {
+            deadline: None,
+            subscribers: vec![],
+            keep_alive: Some(keep_alive),
+            drained: Some(drained),
+        })))
+    }
+
+    /// Initiates shutdown, giving in-flight requests `grace_period` to complete. Returns a future
+    /// that resolves once the server and all its connections have shut down.
+    ///
+    /// Only the future returned by the first call waits for shutdown to complete; subsequent
+    /// calls return futures that resolve immediately.
+    pub fn shutdown(&self, grace_period: Duration) -> impl Future<Output = ()> {
+        let mut state = self.0.lock().unwrap();
+        let deadline = *state
+            .deadline
+            .get_or_insert_with(|| Instant::now() + grace_period);
+        info!("Shutting down server, with a grace period of {:?}.", grace_period);
+        for subscriber in state.subscribers.drain(..) {
+            let _ = subscriber.send(deadline);
+        }
+        state.keep_alive.take();
+        let drained = state.drained.take();
+
+        async move {
+            if let Some(mut drained) = drained {
+                while let Some(()) = await!(drained.next()) {}
+            }
+        }
+    }
+
+    /// Returns a signal that is notified when shutdown is initiated. Shutdown is not complete
+    /// until the signal is dropped.
+    pub(crate) fn subscribe(&self) -> ShutdownSignal {
+        let mut state = self.0.lock().unwrap();
+        let (tx, rx) = oneshot::channel();
+        match state.deadline {
+            Some(deadline) => {
+                let _ = tx.send(deadline);
+            }
+            None => {
+                state.subscribers.retain(|subscriber| !subscriber.is_canceled());
+                state.subscribers.push(tx);
+            }
+        }
+        ShutdownSignal {
+            state: SignalState::Serving(rx),
+            _keep_alive: state.keep_alive.clone(),
+        }
+    }
+}
+
+/// The phases of a graceful shutdown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Shutdown has not been initiated.
+    Serving,
+    /// Shutdown has been initiated, and in-flight requests are given time to complete.
+    Draining,
+    /// The grace period has elapsed, so in-flight requests should be aborted.
+    Expired,
+}
+
+/// Notifies a server or connection of shutdown.
+#[derive(Debug)]
+pub(crate) struct ShutdownSignal {
+    state: SignalState,
+    _keep_alive: Option<mpsc::UnboundedSender<()>>,
+}
+
+#[derive(Debug)]
+enum SignalState {
+    Serving(oneshot::Receiver<Instant>),
+    Draining(Compat01As03<Delay>),
+    Expired,
+    /// The server can no longer be shut down, because all handles to it were dropped.
+    Never,
+}
+
+impl ShutdownSignal {
+    /// Returns a signal that is never notified.
+    pub(crate) fn never() -> Self {
+        ShutdownSignal {
+            state: SignalState::Never,
+            _keep_alive: None,
+        }
+    }
+
+    /// Returns the current phase of shutdown, scheduling a wakeup for when it changes.
+    pub(crate) fn poll_phase(&mut self, cx: &LocalWaker) -> Phase {
+        loop {
+            match self.state {
+                SignalState::Serving(ref mut deadline) => match deadline.poll_unpin(cx) {
+                    Poll::Ready(Ok(deadline)) => {
+                        self.state = SignalState::Draining(Delay::new(deadline).compat())
+                    }
+                    Poll::Ready(Err(oneshot::Canceled)) => self.state = SignalState::Never,
+                    Poll::Pending => return Phase::Serving,
+                },
+                SignalState::Draining(ref mut grace_period) => match grace_period.poll_unpin(cx) {
+                    Poll::Ready(Ok(())) => self.state = SignalState::Expired,
+                    Poll::Ready(Err(e)) => {
+                        error!("Grace period timer failed: {}", e);
+                        self.state = SignalState::Expired;
+                    }
+                    Poll::Pending => return Phase::Draining,
+                },
+                SignalState::Expired => return Phase::Expired,
+                SignalState::Never => return Phase::Serving,
+            }
+        }
+    }
+}
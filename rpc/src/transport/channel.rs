@@ -6,14 +6,10 @@
 
 //! Transports backed by in-memory channels.
 
-use crate::Transport;
+use crate::{transport::Address, Transport};
 use futures::{channel::mpsc, task::{LocalWaker}, Poll, Sink, Stream};
 use pin_utils::unsafe_pinned;
-use std::pin::Pin;
-use std::{
-    io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-};
+use std::{io, pin::Pin};
 
 /// Returns two unbounded channel peers. Each [`Stream`] yields items sent through the other's
 /// [`Sink`].
@@ -86,21 +82,41 @@ impl<Item, SinkItem> Transport for UnboundedChannel<Item, SinkItem> {
     type Item = Item;
     type SinkItem = SinkItem;
 
-    fn peer_addr(&self) -> io::Result<SocketAddr> {
-        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    fn peer_addr(&self) -> io::Result<Address> {
+        Ok(Address::InProcess)
     }
 
-    fn local_addr(&self) -> io::Result<SocketAddr> {
-        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    fn local_addr(&self) -> io::Result<Address> {
+        Ok(Address::InProcess)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{client::{self, Client}, context, server::{self, Handler, Server}, transport};
-    use futures::{prelude::*, stream, compat::TokioDefaultSpawner};
+    use crate::{
+        client::{self, Client}, context::{self, Context}, server::{self, Handler, Reply, Server},
+        transport, ClientMessage, ClientMessageKind, Code, Handshake, Request, ServerError,
+        ServerMessage,
+    };
+    use futures::{
+        channel::oneshot,
+        compat::{Future01CompatExt, TokioDefaultSpawner},
+        future::abortable,
+        prelude::*,
+        stream::{self, BoxStream},
+        Poll,
+    };
     use log::trace;
-    use std::io;
+    use std::{
+        collections::BTreeMap,
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant, SystemTime},
+    };
+    use tokio_timer::Delay;
 
     #[test]
     fn integration() {
@@ -140,6 +156,874 @@ mod tests {
         assert_eq!(response2.err().unwrap().kind(), io::ErrorKind::InvalidInput);
     }
 
+    #[test]
+    fn middleware() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            // Rejects requests before they reach the handler.
+            .layer(|ctx, _peer, request: String, next: server::Next<String, String>| {
+                if request.is_empty() {
+                    future::ready(Err(io::Error::from(io::ErrorKind::PermissionDenied))).boxed()
+                } else {
+                    next.run(ctx, request)
+                }
+            })
+            // Modifies responses from the handler.
+            .layer(|ctx, _peer, request, next: server::Next<String, String>| {
+                next.run(ctx, request)
+                    .map_ok(|reply| reply.map(|response| format!("<{}>", response)))
+                    .boxed()
+            })
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let responses = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+
+            let response1 = await!(client.call(context::current(), "hi".into()));
+            let response2 = await!(client.call(context::current(), "".into()));
+
+            Ok::<_, io::Error>((response1, response2))
+        };
+
+        let (response1, response2) =
+            run_future(server.join(responses.unwrap_or_else(|e| panic!(e)))).1;
+
+        assert_eq!(response1.unwrap(), "<hi>");
+        assert_eq!(response2.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn interceptor() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with({
+                let requests = requests.clone();
+                move |ctx, key| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    future::ready(Ok(ctx.metadata.get(&key).cloned().unwrap_or_default()))
+                }
+            });
+
+        let responses = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            // Authenticates calls, and fails calls it can't authenticate without sending them.
+            client.intercept(|mut ctx: Context, key: String, next: client::Next<String, String>| {
+                if key.is_empty() {
+                    return future::ready(Err(io::Error::from(io::ErrorKind::PermissionDenied)))
+                        .boxed();
+                }
+                ctx.metadata.insert("token".into(), "secret".into());
+                next.run(ctx, key)
+            });
+            // Sees the metadata set by the interceptor added before it.
+            client.intercept(|mut ctx: Context, key, next: client::Next<String, String>| {
+                let token = ctx.metadata.get("token").cloned().unwrap_or_default();
+                ctx.metadata.insert("seen".into(), format!("{} seen", token));
+                next.run(ctx, key)
+            });
+
+            let response1 = await!(client.call(context::current(), "token".into()));
+            let response2 = await!(client.call(context::current(), "seen".into()));
+            let response3 = await!(client.call(context::current(), "".into()));
+            // Streaming calls would bypass the interceptors, so they aren't sent.
+            let stream = await!(client.call_stream(context::current(), "token".into()));
+
+            Ok::<_, io::Error>((response1, response2, response3, stream.map(|_| ())))
+        };
+
+        let (response1, response2, response3, stream) =
+            run_future(server.join(responses.unwrap_or_else(|e| panic!(e)))).1;
+
+        // The server receives the metadata set by the interceptors.
+        assert_eq!(response1.unwrap(), "secret");
+        assert_eq!(response2.unwrap(), "secret seen");
+        assert_eq!(response3.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(stream.unwrap_err().kind(), io::ErrorKind::Other);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn skewed_clock() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, SystemTime>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|ctx, _request| future::ready(Ok(ctx.deadline)));
+
+        // Requests from a client whose clock runs an hour behind the server's: by the server's
+        // clock, their deadlines passed long ago.
+        let request = |id, timeout| ClientMessage {
+            trace_context: trace::Context::new_root(),
+            message: ClientMessageKind::Request(Request {
+                id,
+                message: "hi".to_string(),
+                deadline: SystemTime::now() - Duration::from_secs(3600) + Duration::from_secs(10),
+                timeout,
+                metadata: BTreeMap::new(),
+                streams_input: false,
+            }),
+        };
+
+        let responses = async move {
+            await!(client_channel.send(request(0, Some(Duration::from_secs(10)))))?;
+            await!(client_channel.send(request(1, None)))?;
+            let mut responses = vec![];
+            while responses.len() < 2 {
+                match await!(client_channel.next()) {
+                    Some(Ok(ServerMessage::Response(response))) => responses.push(response),
+                    message => panic!("Unexpected message: {:?}", message),
+                }
+            }
+            responses.sort_by_key(|response| response.request_id);
+            Ok::<_, io::Error>(responses)
+        };
+
+        let start = SystemTime::now();
+        let (_, responses) = run_future(server.join(responses));
+        let mut responses = responses.unwrap().into_iter().map(|response| response.message);
+
+        // The server measures the timeout from when it receives the request...
+        let deadline = responses.next().unwrap().unwrap();
+        assert!(deadline >= start + Duration::from_secs(9));
+        // ...but has to trust the client's deadline when the client sends no timeout.
+        assert_eq!(responses.next().unwrap().unwrap_err().code, Code::DeadlineExceeded);
+    }
+
+    #[test]
+    fn current_context() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, bool>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|ctx, _request| {
+                future::lazy(move |_| {
+                    let current = context::current();
+                    Ok(current.deadline == ctx.deadline
+                        && current.trace_id() == ctx.trace_id()
+                        && current.trace_context.parent_id == Some(ctx.trace_context.span_id))
+                })
+            });
+
+        let response = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        assert!(response.unwrap());
+    }
+
+    #[test]
+    fn metadata() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|ctx, key| {
+                future::lazy(move |_| {
+                    // Metadata propagates to the context used for downstream requests.
+                    assert_eq!(context::current().metadata, ctx.metadata);
+                    Ok(ctx.metadata.get(&key).cloned().unwrap_or_default())
+                })
+            });
+
+        let response = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let mut ctx = context::current();
+            ctx.metadata.insert("tenant".into(), "acme".into());
+            await!(client.call(ctx, "tenant".into()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        assert_eq!(response.unwrap(), "acme");
+    }
+
+    #[test]
+    fn server_error() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, _request| {
+                let e = ServerError::new(Code::Unauthenticated, "Who are you?")
+                    .with_retry_after(Duration::from_secs(1))
+                    .with_detail("realm", b"rpc".to_vec());
+                future::ready(Err::<String, _>(io::Error::from(e)))
+            });
+
+        let response = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        let e = response.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(e.to_string(), "Who are you?");
+        let e = ServerError::downcast_ref(&e).unwrap();
+        assert_eq!(e.code, Code::Unauthenticated);
+        assert_eq!(e.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(e.details["realm"], b"rpc");
+    }
+
+    /// Returns a stream that yields `items` and then never ends, and that takes the sender out of
+    /// `dropped` to notify when the stream is dropped.
+    fn unending_stream<T: Send + 'static>(
+        items: Vec<T>,
+        dropped: &Mutex<Option<oneshot::Sender<()>>>,
+    ) -> BoxStream<'static, T> {
+        struct Guard(Option<oneshot::Sender<()>>);
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                if let Some(dropped) = self.0.take() {
+                    let _ = dropped.send(());
+                }
+            }
+        }
+
+        let guard = Guard(dropped.lock().unwrap().take());
+        stream::iter(items)
+            .chain(stream::poll_fn(move |_| {
+                let _ = &guard;
+                Poll::Pending
+            }))
+            .boxed()
+    }
+
+    #[test]
+    fn stream_cancellation() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (dropped_tx, dropped) = oneshot::channel();
+        let dropped_tx = Arc::new(Mutex::new(Some(dropped_tx)));
+        let server = Server::<String, u64>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(move |_ctx, _request| {
+                future::ready(Ok(Reply::Stream(unending_stream(vec![Ok(1)], &dropped_tx))))
+            });
+
+        let elapsed = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_secs(60);
+            let mut responses = await!(client.call_stream(ctx, "hi".into()))?;
+            assert_eq!(await!(responses.next()).unwrap()?, 1);
+
+            let start = Instant::now();
+            drop(responses);
+            // The server drops the stream when the cancellation arrives, well before the
+            // deadline.
+            await!(dropped).unwrap();
+            Ok::<_, io::Error>(start.elapsed())
+        };
+
+        let elapsed = run_future(server.join(elapsed.unwrap_or_else(|e| panic!(e)))).1;
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn stream_error() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, u64>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, _request| {
+                let responses: Vec<io::Result<u64>> = vec![
+                    Ok(1),
+                    Err(ServerError::new(Code::Internal, "Out of numbers.").into()),
+                    Ok(3),
+                ];
+                future::ready(Ok(Reply::Stream(stream::iter(responses).boxed())))
+            });
+
+        let responses = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let responses = await!(client.call_stream(context::current(), "hi".into()))?;
+            Ok::<_, io::Error>(await!(responses.collect::<Vec<_>>()))
+        };
+
+        let mut responses = run_future(server.join(responses.unwrap_or_else(|e| panic!(e))))
+            .1
+            .into_iter();
+
+        // The stream ends with the error.
+        assert_eq!(responses.next().unwrap().unwrap(), 1);
+        let e = responses.next().unwrap().unwrap_err();
+        assert_eq!(ServerError::downcast_ref(&e).unwrap().code, Code::Internal);
+        assert!(responses.next().is_none());
+    }
+
+    #[test]
+    fn stream_deadline() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (dropped_tx, dropped) = oneshot::channel();
+        let dropped_tx = Arc::new(Mutex::new(Some(dropped_tx)));
+        let server = Server::<String, u64>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(move |_ctx, _request| {
+                future::ready(Ok(Reply::Stream(unending_stream(vec![Ok(1)], &dropped_tx))))
+            });
+
+        let responses = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_millis(200);
+            let responses = await!(client.call_stream(ctx, "hi".into()))?;
+            let responses = await!(responses.collect::<Vec<_>>());
+            // The server drops the stream too.
+            await!(dropped).unwrap();
+            Ok::<_, io::Error>(responses)
+        };
+
+        let mut responses = run_future(server.join(responses.unwrap_or_else(|e| panic!(e))))
+            .1
+            .into_iter();
+
+        assert_eq!(responses.next().unwrap().unwrap(), 1);
+        assert_eq!(responses.next().unwrap().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(responses.next().is_none());
+    }
+
+    #[test]
+    fn stream_paused_until_consumed() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let produced = Arc::new(AtomicUsize::new(0));
+        let server = Server::<String, u64>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with({
+                let produced = produced.clone();
+                move |_ctx, _request| {
+                    let produced = produced.clone();
+                    let responses = stream::iter(0..100).map(move |response| {
+                        produced.fetch_add(1, Ordering::SeqCst);
+                        Ok::<u64, io::Error>(response)
+                    });
+                    future::ready(Ok(Reply::Stream(responses.boxed())))
+                }
+            });
+
+        let responses = async move {
+            let mut config = client::Config::default();
+            config.pending_request_buffer = 2;
+            let mut client = await!(Client::new(config, client_channel))?;
+            let responses = await!(client.call_stream(context::current(), "hi".into()))?;
+            // The server could produce responses faster than they're consumed.
+            await!(Delay::new(Instant::now() + Duration::from_millis(200)).compat()).unwrap();
+            let produced_while_paused = produced.load(Ordering::SeqCst);
+            Ok::<_, io::Error>((produced_while_paused, await!(responses.collect::<Vec<_>>())))
+        };
+
+        let (produced_while_paused, responses) =
+            run_future(server.join(responses.unwrap_or_else(|e| panic!(e)))).1;
+
+        // The server's stream got only as far ahead as the client had room for, and no
+        // responses were lost.
+        assert_eq!(produced_while_paused, 2);
+        assert_eq!(responses.len(), 100);
+        assert!(responses.into_iter().all(|response| response.is_ok()));
+    }
+
+    #[test]
+    fn inputs_canceled() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (received_tx, received) = oneshot::channel();
+        let (ended_tx, ended) = oneshot::channel();
+        let senders = Arc::new(Mutex::new(Some((received_tx, ended_tx))));
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with_extras(move |_ctx, _request, extras| {
+                let mut inputs = extras.inputs.unwrap();
+                let (received_tx, ended_tx) = senders.lock().unwrap().take().unwrap();
+                // Reads the inputs outside the handler, which never completes.
+                crate::spawn(
+                    async move {
+                        let _ = received_tx.send(await!(inputs.next()).unwrap().unwrap());
+                        let _ = ended_tx.send(await!(inputs.collect::<Vec<_>>()));
+                    },
+                ).unwrap();
+                future::empty::<io::Result<String>>()
+            });
+
+        let (dropped_tx, dropped) = oneshot::channel();
+        let dropped_tx = Mutex::new(Some(dropped_tx));
+        let ended = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let inputs = unending_stream(vec!["a".to_string()], &dropped_tx);
+            let (call, abort) =
+                abortable(client.call_with_inputs(context::current(), "hi".into(), inputs));
+            let drop_call = async move {
+                let input = await!(received).unwrap();
+                abort.abort();
+                input
+            };
+            let (call, input) = await!(call.join(drop_call));
+            assert!(call.is_err());
+            assert_eq!(input, "a");
+
+            // The client stops sending inputs once the call is dropped.
+            await!(dropped).unwrap();
+            Ok::<_, io::Error>(await!(ended).unwrap())
+        };
+
+        let ended = run_future(server.join(ended.unwrap_or_else(|e| panic!(e)))).1;
+
+        // The server ends the canceled request's inputs.
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].as_ref().unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn inputs_not_read() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let mut config = server::Config::default();
+        config.pending_request_buffer = 2;
+        let server = Server::<String, String>::new(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, request| {
+                // Drops the inputs unread, and responds after a while.
+                async move {
+                    await!(Delay::new(Instant::now() + Duration::from_millis(100)).compat())
+                        .unwrap();
+                    Ok::<_, io::Error>(request)
+                }
+            });
+
+        let response = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let inputs = stream::iter(vec!["a".to_string(); 10]);
+            await!(client.call_with_inputs(context::current(), "hi".into(), inputs))
+        };
+
+        let response = run_future(server.join(response)).1;
+        assert_eq!(response.unwrap(), "hi");
+    }
+
+    #[test]
+    fn inputs_paused_until_read() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let mut config = server::Config::default();
+        config.pending_request_buffer = 2;
+        let server = Server::<String, String>::new(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with_extras({
+                let sent = sent.clone();
+                move |_ctx, request, extras| {
+                    let inputs = extras.inputs;
+                    let sent = sent.clone();
+                    async move {
+                        let inputs = match inputs {
+                            Some(inputs) => inputs,
+                            None => return Ok(request),
+                        };
+                        // The client could send inputs faster than they're read.
+                        await!(Delay::new(Instant::now() + Duration::from_millis(200)).compat())
+                            .unwrap();
+                        let sent_while_unread = sent.load(Ordering::SeqCst);
+                        let inputs = await!(inputs.collect::<Vec<_>>());
+                        Ok::<_, io::Error>(format!("{} {}", sent_while_unread, inputs.len()))
+                    }
+                }
+            });
+
+        let responses = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            let inputs = stream::iter(vec!["a".to_string(); 10]).map(move |input| {
+                sent.fetch_add(1, Ordering::SeqCst);
+                input
+            });
+            let mut paused_client = client.clone();
+            let paused = paused_client.call_with_inputs(context::current(), "pause".into(), inputs);
+            let unary = client.call(context::current(), "hi".into());
+            Ok::<_, io::Error>(await!(paused.join(unary)))
+        };
+
+        let (paused, unary) = run_future(server.join(responses.unwrap_or_else(|e| panic!(e)))).1;
+
+        // The client's inputs got only as far ahead as the server had room for, and no inputs
+        // were lost. The connection kept serving other requests meanwhile.
+        assert_eq!(paused.unwrap(), "2 10");
+        assert_eq!(unary.unwrap(), "hi");
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, _request| future::empty::<io::Result<String>>());
+        let shutdown = server.shutdown_handle();
+
+        let response = async move {
+            let mut client = await!(Client::new(client::Config::default(), client_channel))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+        let shutdown = async move {
+            await!(Delay::new(Instant::now() + Duration::from_millis(100)).compat()).unwrap();
+            await!(shutdown.shutdown(Duration::from_millis(100)));
+        };
+
+        // The response never completes on its own, so it is aborted once the grace period
+        // elapses, and the connection is closed.
+        let (_, (response, ())) = run_future(server.join(response.join(shutdown)));
+        assert_eq!(response.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn handshake() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let mut server_config = server::Config::default();
+        server_config.handshake = Some(Handshake::new("Echo", "rpc echo(s: String) -> String;"));
+        let (client_channel1, server_channel1) = transport::channel::unbounded();
+        let (client_channel2, server_channel2) = transport::channel::unbounded();
+        let server = Server::<String, String>::new(server_config)
+            .incoming(stream::iter(vec![Ok(server_channel1), Ok(server_channel2)]))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let responses = async move {
+            let mut config = client::Config::default();
+            config.handshake = Some(Handshake::new("Echo", "rpc echo(s: String) -> String;"));
+            let mut client = await!(Client::new(config, client_channel1))?;
+            let response = await!(client.call(context::current(), "hi".into()));
+
+            let mut config = client::Config::default();
+            config.handshake = Some(Handshake::new("Echo", "rpc echo(s: String) -> u64;"));
+            let rejected = await!(Client::<String, String>::new(config, client_channel2));
+
+            Ok::<_, io::Error>((response, rejected.map(|_| ())))
+        };
+
+        let (response, rejected) =
+            run_future(server.join(responses.unwrap_or_else(|e| panic!(e)))).1;
+
+        assert_eq!(response.unwrap(), "hi");
+        let e = rejected.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("different definitions of service \"Echo\""), "{}", e);
+    }
+
+    #[test]
+    fn handshake_fingerprint_ignores_whitespace() {
+        let handshake = Handshake::new("Echo", "rpc echo(s: Vec<u8>) -> String;");
+        assert_eq!(
+            handshake,
+            Handshake::new("Echo", "rpc echo ( s : Vec < u8 > ) -> String ;")
+        );
+        assert_ne!(handshake, Handshake::new("Echo", "rpcecho(s: Vec<u8>) -> String;"));
+
+        let renamed = handshake.clone().with_service_name("Echo2");
+        assert_eq!(renamed.service_name, "Echo2");
+        assert_eq!(renamed.schema_fingerprint, handshake.schema_fingerprint);
+    }
+
+    #[test]
+    fn reconnect() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel1, server_channel1) = transport::channel::unbounded();
+        let (client_channel2, server_channel2) = transport::channel::unbounded();
+        // The first connection breaks right away, and the first attempt to replace it fails.
+        drop(server_channel1);
+        let transports = vec![
+            Ok(client_channel1),
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+            Ok(client_channel2),
+        ];
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let connect = {
+            let attempts = attempts.clone();
+            let mut transports = transports.into_iter();
+            move || {
+                attempts.lock().unwrap().push(Instant::now());
+                future::ready(transports.next().expect("Too many connection attempts."))
+            }
+        };
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel2))))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let response = async move {
+            let mut reconnect_config = client::ReconnectConfig::default();
+            reconnect_config.initial_backoff = Duration::from_millis(50);
+            let mut client = await!(Client::new_reconnecting(
+                client::Config::default(),
+                reconnect_config,
+                client::InFlightPolicy::fail(),
+                connect
+            ))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        assert_eq!(response.unwrap(), "hi");
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn reconnect_first_connect() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        // The server isn't listening yet when the client first tries to connect.
+        let transports = vec![
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+            Ok(client_channel),
+        ];
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let connect = {
+            let attempts = attempts.clone();
+            let mut transports = transports.into_iter();
+            move || {
+                attempts.lock().unwrap().push(Instant::now());
+                future::ready(transports.next().expect("Too many connection attempts."))
+            }
+        };
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let response = async move {
+            let mut reconnect_config = client::ReconnectConfig::default();
+            reconnect_config.initial_backoff = Duration::from_millis(50);
+            let mut client = await!(Client::new_reconnecting(
+                client::Config::default(),
+                reconnect_config,
+                client::InFlightPolicy::fail(),
+                connect
+            ))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        assert_eq!(response.unwrap(), "hi");
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn reconnect_on_shutdown() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_channel1, server_channel1) = transport::channel::unbounded();
+        let (client_channel2, server_channel2) = transport::channel::unbounded();
+        let connect = {
+            let mut transports = vec![client_channel1, client_channel2].into_iter();
+            move || future::ready(Ok(transports.next().expect("Too many connection attempts.")))
+        };
+        let draining = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel1))))
+            .respond_with(|_ctx, request: String| {
+                async move {
+                    if request == "slow" {
+                        await!(Delay::new(Instant::now() + Duration::from_millis(200)).compat())
+                            .unwrap();
+                    }
+                    Ok(format!("draining {}", request))
+                }
+            });
+        let shutdown = draining.shutdown_handle();
+        let replacement = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel2))))
+            .respond_with(|_ctx, request| future::ready(Ok(format!("replacement {}", request))));
+
+        let responses = async move {
+            let mut client = await!(Client::new_reconnecting(
+                client::Config::default(),
+                client::ReconnectConfig::default(),
+                client::InFlightPolicy::fail(),
+                connect
+            ))?;
+            let mut client2 = client.clone();
+            let slow = client.call(context::current(), "slow".into());
+            let fast = async move {
+                await!(Delay::new(Instant::now() + Duration::from_millis(50)).compat()).unwrap();
+                let _ = shutdown.shutdown(Duration::from_secs(10));
+                await!(Delay::new(Instant::now() + Duration::from_millis(50)).compat()).unwrap();
+                // Sent after the client was told of the shutdown, so it waits for the new
+                // connection rather than being rejected.
+                await!(client2.call(context::current(), "fast".into()))
+            };
+            Ok::<_, io::Error>(await!(slow.join(fast)))
+        };
+
+        let (slow, fast) =
+            run_future(draining.join(replacement).join(responses.unwrap_or_else(|e| panic!(e)))).1;
+        assert_eq!(slow.unwrap(), "draining slow");
+        assert_eq!(fast.unwrap(), "replacement fast");
+    }
+
+    #[test]
+    fn retry() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let mut retry_policy = client::RetryPolicy::default();
+        retry_policy.initial_backoff = Duration::from_millis(20);
+        retry_policy.jitter = 0.;
+        let errors = vec![
+            io::Error::from(io::ErrorKind::WouldBlock),
+            io::Error::from(io::ErrorKind::WouldBlock),
+        ];
+        let (response, attempts) = call_with_retry(retry_policy, Duration::from_secs(10), errors);
+
+        assert_eq!(response.unwrap(), "hi");
+        assert_eq!(attempts.len(), 3);
+        // The backoff doubles after each attempt.
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(20));
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn retry_waits_out_retry_after() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let mut retry_policy = client::RetryPolicy::default();
+        retry_policy.initial_backoff = Duration::from_millis(1);
+        retry_policy.jitter = 0.;
+        let errors = vec![ServerError::new(Code::ResourceExhausted, "Slow down.")
+            .with_retry_after(Duration::from_millis(200))
+            .into()];
+        let (response, attempts) = call_with_retry(retry_policy, Duration::from_secs(10), errors);
+
+        assert_eq!(response.unwrap(), "hi");
+        assert_eq!(attempts.len(), 2);
+        // The server's hint is longer than the backoff, so the client waits it out.
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let mut retry_policy = client::RetryPolicy::default();
+        retry_policy.initial_backoff = Duration::from_millis(1);
+        let errors = (0..3).map(|_| io::Error::from(io::ErrorKind::WouldBlock)).collect();
+        let (response, attempts) = call_with_retry(retry_policy, Duration::from_secs(10), errors);
+
+        assert_eq!(response.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(attempts.len(), 3);
+    }
+
+    #[test]
+    fn retry_skips_permanent_errors() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let errors = vec![io::Error::from(io::ErrorKind::InvalidInput)];
+        let (response, attempts) =
+            call_with_retry(client::RetryPolicy::default(), Duration::from_secs(10), errors);
+
+        assert_eq!(response.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[test]
+    fn retry_stops_at_deadline() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let mut retry_policy = client::RetryPolicy::default();
+        retry_policy.initial_backoff = Duration::from_secs(1);
+        retry_policy.jitter = 0.;
+        let errors = vec![io::Error::from(io::ErrorKind::WouldBlock)];
+        let start = Instant::now();
+        let (response, attempts) =
+            call_with_retry(retry_policy, Duration::from_millis(500), errors);
+
+        // The backoff would end after the deadline, so the client gives up without waiting.
+        assert_eq!(response.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(attempts.len(), 1);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    /// Calls a server that fails the first requests it receives with `errors` and echoes the rest,
+    /// retrying according to `retry_policy`. Returns the result of the call and the times at which
+    /// the server received each attempt.
+    fn call_with_retry(
+        retry_policy: client::RetryPolicy,
+        timeout: Duration,
+        errors: Vec<io::Error>,
+    ) -> (io::Result<String>, Vec<Instant>) {
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let errors = Arc::new(Mutex::new(errors.into_iter()));
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with({
+                let attempts = attempts.clone();
+                move |_ctx, request| {
+                    attempts.lock().unwrap().push(Instant::now());
+                    future::ready(match errors.lock().unwrap().next() {
+                        Some(e) => Err(e),
+                        None => Ok(request),
+                    })
+                }
+            });
+
+        let response = async move {
+            let mut config = client::Config::default();
+            config.retry_policy = retry_policy;
+            let mut client = await!(Client::new(config, client_channel))?;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + timeout;
+            await!(client.call_with_retry(ctx, || "hi".to_string()))
+        };
+
+        let (_, response) = run_future(server.join(response));
+        let attempts = attempts.lock().unwrap().clone();
+        (response, attempts)
+    }
+
     fn run_future<F>(f: F) -> F::Output
     where
         F: Future + Send + 'static,
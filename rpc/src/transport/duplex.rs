@@ -0,0 +1,913 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Transports that let both peers of one connection make requests.
+//!
+//! A duplex transport carries [`Frame`]s, each of which belongs either to a *call*, made by the
+//! peer that established the connection, or to a *callback*, made by the peer that accepted it.
+//! [`split`] separates a duplex transport into one transport for calls and one for callbacks, so
+//! that each peer can run a client on one and a server on the other. This lets a server push to
+//! its clients without the clients having to accept connections of their own.
+//!
+//! On the accepting side, [`accept`] returns the transport for calls with a client over the
+//! transport for callbacks attached, so that it can be passed to
+//! [`Server::incoming`](crate::Server::incoming) like any other transport. Request handlers get
+//! the client for the service exposed by the peer from each request's
+//! [`Extras`](crate::server::Extras):
+//!
+//! ```ignore
+//! server
+//!     .incoming(listener.and_then(|conn| duplex::accept(duplex::Config::default(), conn)))
+//!     .respond_with_extras(|ctx, request, extras| {
+//!         let callbacks: Client<CallbackReq, CallbackResp> =
+//!             extras.callbacks.and_then(|callbacks| callbacks.client()).unwrap();
+//!         // Respond to `request`, making callbacks to the peer as needed.
+//!     })
+//! ```
+
+use crate::{
+    client::{self, Client},
+    transport::{Address, Callbacks, Message, Peer, SkippedMessage},
+    ClientMessage, ServerMessage, Transport,
+};
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    ready,
+    stream::Fuse,
+    task::{LocalWaker, Poll},
+};
+use log::{debug, warn};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Settings that control the behavior of a split duplex transport.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of messages buffered in each direction of each half. A half that falls further
+    /// behind the messages read for it fails with a
+    /// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) error, rather than holding up
+    /// reading for the other half.
+    pub buffer: usize,
+    /// Settings of the client for callbacks that [`accept`] makes.
+    pub callbacks: client::Config,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            buffer: 16,
+            callbacks: client::Config::default(),
+        }
+    }
+}
+
+/// A message on a duplex transport.
+#[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum Frame<Call, Callback> {
+    /// A message of a call, which the peer that established the connection makes.
+    Call(Call),
+    /// A message of a callback, which the peer that accepted the connection makes.
+    Callback(Callback),
+}
+
+impl<Call, Callback> Message for Frame<Call, Callback>
+where
+    Call: Message,
+    Callback: Message,
+{
+    type Header = Frame<Call::Header, Callback::Header>;
+}
+
+/// Splits a duplex transport into a transport for calls and a transport for callbacks.
+///
+/// A task is spawned to move messages between `transport` and the two halves. It closes
+/// `transport` once both halves are dropped. The halves fail to send a message that `transport`
+/// [can tell](Transport::check) it can't send, e.g. because it's too large, so that the request
+/// it belongs to fails right away; other messages that `transport` fails to send are dropped.
+pub fn split<T, Call, Callback, SinkCall, SinkCallback>(
+    config: Config,
+    transport: T,
+) -> io::Result<(Half<Call, SinkCall>, Half<Callback, SinkCallback>)>
+where
+    T: Transport<Item = Frame<Call, Callback>, SinkItem = Frame<SinkCall, SinkCallback>>
+        + Send
+        + 'static,
+    Call: Message + Send + 'static,
+    Callback: Message + Send + 'static,
+    SinkCall: Send + 'static,
+    SinkCallback: Send + 'static,
+{
+    let peer = transport.peer().map_err(|e| e.kind());
+    let local_addr = transport.local_addr().map_err(|e| e.kind());
+    let (calls_in, calls_rx) = mpsc::channel(config.buffer);
+    let (callbacks_in, callbacks_rx) = mpsc::channel(config.buffer);
+    let (calls_tx, calls_out) = mpsc::channel(config.buffer);
+    let (callbacks_tx, callbacks_out) = mpsc::channel(config.buffer);
+    let transport = Arc::new(Mutex::new(Box::pin(transport)));
+    let check_call = {
+        let transport = transport.clone();
+        move |call| {
+            let frame = Frame::Call(call);
+            transport.lock().unwrap().check(&frame)?;
+            match frame {
+                Frame::Call(call) => Ok(call),
+                Frame::Callback(_) => unreachable!(),
+            }
+        }
+    };
+    let check_callback = {
+        let transport = transport.clone();
+        move |callback| {
+            let frame = Frame::Callback(callback);
+            transport.lock().unwrap().check(&frame)?;
+            match frame {
+                Frame::Callback(callback) => Ok(callback),
+                Frame::Call(_) => unreachable!(),
+            }
+        }
+    };
+
+    let name = match &peer {
+        Ok(peer) => peer.to_string(),
+        Err(_) => "unknown peer".to_string(),
+    };
+    crate::spawn(
+        Mux {
+            transport,
+            calls_in,
+            callbacks_in,
+            calls_out: calls_out.fuse(),
+            callbacks_out: callbacks_out.fuse(),
+            pending_calls: VecDeque::new(),
+            pending_callbacks: VecDeque::new(),
+            calls_lagged: false,
+            callbacks_lagged: false,
+            buffer: config.buffer,
+            read_closed: false,
+        }
+        .unwrap_or_else(move |e| warn!("[{}] Duplex transport broke: {}", name, e)),
+    )
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Could not spawn duplex transport task: {:?}", e),
+        )
+    })?;
+
+    Ok((
+        Half {
+            rx: calls_rx,
+            tx: calls_tx,
+            check: Arc::new(check_call),
+            peer: peer.clone(),
+            local_addr: local_addr.clone(),
+        },
+        Half {
+            rx: callbacks_rx,
+            tx: callbacks_tx,
+            check: Arc::new(check_callback),
+            peer,
+            local_addr,
+        },
+    ))
+}
+
+/// Splits a duplex transport accepted by a server, returning the transport for calls with a
+/// client for callbacks over the other half attached. Resolves once the client is created; see
+/// [`Client::new`].
+pub async fn accept<T, Call, SinkCall, CallbackReq, CallbackResp>(
+    config: Config,
+    transport: T,
+) -> io::Result<Accepted<Call, SinkCall, CallbackReq, CallbackResp>>
+where
+    T: Transport<
+            Item = Frame<Call, ServerMessage<CallbackResp>>,
+            SinkItem = Frame<SinkCall, ClientMessage<CallbackReq>>,
+        > + Send
+        + 'static,
+    Call: Message + Send + 'static,
+    SinkCall: Send + 'static,
+    CallbackReq: Send + 'static,
+    CallbackResp: Send + 'static,
+{
+    let client_config = config.callbacks.clone();
+    let (calls, callbacks) = split(config, transport)?;
+    let callbacks = await!(Client::new(client_config, callbacks))?;
+    Ok(Accepted { calls, callbacks })
+}
+
+/// One half of a split duplex transport.
+///
+/// Reports the addresses of the transport it was split from.
+pub struct Half<Item, SinkItem> {
+    rx: mpsc::Receiver<io::Result<Item>>,
+    tx: mpsc::Sender<SinkItem>,
+    /// Returns an item that the transport the half was split from can send, or the error it
+    /// would fail to send it with.
+    check: Arc<dyn Fn(SinkItem) -> io::Result<SinkItem> + Send + Sync>,
+    peer: Result<Peer, io::ErrorKind>,
+    local_addr: Result<Address, io::ErrorKind>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Half<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Half")
+    }
+}
+
+impl<Item, SinkItem> Half<Item, SinkItem> {
+    unsafe_pinned!(rx: mpsc::Receiver<io::Result<Item>>);
+    unsafe_pinned!(tx: mpsc::Sender<SinkItem>);
+}
+
+impl<Item, SinkItem> Stream for Half<Item, SinkItem> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<io::Result<Item>>> {
+        self.rx().poll_next(cx)
+    }
+}
+
+impl<Item, SinkItem> Sink for Half<Item, SinkItem> {
+    type SinkItem = SinkItem;
+    type SinkError = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.tx()
+            .poll_ready(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    /// Fails, without sending anything, if the transport the half was split from can tell it
+    /// would fail to send `item`; see [`Transport::check`].
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let item = (self.check)(item)?;
+        self.tx()
+            .start_send(item)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.tx()
+            .poll_flush(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.tx()
+            .poll_close(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
+impl<Item, SinkItem> Transport for Half<Item, SinkItem> {
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<Address> {
+        self.peer().map(|peer| peer.addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        self.local_addr.clone().map_err(io::Error::from)
+    }
+
+    fn peer(&self) -> io::Result<Peer> {
+        self.peer.clone().map_err(io::Error::from)
+    }
+}
+
+/// The transport for calls of a duplex transport accepted by a server, with a client for
+/// callbacks to the service that the peer serves on the other half.
+///
+/// A server serving the transport passes the client to its request handlers in each request's
+/// [`Extras`](crate::server::Extras).
+#[derive(Debug)]
+pub struct Accepted<Item, SinkItem, CallbackReq, CallbackResp> {
+    calls: Half<Item, SinkItem>,
+    callbacks: Client<CallbackReq, CallbackResp>,
+}
+
+impl<Item, SinkItem, CallbackReq, CallbackResp>
+    Accepted<Item, SinkItem, CallbackReq, CallbackResp>
+{
+    unsafe_pinned!(calls: Half<Item, SinkItem>);
+}
+
+impl<Item, SinkItem, CallbackReq, CallbackResp> Stream
+    for Accepted<Item, SinkItem, CallbackReq, CallbackResp>
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<io::Result<Item>>> {
+        self.calls().poll_next(cx)
+    }
+}
+
+impl<Item, SinkItem, CallbackReq, CallbackResp> Sink
+    for Accepted<Item, SinkItem, CallbackReq, CallbackResp>
+{
+    type SinkItem = SinkItem;
+    type SinkError = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.calls().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.calls().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.calls().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        self.calls().poll_close(cx)
+    }
+}
+
+impl<Item, SinkItem, CallbackReq, CallbackResp> Transport
+    for Accepted<Item, SinkItem, CallbackReq, CallbackResp>
+where
+    CallbackReq: Send + 'static,
+    CallbackResp: Send + 'static,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<Address> {
+        self.calls.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        self.calls.local_addr()
+    }
+
+    fn peer(&self) -> io::Result<Peer> {
+        self.calls.peer()
+    }
+
+    fn callbacks(&self) -> Option<Callbacks> {
+        Some(Callbacks::new(self.callbacks.clone()))
+    }
+}
+
+/// Moves messages between a duplex transport and the halves it was split into.
+struct Mux<T, Call, Callback, SinkCall, SinkCallback> {
+    /// Shared with the halves, which check the messages sent to them against it.
+    transport: Arc<Mutex<Pin<Box<T>>>>,
+    calls_in: mpsc::Sender<io::Result<Call>>,
+    callbacks_in: mpsc::Sender<io::Result<Callback>>,
+    calls_out: Fuse<mpsc::Receiver<SinkCall>>,
+    callbacks_out: Fuse<mpsc::Receiver<SinkCallback>>,
+    /// Calls, or errors reading them, read off the transport that their half isn't ready to
+    /// receive.
+    pending_calls: VecDeque<io::Result<Call>>,
+    /// Callbacks, or errors reading them, read off the transport that their half isn't ready to
+    /// receive.
+    pending_callbacks: VecDeque<io::Result<Callback>>,
+    /// The most messages that can be pending for each half before it fails.
+    buffer: usize,
+    /// Whether the calls half fell behind and was failed, so that later calls are dropped.
+    calls_lagged: bool,
+    /// Whether the callbacks half fell behind and was failed, so that later callbacks are
+    /// dropped.
+    callbacks_lagged: bool,
+    read_closed: bool,
+}
+
+impl<T, Call, Callback, SinkCall, SinkCallback> Mux<T, Call, Callback, SinkCall, SinkCallback>
+where
+    T: Transport<Item = Frame<Call, Callback>, SinkItem = Frame<SinkCall, SinkCallback>>,
+    Call: Message,
+    Callback: Message,
+{
+    unsafe_unpinned!(calls_in: mpsc::Sender<io::Result<Call>>);
+    unsafe_unpinned!(callbacks_in: mpsc::Sender<io::Result<Callback>>);
+    unsafe_unpinned!(calls_out: Fuse<mpsc::Receiver<SinkCall>>);
+    unsafe_unpinned!(callbacks_out: Fuse<mpsc::Receiver<SinkCallback>>);
+    unsafe_unpinned!(pending_calls: VecDeque<io::Result<Call>>);
+    unsafe_unpinned!(pending_callbacks: VecDeque<io::Result<Callback>>);
+    unsafe_unpinned!(read_closed: bool);
+
+    fn transport(&self) -> MutexGuard<Pin<Box<T>>> {
+        self.transport.lock().unwrap()
+    }
+
+    /// Reads a frame off the transport and queues it for its half, after handing the halves
+    /// the frames they're ready for. Returns `Ready(None)` once the transport is closed for
+    /// reading and the halves have all the frames read for them.
+    ///
+    /// Reading never waits on a half, so that a half whose reader stalls can't hold up the other
+    /// half. Instead, a half that falls `buffer` frames behind is failed.
+    fn pump_read(self: &mut Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<io::Result<()>>> {
+        {
+            let mux = unsafe { Pin::get_mut_unchecked(self.as_mut()) };
+            deliver(&mut mux.calls_in, &mut mux.pending_calls, mux.calls_lagged, cx, "call");
+            deliver(
+                &mut mux.callbacks_in,
+                &mut mux.pending_callbacks,
+                mux.callbacks_lagged,
+                cx,
+                "callback",
+            );
+        }
+        if *self.read_closed() {
+            if !self.pending_calls.is_empty() || !self.pending_callbacks.is_empty() {
+                return Poll::Pending;
+            }
+            self.calls_in().close_channel();
+            self.callbacks_in().close_channel();
+            return Poll::Ready(None);
+        }
+        let next = ready!(self.transport().as_mut().poll_next(cx));
+        let mux = unsafe { Pin::get_mut_unchecked(self.as_mut()) };
+        let buffer = mux.buffer;
+        match next {
+            Some(Ok(Frame::Call(call))) => {
+                queue(&mut mux.pending_calls, &mut mux.calls_lagged, buffer, Ok(call), "call")
+            }
+            Some(Ok(Frame::Callback(callback))) => queue(
+                &mut mux.pending_callbacks,
+                &mut mux.callbacks_lagged,
+                buffer,
+                Ok(callback),
+                "callback",
+            ),
+            Some(Err(e)) => match route_skipped::<Call, Callback>(e) {
+                Ok(Frame::Call(call)) => {
+                    queue(&mut mux.pending_calls, &mut mux.calls_lagged, buffer, call, "call")
+                }
+                Ok(Frame::Callback(callback)) => queue(
+                    &mut mux.pending_callbacks,
+                    &mut mux.callbacks_lagged,
+                    buffer,
+                    callback,
+                    "callback",
+                ),
+                Err(e) => {
+                    // The halves learn of the error through their own reads. The error
+                    // can't be cloned, so each gets a copy, unless it's already full.
+                    let copy = io::Error::new(e.kind(), e.to_string());
+                    let _ = self.callbacks_in().try_send(Err(copy));
+                    let copy = io::Error::new(e.kind(), e.to_string());
+                    let _ = self.calls_in().try_send(Err(copy));
+                    self.close_read();
+                    return Poll::Ready(Some(Err(e)));
+                }
+            },
+            None => *self.read_closed() = true,
+        }
+        Poll::Ready(Some(Ok(())))
+    }
+
+    fn close_read(self: &mut Pin<&mut Self>) {
+        self.pending_calls().clear();
+        self.pending_callbacks().clear();
+        self.calls_in().close_channel();
+        self.callbacks_in().close_channel();
+        *self.read_closed() = true;
+    }
+
+    /// Writes a message from each half that has one to the transport. Returns `Ready(None)` once
+    /// both halves are dropped and the transport is closed.
+    fn pump_write(self: &mut Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<io::Result<()>>> {
+        let mut wrote = false;
+        if !self.calls_out().is_done() {
+            match self.poll_next_out(cx, |mux, cx| {
+                mux.calls_out().poll_next_unpin(cx).map(|call| call.map(Frame::Call))
+            })? {
+                Poll::Ready(Some(())) => wrote = true,
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+        }
+        if !self.callbacks_out().is_done() {
+            match self.poll_next_out(cx, |mux, cx| {
+                mux.callbacks_out()
+                    .poll_next_unpin(cx)
+                    .map(|callback| callback.map(Frame::Callback))
+            })? {
+                Poll::Ready(Some(())) => wrote = true,
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+        }
+        if wrote {
+            return Poll::Ready(Some(Ok(())));
+        }
+        if !self.calls_out().is_done() || !self.callbacks_out().is_done() {
+            ready!(self.transport().as_mut().poll_flush(cx)?);
+            return Poll::Pending;
+        }
+        ready!(self.transport().as_mut().poll_close(cx)?);
+        Poll::Ready(None)
+    }
+
+    /// Writes the next message yielded by `next` to the transport, once the transport is ready
+    /// for it. A message the transport can't send is dropped; the halves fail most such messages
+    /// before queuing them, by [checking](Transport::check) them.
+    fn poll_next_out(
+        self: &mut Pin<&mut Self>,
+        cx: &LocalWaker,
+        next: impl FnOnce(
+            &mut Pin<&mut Self>,
+            &LocalWaker,
+        ) -> Poll<Option<Frame<SinkCall, SinkCallback>>>,
+    ) -> Poll<Option<io::Result<()>>> {
+        // Each call locks the transport only for its own statement, as the halves lock it too.
+        loop {
+            let ready = self.transport().as_mut().poll_ready(cx)?;
+            if ready.is_ready() {
+                break;
+            }
+            ready!(self.transport().as_mut().poll_flush(cx)?);
+        }
+        let frame = match ready!(next(self, cx)) {
+            Some(frame) => frame,
+            None => return Poll::Ready(None),
+        };
+        let sent = self.transport().as_mut().start_send(frame);
+        match sent {
+            Ok(()) => Poll::Ready(Some(Ok(()))),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                warn!("Dropping a message the transport couldn't send: {}", e);
+                Poll::Ready(Some(Ok(())))
+            }
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+/// Queues a message read for a half, unless the half already has `buffer` messages pending, in
+/// which case the half is failed, and `lagged` is set so that later messages are dropped.
+fn queue<M>(
+    pending: &mut VecDeque<io::Result<M>>,
+    lagged: &mut bool,
+    buffer: usize,
+    message: io::Result<M>,
+    kind: &str,
+) {
+    if *lagged {
+        return;
+    }
+    if pending.len() < buffer {
+        pending.push_back(message);
+        return;
+    }
+    warn!("The {}s half of a duplex transport fell {} messages behind.", kind, buffer);
+    *lagged = true;
+    pending.push_back(Err(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("Fell more than {} {}s behind the duplex transport.", buffer, kind),
+    )));
+}
+
+/// Hands `pending` messages to a half until it isn't ready for more. If the half was dropped, the
+/// messages are dropped too. A half that `lagged` is closed once it has all of its messages.
+fn deliver<M>(
+    half: &mut mpsc::Sender<io::Result<M>>,
+    pending: &mut VecDeque<io::Result<M>>,
+    lagged: bool,
+    cx: &LocalWaker,
+    kind: &str,
+) {
+    while !pending.is_empty() {
+        match half.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {
+                let _ = half.start_send(pending.pop_front().unwrap());
+            }
+            // The half was dropped, so nobody is listening.
+            Poll::Ready(Err(_)) => {
+                debug!("Dropping {} {}s read after their half closed.", pending.len(), kind);
+                pending.clear();
+            }
+            Poll::Pending => break,
+        }
+    }
+    if lagged && pending.is_empty() {
+        half.close_channel();
+    }
+}
+
+/// Returns the skipped message that `e` reports as an error for the half it belongs to, so that
+/// the half can fail just the message's request. Returns `e` itself if it doesn't report a skipped
+/// message, or the skipped message's header, and so its half, is unknown.
+fn route_skipped<Call, Callback>(
+    e: io::Error,
+) -> Result<Frame<io::Result<Call>, io::Result<Callback>>, io::Error>
+where
+    Call: Message,
+    Callback: Message,
+{
+    type Skipped<Call, Callback> =
+        SkippedMessage<Frame<<Call as Message>::Header, <Callback as Message>::Header>>;
+
+    match Skipped::<Call, Callback>::from_io_error(&e) {
+        Some(skipped) if skipped.header.is_some() => {}
+        _ => return Err(e),
+    }
+    let skipped = e
+        .into_inner()
+        .and_then(|e| e.downcast::<Skipped<Call, Callback>>().ok())
+        .unwrap();
+    Ok(match skipped.header.unwrap() {
+        Frame::Call(header) => {
+            Frame::Call(Err(SkippedMessage::new(Some(header), skipped.reason).into()))
+        }
+        Frame::Callback(header) => {
+            Frame::Callback(Err(SkippedMessage::new(Some(header), skipped.reason).into()))
+        }
+    })
+}
+
+impl<T, Call, Callback, SinkCall, SinkCallback> Future
+    for Mux<T, Call, Callback, SinkCall, SinkCallback>
+where
+    T: Transport<Item = Frame<Call, Callback>, SinkItem = Frame<SinkCall, SinkCallback>>,
+    Call: Message,
+    Callback: Message,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+        loop {
+            match (self.pump_read(cx)?, self.pump_write(cx)?) {
+                // Both halves are gone, so nothing else will be read or written.
+                (_, Poll::Ready(None)) => return Poll::Ready(Ok(())),
+                (Poll::Ready(Some(())), _) | (_, Poll::Ready(Some(()))) => continue,
+                (Poll::Ready(None), Poll::Pending) | (Poll::Pending, Poll::Pending) => {
+                    return Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, split, Config, Frame, Half};
+    use crate::{
+        client::{self, Client},
+        context,
+        server::{self, Handler, Server},
+        transport, ClientMessage, ClientMessageKind, ServerMessage, Transport,
+    };
+    use futures::{
+        compat::TokioDefaultSpawner,
+        prelude::*,
+        stream,
+        task::{LocalWaker, Poll},
+    };
+    use pin_utils::unsafe_pinned;
+    use std::{
+        io,
+        pin::Pin,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    /// A transport that fails to send the items that `check` fails, like a transport with a max
+    /// frame length.
+    struct Checked<T, SinkItem> {
+        transport: T,
+        check: fn(&SinkItem) -> io::Result<()>,
+    }
+
+    impl<T, SinkItem> Checked<T, SinkItem> {
+        unsafe_pinned!(transport: T);
+    }
+
+    impl<T: Transport> Stream for Checked<T, <T as Transport>::SinkItem> {
+        type Item = io::Result<<T as Transport>::Item>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<Option<Self::Item>> {
+            self.transport().poll_next(cx)
+        }
+    }
+
+    impl<T: Transport> Sink for Checked<T, <T as Transport>::SinkItem> {
+        type SinkItem = <T as Transport>::SinkItem;
+        type SinkError = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.transport().poll_ready(cx)
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: <T as Transport>::SinkItem,
+        ) -> io::Result<()> {
+            (self.check)(&item)?;
+            self.transport().start_send(item)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.transport().poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &LocalWaker) -> Poll<io::Result<()>> {
+            self.transport().poll_close(cx)
+        }
+    }
+
+    impl<T: Transport> Transport for Checked<T, <T as Transport>::SinkItem> {
+        type Item = <T as Transport>::Item;
+        type SinkItem = <T as Transport>::SinkItem;
+
+        fn peer_addr(&self) -> io::Result<transport::Address> {
+            self.transport.peer_addr()
+        }
+
+        fn local_addr(&self) -> io::Result<transport::Address> {
+            self.transport.local_addr()
+        }
+
+        fn check(&self, item: &<T as Transport>::SinkItem) -> io::Result<()> {
+            (self.check)(item)
+        }
+    }
+
+    /// Fails requests of more than 100 bytes.
+    fn check_length(
+        frame: &Frame<ClientMessage<String>, ServerMessage<String>>,
+    ) -> io::Result<()> {
+        match frame {
+            Frame::Call(ClientMessage {
+                message: ClientMessageKind::Request(request),
+                ..
+            }) if request.message.len() > 100 => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Request exceeds 100 bytes",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    #[test]
+    fn callbacks() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (client_conn, server_conn) = transport::channel::unbounded();
+
+        // Greets each client by a name it asks the client for.
+        let server = Server::<String, String>::new(server::Config::default())
+            .incoming(stream::once(accept::<_, _, _, (), String>(
+                Config::default(),
+                server_conn,
+            )))
+            .respond_with_extras(|_ctx, greeting, extras| {
+                let names = extras.callbacks.and_then(|callbacks| callbacks.client());
+                async move {
+                    let mut names: Client<(), String> = names.unwrap();
+                    let name = await!(names.call(context::current(), ()))?;
+                    Ok::<_, io::Error>(format!("{}, {}!", greeting, name))
+                }
+            });
+        crate::spawn(server).unwrap();
+
+        let responses = async move {
+            let conn = Checked {
+                transport: client_conn,
+                check: check_length,
+            };
+            let (calls, callbacks) = split(Config::default(), conn)?;
+            let names = Server::<(), String>::new(server::Config::default())
+                .incoming(stream::once(future::ready(Ok(callbacks))))
+                .respond_with(|_ctx, ()| future::ready(Ok("Alice".to_string())));
+            let shutdown = names.shutdown_handle();
+            crate::spawn(names).unwrap();
+
+            let mut client = await!(Client::new(client::Config::default(), calls))?;
+            let response = await!(client.call(context::current(), "Hello".to_string()));
+
+            // A request the transport can't send fails right away, rather than by its deadline.
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_secs(60);
+            let start = Instant::now();
+            let oversize = await!(client.call(ctx, "x".repeat(1000)));
+            let elapsed = start.elapsed();
+
+            // The connection stays open while either peer can still make requests over it.
+            drop(client);
+            await!(shutdown.shutdown(Duration::from_millis(100)));
+            Ok::<_, io::Error>((response, oversize, elapsed))
+        };
+
+        let (response, oversize, elapsed) = run_future(responses).unwrap();
+        assert_eq!(response.unwrap(), "Hello, Alice!");
+        assert_eq!(oversize.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn halves_buffered_separately() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (mut peer, conn) = transport::channel::unbounded();
+        let call = async move {
+            let mut config = Config::default();
+            config.buffer = 2;
+            let (calls, callbacks): (
+                Half<ClientMessage<String>, ServerMessage<String>>,
+                Half<ServerMessage<String>, ClientMessage<String>>,
+            ) = split(config, conn)?;
+
+            // More callbacks than the callbacks half buffers, followed by a call.
+            for request_id in 0..4 {
+                await!(peer.send(Frame::Callback(ServerMessage::StreamEnd { request_id })))?;
+            }
+            await!(peer.send(Frame::Call(ClientMessage {
+                trace_context: trace::Context::new_root(),
+                message: ClientMessageKind::Cancel { request_id: 7 },
+            })))?;
+
+            // The callbacks half isn't read, but the call gets through.
+            let (call, _calls) = await!(calls.into_future());
+            drop(callbacks);
+            call.unwrap()
+        };
+
+        match run_future(call).unwrap().message {
+            ClientMessageKind::Cancel { request_id } => assert_eq!(request_id, 7),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn lagging_half_fails_alone() {
+        let _ = env_logger::try_init();
+        crate::init(TokioDefaultSpawner);
+
+        let (mut peer, conn) = transport::channel::unbounded();
+        let received = async move {
+            let mut config = Config::default();
+            config.buffer = 2;
+            let (calls, callbacks): (
+                Half<ClientMessage<String>, ServerMessage<String>>,
+                Half<ServerMessage<String>, ClientMessage<String>>,
+            ) = split(config, conn)?;
+
+            // Far more callbacks than the callbacks half buffers, between calls.
+            for request_id in 0..10 {
+                await!(peer.send(Frame::Callback(ServerMessage::StreamEnd { request_id })))?;
+                await!(peer.send(Frame::Call(ClientMessage {
+                    trace_context: trace::Context::new_root(),
+                    message: ClientMessageKind::Cancel { request_id },
+                })))?;
+            }
+
+            // The callbacks half isn't read, but every call gets through.
+            let calls = await!(calls.take(10).collect::<Vec<_>>());
+            let callbacks = await!(callbacks.collect::<Vec<_>>());
+            Ok::<_, io::Error>((calls, callbacks))
+        };
+
+        let (calls, callbacks) = run_future(received).unwrap();
+        let calls: Vec<_> = calls
+            .into_iter()
+            .map(|call| match call.unwrap().message {
+                ClientMessageKind::Cancel { request_id } => request_id,
+                message => panic!("Unexpected message: {:?}", message),
+            })
+            .collect();
+        assert_eq!(calls, (0..10).collect::<Vec<_>>());
+
+        // The callbacks half gets what it had room for, then fails and ends.
+        assert_eq!(callbacks.len(), 6);
+        assert!(callbacks[..5].iter().all(|callback| callback.is_ok()));
+        assert_eq!(
+            callbacks[5].as_ref().unwrap_err().kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+    }
+
+    fn run_future<F>(f: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        tokio::run(
+            f.map(|result| tx.send(result).unwrap_or_else(|_| unreachable!()))
+                .boxed()
+                .unit_error()
+                .compat(),
+        );
+        futures::executor::block_on(rx).unwrap()
+    }
+}
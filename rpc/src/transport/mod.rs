@@ -9,12 +9,140 @@
 //! The rpc crate is transport- and protocol-agnostic. Any transport that impls [`Transport`]
 //! can be plugged in, using whatever protocol it wants.
 
+use crate::Client;
 use futures::prelude::*;
-use std::{io, net::SocketAddr};
+use std::{
+    any::Any,
+    error::Error,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 pub mod channel;
+pub mod duplex;
+
+/// The address of one end of a [`Transport`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Address {
+    /// An IP address and port.
+    Inet(SocketAddr),
+    /// The path of a Unix domain socket, or `None` if the socket is unnamed, as client sockets
+    /// typically are.
+    Unix(Option<PathBuf>),
+    /// The other end of an in-process transport, such as an
+    /// [`UnboundedChannel`](channel::UnboundedChannel).
+    InProcess,
+}
+
+impl Address {
+    /// Returns the IP address, if this is an IP address.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::Inet(addr) => Some(addr.ip()),
+            Address::Unix(_) | Address::InProcess => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Inet(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Inet(addr) => write!(f, "{}", addr),
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Address::Unix(None) => write!(f, "unix:(unnamed)"),
+            Address::InProcess => write!(f, "in-process"),
+        }
+    }
+}
+
+/// An identity of a peer, established by the transport rather than claimed by the peer.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Identity {
+    /// The credentials of the process on the other end of a Unix domain socket.
+    Unix {
+        /// The peer's effective user ID.
+        uid: u32,
+        /// The peer's effective group ID.
+        gid: u32,
+    },
+    /// The DER-encoded end-entity certificate that a peer presented, and that was verified, during
+    /// a TLS handshake.
+    Certificate(Vec<u8>),
+    /// A name assigned by the transport, e.g. to an in-process peer or to a principal
+    /// authenticated by a custom protocol.
+    Name(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Identity::Unix { uid, gid } => write!(f, "uid={},gid={}", uid, gid),
+            Identity::Certificate(der) => {
+                // The tail of a certificate includes its signature, which is effectively unique.
+                write!(f, "cert=")?;
+                for byte in &der[der.len().saturating_sub(8)..] {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            Identity::Name(name) => write!(f, "name={}", name),
+        }
+    }
+}
+
+/// The peer on the other end of a [`Transport`]: its address, plus its identity if the transport
+/// authenticates peers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Peer {
+    /// The address of the peer.
+    pub addr: Address,
+    /// The identity of the peer, if known.
+    pub identity: Option<Identity>,
+}
+
+impl Peer {
+    /// Returns a peer at `addr` with the given identity.
+    pub fn new(addr: Address, identity: Option<Identity>) -> Self {
+        Peer { addr, identity }
+    }
+}
+
+impl From<Address> for Peer {
+    fn from(addr: Address) -> Self {
+        Peer::new(addr, None)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "{} ({})", self.addr, identity),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
 
 /// A bidirectional stream ([`Sink`] + [`Stream`]) of messages.
+///
+/// A transport that can't send a particular message, e.g. because it's too large, should fail
+/// [`start_send`](Sink::start_send) with [`InvalidInput`](io::ErrorKind::InvalidInput) without
+/// affecting the rest of the transport. Clients and servers report such errors to the caller of
+/// the request and keep using the transport; any other error breaks the connection.
+///
+/// Likewise, a transport that receives a message it can't read should, if it can carry on reading
+/// the messages after it, yield a [`SkippedMessage`] error. Clients and servers then fail only the
+/// request the message belongs to.
 pub trait Transport
 where
     Self: Stream<Item = io::Result<<Self as Transport>::Item>>,
@@ -26,7 +154,115 @@ where
     type SinkItem;
 
     /// The address of the remote peer this transport is in communication with.
-    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<Address>;
     /// The address of the local half of this transport.
-    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<Address>;
+
+    /// The remote peer this transport is in communication with. Transports that authenticate
+    /// their peers should override this to include the peer's identity.
+    fn peer(&self) -> io::Result<Peer> {
+        self.peer_addr().map(Peer::from)
+    }
+
+    /// Fails with the error that [`start_send`](Sink::start_send) would fail `item` with, if the
+    /// transport can tell without sending it. Transports that queue items to send over another
+    /// transport, like the halves of a [`duplex`] transport, use this to fail items up front. By
+    /// default, no item fails.
+    fn check(&self, _item: &<Self as Transport>::SinkItem) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A client for requests to the peer over this transport's connection, if the peer serves
+    /// requests over it too, as on an [accepted](duplex::accept) duplex transport. Servers pass
+    /// it to request handlers in each request's [`Extras`](crate::server::Extras).
+    fn callbacks(&self) -> Option<Callbacks> {
+        None
+    }
+}
+
+/// A client for requests to the peer on the other end of a [`Transport`]; see
+/// [`Transport::callbacks`].
+#[derive(Clone)]
+pub struct Callbacks(Arc<dyn Any + Send + Sync>);
+
+impl Callbacks {
+    /// Wraps a client for requests to the peer.
+    pub fn new<Req, Resp>(client: Client<Req, Resp>) -> Self
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        Callbacks(Arc::new(Mutex::new(client)))
+    }
+
+    /// Returns the client, if it sends requests of type `Req` and receives responses of type
+    /// `Resp`.
+    pub fn client<Req, Resp>(&self) -> Option<Client<Req, Resp>>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let client = self.0.downcast_ref::<Mutex<Client<Req, Resp>>>()?;
+        Some(client.lock().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Callbacks")
+    }
+}
+
+/// A message that starts with a header identifying it, e.g. by the ID of the request it belongs
+/// to. A transport that skips a message can often still read its header; see [`SkippedMessage`].
+pub trait Message {
+    /// The start of the message, such as a [`ClientMessageHeader`](crate::ClientMessageHeader).
+    type Header: fmt::Debug + Send + Sync + 'static;
+}
+
+/// The error that a transport yields, wrapped in an [`InvalidData`](io::ErrorKind::InvalidData)
+/// [`io::Error`], when it skips a message it received but couldn't read, e.g. because it exceeded
+/// a size limit, and can carry on reading the messages after it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SkippedMessage<H> {
+    /// The header of the skipped message, if the transport could read it.
+    pub header: Option<H>,
+    /// Why the message was skipped.
+    pub reason: String,
+}
+
+impl<H> SkippedMessage<H>
+where
+    H: fmt::Debug + Send + Sync + 'static,
+{
+    /// Returns the error for a message with the given header, skipped for `reason`.
+    pub fn new(header: Option<H>, reason: impl Into<String>) -> Self {
+        SkippedMessage {
+            header,
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns the skipped message that `e` reports, if it reports one.
+    pub fn from_io_error(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl<H> fmt::Display for SkippedMessage<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Skipped message: {}", self.reason)
+    }
+}
+
+impl<H: fmt::Debug> Error for SkippedMessage<H> {}
+
+impl<H> From<SkippedMessage<H>> for io::Error
+where
+    H: fmt::Debug + Send + Sync + 'static,
+{
+    fn from(skipped: SkippedMessage<H>) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, skipped)
+    }
 }
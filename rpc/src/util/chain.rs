@@ -0,0 +1,88 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Chains of layers that each wrap the remainder of the chain, such as server middleware and
+//! client interceptors.
+
+use std::{fmt, sync::Arc};
+
+/// Layers that each wrap the remainder of the chain, outermost first. Clones share their layers
+/// until one is pushed to.
+pub(crate) struct Chain<L: ?Sized>(Arc<Vec<Arc<L>>>);
+
+impl<L: ?Sized> Chain<L> {
+    /// Adds `layer` as the innermost layer.
+    pub(crate) fn push(&mut self, layer: Arc<L>) {
+        Arc::make_mut(&mut self.0).push(layer);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the chain's layers, outermost first.
+    pub(crate) fn get(&self) -> Arc<Vec<Arc<L>>> {
+        self.0.clone()
+    }
+}
+
+impl<L: ?Sized> Clone for Chain<L> {
+    fn clone(&self) -> Self {
+        Chain(self.0.clone())
+    }
+}
+
+impl<L: ?Sized> Default for Chain<L> {
+    fn default() -> Self {
+        Chain(Arc::new(vec![]))
+    }
+}
+
+impl<L: ?Sized> fmt::Debug for Chain<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chain({} layers)", self.0.len())
+    }
+}
+
+/// The layers of a chain that have yet to run, followed by `terminal`, which runs after the
+/// innermost layer.
+pub(crate) struct Remainder<'a, L: ?Sized, T> {
+    layers: &'a [Arc<L>],
+    terminal: T,
+}
+
+/// What runs next in a chain.
+pub(crate) enum Step<'a, L: ?Sized, T> {
+    /// The next layer, which wraps the remainder of the chain.
+    Layer(&'a L, Remainder<'a, L, T>),
+    /// No layers remain.
+    Terminal(T),
+}
+
+impl<'a, L: ?Sized, T> Remainder<'a, L, T> {
+    pub(crate) fn new(layers: &'a [Arc<L>], terminal: T) -> Self {
+        Remainder { layers, terminal }
+    }
+
+    /// Returns the number of layers that have yet to run.
+    pub(crate) fn remaining(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Advances the chain past its next layer.
+    pub(crate) fn step(self) -> Step<'a, L, T> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => Step::Layer(
+                layer,
+                Remainder {
+                    layers,
+                    terminal: self.terminal,
+                },
+            ),
+            None => Step::Terminal(self.terminal),
+        }
+    }
+}
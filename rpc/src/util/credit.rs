@@ -0,0 +1,75 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{
+    channel::mpsc,
+    future,
+    prelude::*,
+    ready,
+    task::{LocalWaker, Poll},
+};
+
+/// Returns a sender of credit grants, and the credit they add to, which starts at `initial`.
+pub fn channel(initial: u64) -> (mpsc::UnboundedSender<u64>, Credit) {
+    let (grants_tx, grants) = mpsc::unbounded();
+    let credit = Credit {
+        available: initial,
+        grants,
+    };
+    (grants_tx, credit)
+}
+
+/// The number of messages belonging to one request that a peer is prepared to buffer: a sender
+/// uses up one unit of credit per message, and waits for the peer to grant more when it runs out.
+#[derive(Debug)]
+pub struct Credit {
+    available: u64,
+    grants: mpsc::UnboundedReceiver<u64>,
+}
+
+impl Credit {
+    /// Uses up one unit of credit, waiting for more to be granted if there's none. Resolves to
+    /// false, without using up credit, if none is left and the grants sender was dropped.
+    pub fn poll_acquire(&mut self, waker: &LocalWaker) -> Poll<bool> {
+        while self.available == 0 {
+            match ready!(self.grants.poll_next_unpin(waker)) {
+                Some(credit) => self.available = self.available.saturating_add(credit),
+                None => return Poll::Ready(false),
+            }
+        }
+        self.available -= 1;
+        Poll::Ready(true)
+    }
+
+    /// Like [`poll_acquire`](Credit::poll_acquire), but as a future.
+    pub fn acquire<'a>(&'a mut self) -> impl Future<Output = bool> + 'a {
+        future::poll_fn(move |waker| self.poll_acquire(waker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel;
+    use futures::task::Poll;
+    use futures_test::task::noop_local_waker_ref;
+
+    #[test]
+    fn waits_for_grants() {
+        let waker = &noop_local_waker_ref();
+        let (grants, mut credit) = channel(1);
+
+        assert_eq!(credit.poll_acquire(waker), Poll::Ready(true));
+        assert_eq!(credit.poll_acquire(waker), Poll::Pending);
+
+        grants.unbounded_send(2).unwrap();
+        assert_eq!(credit.poll_acquire(waker), Poll::Ready(true));
+        assert_eq!(credit.poll_acquire(waker), Poll::Ready(true));
+        assert_eq!(credit.poll_acquire(waker), Poll::Pending);
+
+        drop(grants);
+        assert_eq!(credit.poll_acquire(waker), Poll::Ready(false));
+    }
+}
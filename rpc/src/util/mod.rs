@@ -10,6 +10,8 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+pub mod chain;
+pub mod credit;
 pub mod deadline_compat;
 #[cfg(feature = "serde")]
 pub mod serde;
@@ -4,30 +4,55 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use crate::Code;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     io,
     time::{Duration, SystemTime},
 };
 
-/// Serializes `system_time` as a `u64` equal to the number of seconds since the epoch.
-pub fn serialize_epoch_secs<S>(system_time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+/// Epoch timestamps below this value are interpreted as seconds rather than nanoseconds. Seconds
+/// since the epoch stay below it until the year 36812, while nanoseconds since the epoch exceed
+/// it for any time after 1970-01-01T00:18:19Z.
+const MIN_EPOCH_NANOS: u64 = 1 << 40;
+
+/// Serializes `system_time` as a `u64` equal to the number of nanoseconds since the epoch.
+///
+/// Times before the epoch are serialized as the epoch, and times after the year 2554 are
+/// serialized as `u64::MAX` nanoseconds.
+pub fn serialize_epoch_nanos<S>(system_time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    system_time
+    let since_epoch = system_time
         .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or(Duration::from_secs(0))
-        .as_secs() // Only care about second precision
-        .serialize(serializer)
+        .unwrap_or(Duration::from_secs(0));
+    let nanos = since_epoch
+        .as_secs()
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(u64::from(since_epoch.subsec_nanos())))
+        .unwrap_or(u64::max_value());
+    nanos.max(MIN_EPOCH_NANOS).serialize(serializer)
 }
 
-/// Deserializes [`SystemTime`] from a `u64` equal to the number of seconds since the epoch.
-pub fn deserialize_epoch_secs<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+/// Deserializes [`SystemTime`] from a `u64` equal to the number of nanoseconds since the epoch.
+///
+/// For compatibility with peers that serialize with second precision, values too small to be
+/// nanoseconds since the epoch are deserialized as seconds since the epoch.
+pub fn deserialize_epoch_nanos<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::deserialize(deserializer)?))
+    let epoch_time = u64::deserialize(deserializer)?;
+    let since_epoch = if epoch_time < MIN_EPOCH_NANOS {
+        Duration::from_secs(epoch_time)
+    } else {
+        Duration::new(
+            epoch_time / 1_000_000_000,
+            (epoch_time % 1_000_000_000) as u32,
+        )
+    };
+    Ok(SystemTime::UNIX_EPOCH + since_epoch)
 }
 
 /// Serializes [`io::ErrorKind`] as a `u32`.
@@ -92,3 +117,152 @@ where
         _ => Other,
     })
 }
+
+/// Serializes [`Code`] as a `u32`.
+pub fn serialize_code_as_u32<S>(code: &Code, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match *code {
+        Code::Cancelled => 1u32,
+        Code::Unknown => 2,
+        Code::InvalidArgument => 3,
+        Code::DeadlineExceeded => 4,
+        Code::NotFound => 5,
+        Code::AlreadyExists => 6,
+        Code::PermissionDenied => 7,
+        Code::ResourceExhausted => 8,
+        Code::FailedPrecondition => 9,
+        Code::Aborted => 10,
+        Code::OutOfRange => 11,
+        Code::Unimplemented => 12,
+        Code::Internal => 13,
+        Code::Unavailable => 14,
+        Code::DataLoss => 15,
+        Code::Unauthenticated => 16,
+    }.serialize(serializer)
+}
+
+/// Deserializes [`Code`] from a `u32`. Codes unknown to this version of the crate are
+/// deserialized as [`Code::Unknown`].
+pub fn deserialize_code_from_u32<'de, D>(deserializer: D) -> Result<Code, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match u32::deserialize(deserializer)? {
+        1 => Code::Cancelled,
+        3 => Code::InvalidArgument,
+        4 => Code::DeadlineExceeded,
+        5 => Code::NotFound,
+        6 => Code::AlreadyExists,
+        7 => Code::PermissionDenied,
+        8 => Code::ResourceExhausted,
+        9 => Code::FailedPrecondition,
+        10 => Code::Aborted,
+        11 => Code::OutOfRange,
+        12 => Code::Unimplemented,
+        13 => Code::Internal,
+        14 => Code::Unavailable,
+        15 => Code::DataLoss,
+        16 => Code::Unauthenticated,
+        _ => Code::Unknown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        deserialize_code_from_u32, deserialize_epoch_nanos, serialize_code_as_u32,
+        serialize_epoch_nanos, MIN_EPOCH_NANOS,
+    };
+    use crate::Code;
+    use serde::{Deserialize, Serialize};
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Deadline(
+        #[serde(
+            serialize_with = "serialize_epoch_nanos",
+            deserialize_with = "deserialize_epoch_nanos"
+        )]
+        SystemTime,
+    );
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ErrorCode(
+        #[serde(
+            serialize_with = "serialize_code_as_u32",
+            deserialize_with = "deserialize_code_from_u32"
+        )]
+        Code,
+    );
+
+    /// Returns the number of nanoseconds since the epoch that `time` is serialized as.
+    fn epoch_nanos(time: SystemTime) -> u64 {
+        let bytes = bincode::serialize(&Deadline(time)).unwrap();
+        bincode::deserialize(&bytes).unwrap()
+    }
+
+    /// Returns the time that `epoch_time` is deserialized as.
+    fn from_epoch_time(epoch_time: u64) -> SystemTime {
+        let bytes = bincode::serialize(&epoch_time).unwrap();
+        bincode::deserialize::<Deadline>(&bytes).unwrap().0
+    }
+
+    #[test]
+    fn epoch_nanos_round_trip() {
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_539_000_000, 123_456_789);
+        assert_eq!(epoch_nanos(time), 1_539_000_000_123_456_789);
+        assert_eq!(from_epoch_time(epoch_nanos(time)), time);
+    }
+
+    #[test]
+    fn epoch_seconds() {
+        // Sent by peers that serialize deadlines with second precision.
+        assert_eq!(
+            from_epoch_time(1_539_000_000),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_539_000_000)
+        );
+        assert_eq!(
+            from_epoch_time(MIN_EPOCH_NANOS - 1),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(MIN_EPOCH_NANOS - 1)
+        );
+        assert_eq!(
+            from_epoch_time(MIN_EPOCH_NANOS),
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(MIN_EPOCH_NANOS)
+        );
+    }
+
+    #[test]
+    fn epoch_nanos_clamped() {
+        // Times that would be read back as seconds are clamped to the earliest time that isn't.
+        let before_epoch = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(epoch_nanos(before_epoch), MIN_EPOCH_NANOS);
+        let soon_after_epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        assert_eq!(epoch_nanos(soon_after_epoch), MIN_EPOCH_NANOS);
+
+        // Times too far in the future to count in nanoseconds saturate.
+        let far_future = SystemTime::UNIX_EPOCH + Duration::from_secs(20_000_000_000);
+        assert_eq!(epoch_nanos(far_future), u64::max_value());
+    }
+
+    #[test]
+    fn code_round_trip() {
+        for &code in &[Code::Cancelled, Code::Unknown, Code::Internal, Code::Unauthenticated] {
+            let bytes = bincode::serialize(&ErrorCode(code)).unwrap();
+            assert_eq!(bincode::deserialize::<ErrorCode>(&bytes).unwrap(), ErrorCode(code));
+        }
+    }
+
+    #[test]
+    fn unknown_code() {
+        // Sent by peers that know of codes this version of the crate doesn't.
+        for &code in &[0u32, 17, u32::max_value()] {
+            let bytes = bincode::serialize(&code).unwrap();
+            assert_eq!(
+                bincode::deserialize::<ErrorCode>(&bytes).unwrap(),
+                ErrorCode(Code::Unknown)
+            );
+        }
+    }
+}
@@ -17,11 +17,12 @@
 use futures::{
     future::{self, Ready},
     prelude::*,
-    Future,
+    stream, Future,
 };
 use rpc::{
     client, context,
     server::{self, Handler, Server},
+    transport::duplex,
 };
 use std::{
     collections::HashMap,
@@ -39,10 +40,9 @@ pub mod subscriber {
 }
 
 pub mod publisher {
-    use std::net::SocketAddr;
     tarpc::service! {
         rpc broadcast(message: String);
-        rpc subscribe(id: u32, address: SocketAddr) -> Result<(), String>;
+        rpc subscribe(id: u32);
         rpc unsubscribe(id: u32);
     }
 }
@@ -62,33 +62,29 @@ impl subscriber::Service for Subscriber {
 }
 
 impl Subscriber {
-    async fn listen(id: u32, config: server::Config) -> io::Result<SocketAddr> {
-        let incoming = bincode_transport::listen(&"0.0.0.0:0".parse().unwrap())?;
-        let addr = incoming.local_addr();
-        tokio_executor::spawn(
-            Server::new(config)
-                .incoming(incoming)
-                .take(1)
-                .respond_with(subscriber::serve(Subscriber { id }))
-                .unit_error()
-                .boxed()
-                .compat()
-        );
-        Ok(addr)
+    /// Connects to the publisher, serving the subscriber over the same connection. Returns a
+    /// publisher stub and a handle that shuts down the subscriber.
+    async fn connect(
+        id: u32,
+        publisher_addr: SocketAddr,
+    ) -> io::Result<(publisher::Client, server::Shutdown)> {
+        let conn = await!(bincode_transport::connect(&publisher_addr))?;
+        let (calls, callbacks) = duplex::split(duplex::Config::default(), conn)?;
+        let server = Server::new(server::Config::default())
+            .incoming(stream::once(future::ready(Ok(callbacks))))
+            .respond_with_extras(subscriber::serve(Subscriber { id }));
+        let shutdown = server.shutdown_handle();
+        tokio_executor::spawn(server.unit_error().boxed().compat());
+        let publisher = await!(publisher::new_stub(client::Config::default(), calls))?;
+        Ok((publisher, shutdown))
     }
 }
 
 #[derive(Clone, Debug)]
 struct Publisher {
     clients: Arc<Mutex<HashMap<u32, subscriber::Client>>>,
-}
-
-impl Publisher {
-    fn new() -> Publisher {
-        Publisher {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
+    /// The subscriber that sent the request being handled, if it serves callbacks.
+    subscriber: Option<subscriber::Client>,
 }
 
 impl publisher::Service for Publisher {
@@ -108,22 +104,17 @@ impl publisher::Service for Publisher {
         broadcast(self.clients.clone(), message)
     }
 
-    existential type SubscribeFut: Future<Output = Result<(), String>>;
-
-    fn subscribe(&self, _: context::Context, id: u32, addr: SocketAddr) -> Self::SubscribeFut {
-        async fn subscribe(
-            clients: Arc<Mutex<HashMap<u32, subscriber::Client>>>,
-            id: u32,
-            addr: SocketAddr,
-        ) -> io::Result<()> {
-            let conn = await!(bincode_transport::connect(&addr))?;
-            let subscriber = await!(subscriber::new_stub(client::Config::default(), conn))?;
-            println!("Subscribing {}.", id);
-            clients.lock().unwrap().insert(id, subscriber);
-            Ok(())
-        }
+    type SubscribeFut = Ready<()>;
 
-        subscribe(Arc::clone(&self.clients), id, addr).map_err(|e| e.to_string())
+    fn subscribe(&self, _: context::Context, id: u32) -> Self::SubscribeFut {
+        println!("Subscribing {}.", id);
+        match &self.subscriber {
+            Some(subscriber) => {
+                self.clients.lock().unwrap().insert(id, subscriber.clone());
+            }
+            None => eprintln!("Subscriber {} doesn't serve callbacks.", id),
+        }
+        future::ready(())
     }
 
     existential type UnsubscribeFut: Future<Output = ()>;
@@ -145,37 +136,43 @@ async fn run() -> io::Result<()> {
     env_logger::init();
     let transport = bincode_transport::listen(&"0.0.0.0:0".parse().unwrap())?;
     let publisher_addr = transport.local_addr();
+    let clients = Arc::new(Mutex::new(HashMap::new()));
     tokio_executor::spawn(
         Server::new(server::Config::default())
-            .incoming(transport)
-            .take(1)
-            .respond_with(publisher::serve(Publisher::new()))
+            .incoming(transport.and_then(|conn| {
+                duplex::accept::<_, _, _, subscriber::Request__, subscriber::Response__>(
+                    duplex::Config::default(),
+                    conn,
+                )
+            }))
+            .take(2)
+            // Each subscriber serves callbacks over its connection to the publisher.
+            .respond_with_extras(publisher::serve_with_callbacks(move |callbacks| Publisher {
+                clients: clients.clone(),
+                subscriber: callbacks
+                    .and_then(|callbacks| callbacks.client())
+                    .map(subscriber::Client::from),
+            }))
             .unit_error()
             .boxed()
             .compat()
     );
 
-    let subscriber1 = await!(Subscriber::listen(0, server::Config::default()))?;
-    let subscriber2 = await!(Subscriber::listen(1, server::Config::default()))?;
-
-    let publisher_conn = bincode_transport::connect(&publisher_addr);
-    let publisher_conn = await!(publisher_conn)?;
-    let mut publisher = await!(publisher::new_stub(
-        client::Config::default(),
-        publisher_conn
-    ))?;
+    let (mut publisher0, shutdown0) = await!(Subscriber::connect(0, publisher_addr))?;
+    let (mut publisher1, shutdown1) = await!(Subscriber::connect(1, publisher_addr))?;
 
-    if let Err(e) = await!(publisher.subscribe(context::current(), 0, subscriber1))? {
-        eprintln!("Couldn't subscribe subscriber 0: {}", e);
-    }
-    if let Err(e) = await!(publisher.subscribe(context::current(), 1, subscriber2))? {
-        eprintln!("Couldn't subscribe subscriber 1: {}", e);
-    }
+    await!(publisher0.subscribe(context::current(), 0))?;
+    await!(publisher1.subscribe(context::current(), 1))?;
 
     println!("Broadcasting...");
-    await!(publisher.broadcast(context::current(), "hello to all".to_string()))?;
-    await!(publisher.unsubscribe(context::current(), 1))?;
-    await!(publisher.broadcast(context::current(), "hi again".to_string()))?;
+    await!(publisher0.broadcast(context::current(), "hello to all".to_string()))?;
+    await!(publisher1.unsubscribe(context::current(), 1))?;
+    await!(publisher0.broadcast(context::current(), "hi again".to_string()))?;
+
+    // Closes the connections, once neither side can make requests over them.
+    drop((publisher0, publisher1));
+    await!(shutdown0.shutdown(Duration::from_millis(100)));
+    await!(shutdown1.shutdown(Duration::from_millis(100)));
     Ok(())
 }
 
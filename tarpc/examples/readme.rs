@@ -60,7 +60,7 @@ async fn run() -> io::Result<()> {
         .take(1)
         // serve is generated by the tarpc::service! macro. It takes as input any type implementing
         // the generated Service trait.
-        .respond_with(serve(HelloServer));
+        .respond_with_extras(serve(HelloServer));
 
     tokio_executor::spawn(server.unit_error().boxed().compat());
 
@@ -74,7 +74,7 @@ async fn run() -> io::Result<()> {
     let add_server = Server::new(server::Config::default())
         .incoming(add_listener)
         .take(1)
-        .respond_with(add::serve(AddServer));
+        .respond_with_extras(add::serve(AddServer));
     tokio_executor::spawn(add_server.unit_error().boxed().compat());
 
     let to_add_server = await!(bincode_transport::connect(&addr))?;
@@ -85,7 +85,7 @@ async fn run() -> io::Result<()> {
     let double_server = rpc::Server::new(server::Config::default())
         .incoming(double_listener)
         .take(1)
-        .respond_with(double::serve(DoubleServer { add_client }));
+        .respond_with_extras(double::serve(DoubleServer { add_client }));
     tokio_executor::spawn(double_server.unit_error().boxed().compat());
 
     let to_double_server = await!(bincode_transport::connect(&addr))?;
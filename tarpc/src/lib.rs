@@ -81,7 +81,7 @@
 //!         .take(1)
 //!         // serve is generated by the service! macro. It takes as input any type implementing
 //!         // the generated Service trait.
-//!         .respond_with(serve(HelloServer));
+//!         .respond_with_extras(serve(HelloServer));
 //!
 //!     tokio_executor::spawn(server.unit_error().boxed().compat());
 //!
@@ -134,3 +134,74 @@ pub use tarpc_plugins::*;
 /// Provides the macro used for constructing rpc services and client stubs.
 #[macro_use]
 mod macros;
+
+use std::{error, fmt, io};
+
+/// The error returned by a client stub for an rpc declared with an application error type, e.g.
+/// `#[error(E)]`. Distinguishes an rpc that failed from one whose handler returned an error.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The rpc failed, e.g. because the connection broke, the deadline passed, or the server
+    /// rejected the request.
+    Rpc(io::Error),
+    /// The rpc's handler returned an error.
+    Application(E),
+}
+
+impl<E> From<io::Error> for Error<E> {
+    fn from(e: io::Error) -> Self {
+        Error::Rpc(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Rpc(e) => write!(f, "rpc failed: {}", e),
+            Error::Application(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error + 'static> error::Error for Error<E> {
+    fn description(&self) -> &str {
+        match self {
+            Error::Rpc(e) => e.description(),
+            Error::Application(e) => e.description(),
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Rpc(e) => Some(e),
+            Error::Application(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use std::{error::Error as StdError, fmt, io};
+
+    #[derive(Debug)]
+    struct DivideByZero;
+
+    impl fmt::Display for DivideByZero {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "divide by zero")
+        }
+    }
+
+    impl StdError for DivideByZero {}
+
+    #[test]
+    fn source_is_wrapped_error() {
+        let rpc = Error::<DivideByZero>::Rpc(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        let source = rpc.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::TimedOut);
+
+        let application = Error::Application(DivideByZero);
+        assert!(application.source().unwrap().is::<DivideByZero>());
+    }
+}
@@ -25,6 +25,297 @@ macro_rules! add_serde_if_enabled {
     }
 }
 
+/// Builds the request for an rpc and sends it with the client, retrying if the rpc is idempotent.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! call_rpc__ {
+    (
+        [idempotent = false] $client:expr, $ctx:expr,
+        $request:ident::$fn_name:ident { $($arg:ident),* }
+    ) => {
+        $client.call($ctx, From::from($request::$fn_name { $($arg),* }))
+    };
+    (
+        [idempotent = true] $client:expr, $ctx:expr,
+        $request:ident::$fn_name:ident { $($arg:ident),* }
+    ) => {
+        $client.call_with_retry($ctx, move || {
+            From::from($request::$fn_name { $($arg: $arg.clone()),* })
+        })
+    };
+}
+
+/// Expands the parts of a service that differ between unary and streaming rpcs.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_kind__ {
+    (@assoc_type [unary] [] $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel! {
+            /// The type of future returned by `{}`.
+            type $fn_name: Future__<Output = $out> + Send;
+        }
+    };
+    (@assoc_type [unary] [ $err:ty ] $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel! {
+            /// The type of future returned by `{}`.
+            type $fn_name: Future__<Output = ::std::result::Result<$out, $err>> + Send;
+        }
+    };
+    (@assoc_type [stream] [] $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel_stream! {
+            /// The type of stream returned by `{}`.
+            type $fn_name: Stream__<Item = $out> + Send;
+        }
+    };
+    (@assoc_type [stream] [ $err:ty ] $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel_stream! {
+            /// The type of stream returned by `{}`.
+            type $fn_name: Stream__<Item = ::std::result::Result<$out, $err>> + Send;
+        }
+    };
+    (@out_ty [] $out:ty) => {
+        $out
+    };
+    (@out_ty [ $err:ty ] $out:ty) => {
+        ::std::result::Result<$out, $err>
+    };
+    (@result_ty [] $out:ty) => {
+        ::std::io::Result<$out>
+    };
+    (@result_ty [ $err:ty ] $out:ty) => {
+        ::std::result::Result<$out, $crate::Error<$err>>
+    };
+    (@ok [] $msg:ident) => {
+        ::std::result::Result::Ok($msg)
+    };
+    (@ok [ $err:ty ] $msg:ident) => {
+        $msg.map_err($crate::Error::Application)
+    };
+    (@ty [unary] $( $path:tt )*) => {
+        $crate::ty_snake_to_camel!($( $path )*)
+    };
+    (@ty [stream] $( $path:tt )*) => {
+        $crate::ty_snake_to_camel_stream!($( $path )*)
+    };
+    (@response_ty [unary] $( $path:tt )*) => {
+        $crate::ty_snake_to_camel!($( $path )*)
+    };
+    (@response_ty [stream] $( $path:tt )*) => {
+        ::std::option::Option<$crate::ty_snake_to_camel_stream!($( $path )*)>
+    };
+    (@input_ty []) => {
+        ()
+    };
+    (@input_ty [ $input:ident : $item:ty ]) => {
+        $item
+    };
+    // The server enforces the client's deadline; an rpc's own timeout also caps its reply.
+    (@cap $ctx:ident []) => {
+        ($ctx, None)
+    };
+    (@cap $ctx:ident [ $timeout:tt ]) => {{
+        let mut ctx = $ctx;
+        ctx.limit_timeout($crate::parse_duration!($timeout));
+        let cap = Some(ctx.clone());
+        (ctx, cap)
+    }};
+    (@wrap [unary] $resp:ident) => {
+        $resp
+    };
+    (@wrap [stream] $resp:ident) => {
+        ::std::option::Option::Some($resp)
+    };
+    (@poll [unary] $fn_name:ident $resp:ident $waker:ident) => {
+        ::std::pin::Pin::new_unchecked($resp)
+            .poll($waker)
+            .map(Response__::$fn_name)
+            .map($crate::server::Reply::Unary)
+            .map(Ok)
+    };
+    (@poll [stream] $fn_name:ident $resp:ident $waker:ident) => {
+        ::std::task::Poll::Ready(Ok($crate::server::Reply::Stream(
+            $crate::futures::StreamExt::boxed($crate::futures::StreamExt::map(
+                $resp.take().expect("Response polled after completion"),
+                |msg__| Ok(Response__::$fn_name(msg__)),
+            )),
+        )))
+    };
+    (
+        @client_method [$kind:ident] [ $input:ident : $item:ty ] [idempotent = true] $error:tt
+        $timeout:tt $attrs:tt $fn_name:ident $( $rest:tt )*
+    ) => {
+        compile_error!(concat!(
+            "Rpc ", stringify!($fn_name), " streams its inputs, so can't be #[idempotent]"
+        ));
+    };
+    (
+        @client_method [unary] [ $input:ident : $item:ty ] [idempotent = false]
+        [ $( $err:ty )* ] [ $( $timeout:tt )* ] [ $( #[$attr:meta] )* ]
+        $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name<S__>(
+            &mut self,
+            ctx: $crate::context::Context,
+            $input: S__,
+            $($arg: $in_),*
+        ) -> impl ::std::future::Future<
+            Output = $crate::rpc_kind__!(@result_ty [ $( $err )* ] $out)
+        > + '_
+        where
+            S__: $crate::futures::Stream<Item = $item> + Send + 'static,
+            Req: 'static,
+        {
+            $(
+                let mut ctx = ctx;
+                ctx.limit_timeout($crate::parse_duration!($timeout));
+            )*
+            let resp = self.0.call_with_inputs(
+                ctx,
+                From::from(Request__::$fn_name { $($arg),* }),
+                $crate::futures::StreamExt::map($input, |input| {
+                    From::from(Request__::Input__(Input__::$fn_name(input)))
+                }),
+            );
+            async move {
+                match Into::<Option<Response__>>::into(await!(resp)?) {
+                    Some(Response__::$fn_name(msg__)) => {
+                        $crate::rpc_kind__!(@ok [ $( $err )* ] msg__)
+                    }
+                    _ => ::std::result::Result::Err(From::from(
+                        $crate::rpc_kind__!(@unexpected $fn_name)
+                    )),
+                }
+            }
+        }
+    };
+    (
+        @client_method [stream] [ $input:ident : $item:ty ] [idempotent = false]
+        [ $( $err:ty )* ] [ $( $timeout:tt )* ] [ $( #[$attr:meta] )* ]
+        $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name<S__>(
+            &mut self,
+            ctx: $crate::context::Context,
+            $input: S__,
+            $($arg: $in_),*
+        ) -> impl ::std::future::Future<Output = ::std::io::Result<
+                impl $crate::futures::Stream<
+                    Item = $crate::rpc_kind__!(@result_ty [ $( $err )* ] $out)
+                >
+            >> + '_
+        where
+            S__: $crate::futures::Stream<Item = $item> + Send + 'static,
+            Req: 'static,
+        {
+            $(
+                let mut ctx = ctx;
+                ctx.limit_timeout($crate::parse_duration!($timeout));
+            )*
+            let resp = self.0.call_stream_with_inputs(
+                ctx,
+                From::from(Request__::$fn_name { $($arg),* }),
+                $crate::futures::StreamExt::map($input, |input| {
+                    From::from(Request__::Input__(Input__::$fn_name(input)))
+                }),
+            );
+            async move {
+                let responses = await!(resp)?;
+                Ok($crate::futures::StreamExt::map(responses, |resp| {
+                    match Into::<Option<Response__>>::into(resp?) {
+                        Some(Response__::$fn_name(msg__)) => {
+                            $crate::rpc_kind__!(@ok [ $( $err )* ] msg__)
+                        }
+                        _ => ::std::result::Result::Err(From::from(
+                            $crate::rpc_kind__!(@unexpected $fn_name)
+                        )),
+                    }
+                }))
+            }
+        }
+    };
+    (
+        @client_method [unary] [] [idempotent = $idempotent:tt] [ $( $err:ty )* ]
+        [ $( $timeout:tt )* ] [ $( #[$attr:meta] )* ]
+        $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
+            -> impl ::std::future::Future<
+                Output = $crate::rpc_kind__!(@result_ty [ $( $err )* ] $out)
+            > + '_ {
+            $(
+                let mut ctx = ctx;
+                ctx.limit_timeout($crate::parse_duration!($timeout));
+            )*
+            let resp = $crate::call_rpc__!(
+                [idempotent = $idempotent] self.0, ctx, Request__::$fn_name { $($arg),* }
+            );
+            async move {
+                match Into::<Option<Response__>>::into(await!(resp)?) {
+                    Some(Response__::$fn_name(msg__)) => {
+                        $crate::rpc_kind__!(@ok [ $( $err )* ] msg__)
+                    }
+                    _ => ::std::result::Result::Err(From::from(
+                        $crate::rpc_kind__!(@unexpected $fn_name)
+                    )),
+                }
+            }
+        }
+    };
+    (
+        @client_method [stream] [] [idempotent = false] [ $( $err:ty )* ]
+        [ $( $timeout:tt )* ] [ $( #[$attr:meta] )* ]
+        $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
+            -> impl ::std::future::Future<Output = ::std::io::Result<
+                impl $crate::futures::Stream<
+                    Item = $crate::rpc_kind__!(@result_ty [ $( $err )* ] $out)
+                >
+            >> + '_ {
+            $(
+                let mut ctx = ctx;
+                ctx.limit_timeout($crate::parse_duration!($timeout));
+            )*
+            let resp = self.0.call_stream(ctx, From::from(Request__::$fn_name { $($arg),* }));
+            async move {
+                let responses = await!(resp)?;
+                Ok($crate::futures::StreamExt::map(responses, |resp| {
+                    match Into::<Option<Response__>>::into(resp?) {
+                        Some(Response__::$fn_name(msg__)) => {
+                            $crate::rpc_kind__!(@ok [ $( $err )* ] msg__)
+                        }
+                        _ => ::std::result::Result::Err(From::from(
+                            $crate::rpc_kind__!(@unexpected $fn_name)
+                        )),
+                    }
+                }))
+            }
+        }
+    };
+    (
+        @client_method [stream] [] [idempotent = true] $error:tt $timeout:tt $attrs:tt
+        $fn_name:ident $( $rest:tt )*
+    ) => {
+        compile_error!(concat!(
+            "Streaming rpc ", stringify!($fn_name), " can't be retried, so can't be #[idempotent]"
+        ));
+    };
+    (@unexpected $fn_name:ident) => {
+        ::std::io::Error::new(
+            ::std::io::ErrorKind::InvalidData,
+            concat!("Unexpected response to rpc ", stringify!($fn_name)),
+        )
+    };
+}
+
 /// The main macro that creates RPC services.
 ///
 /// Rpc methods are specified, mirroring trait syntax:
@@ -38,56 +329,282 @@ macro_rules! add_serde_if_enabled {
 /// # }
 /// ```
 ///
+/// An rpc can stream its responses, in which case the service's fn returns a
+/// [`Stream`](futures::Stream) of them, and the client stub's fn resolves to a stream of them:
+///
+/// ```
+/// # #![feature(await_macro, pin, arbitrary_self_types, async_await, futures_api, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// /// Count to n
+/// rpc count(n: u32) -> stream u32;
+/// # }
+/// ```
+///
+/// An rpc can also take a stream of inputs, declared as its first arg. The service's fn receives
+/// the inputs as [`Inputs`](rpc::server::Inputs), and the client stub's fn takes any
+/// [`Stream`](futures::Stream) of them. Such an rpc can return a single response, or, for
+/// bidirectional streaming, a stream of them:
+///
+/// ```
+/// # #![feature(await_macro, pin, arbitrary_self_types, async_await, futures_api, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// /// Sum the numbers
+/// rpc sum(stream numbers: u64) -> u64;
+/// /// Echo each line, prefixed with `prefix`
+/// rpc echo(stream lines: String, prefix: String) -> stream String;
+/// # }
+/// ```
+///
+/// The server buffers a limited number of inputs that the service's fn hasn't read yet, set by
+/// [`pending_request_buffer`](rpc::server::Config::pending_request_buffer). A client that gets
+/// further ahead waits for the service's fn to read more before sending the rest.
+///
 /// Attributes can be attached to each rpc. These attributes
 /// will then be attached to the generated service traits'
 /// corresponding `fn`s, as well as to the client stubs' RPCs.
 ///
+/// The following attributes are interpreted by the macro rather than attached to the generated
+/// items:
+///
+/// * `#[idempotent]` -- the client stub retries the rpc according to the client's
+///   [`RetryPolicy`](rpc::client::RetryPolicy) when it fails transiently. Because the request is
+///   rebuilt for each attempt, the types of the rpc's args must impl `Clone`.
+/// * `#[error(E)]` -- the rpc's handler can fail with an application error of type `E`. The
+///   service's fn outputs a `Result<_, E>`, which is sent back in the response rather than as a
+///   server error, and the client stub's fn fails with an [`Error<E>`](crate::Error) that tells
+///   whether the rpc failed or its handler returned an error. A streaming rpc does the same for
+///   each response. Errors are never retried, even for `#[idempotent]` rpcs.
+/// * `#[timeout = "250ms"]` -- the client stub shortens the rpc's deadline to the timeout when
+///   the context's deadline is later, and the server handles the rpc with at most the timeout,
+///   even if the client sent a later deadline. Timeouts are written as a sequence of integers
+///   followed by units: `h`, `m`, `s`, `ms`, `us`, or `ns`, e.g. `"1m 30s"`.
+///
+/// A service can give all of its rpcs an application error type by starting with
+/// `#![error(E)]`, which an rpc's own `#[error(..)]` overrides:
+///
+/// ```ignore
+/// tarpc::service! {
+///     #![error(String)]
+///
+///     /// Look up a key
+///     rpc get(key: String) -> String;
+///     /// Delete a key
+///     #[error(u32)]
+///     rpc delete(key: String);
+/// }
+/// ```
+///
 /// The following items are expanded in the enclosing module:
 ///
 /// * `trait Service` -- defines the RPC service.
-///   * `fn serve` -- turns a service impl into a request handler.
+///   * `fn serve` -- turns a service impl into a request handler, to pass to
+///     [`respond_with_extras`](rpc::server::Handler::respond_with_extras).
+///   * `fn serve_with_callbacks` -- like `serve`, but makes a service impl for each request from
+///     the request's [`Callbacks`](rpc::transport::Callbacks), for services that make callbacks
+///     to their clients over a [duplex](rpc::transport::duplex) transport.
 /// * `Client` -- a client stub with a fn for each RPC.
 ///   * `fn new_stub` -- creates a new Client stub.
+///   * `impl From<rpc::client::Client>` -- wraps an existing client, e.g. one with
+///     [interceptors](rpc::client::Interceptor) or one shared by
+///     [multiplexed](tarpc::multiplex) services, in a Client stub.
+/// * `fn handshake` -- returns the service's [`Handshake`](rpc::Handshake). Setting it in the
+///   [client](rpc::client::Config::handshake) and [server](rpc::server::Config::handshake)
+///   configs makes servers reject clients built from a different definition of the service.
+///   The service is named after the enclosing module; call
+///   [`with_service_name`](rpc::Handshake::with_service_name) on the handshake to name it
+///   otherwise, e.g. to keep talking to peers built before the module was renamed.
 ///
 #[macro_export]
 macro_rules! service {
-// Entry point
+// Pattern for when there are no more rpcs to expand.
     (
-        $(
-            $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)*;
-        )*
+        @rpc [idempotent = false] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] []
+        {}
+        $( $expanded:tt )*
     ) => {
-        $crate::service! {{
-            $(
-                $(#[$attr])*
-                rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)*;
-            )*
-        }}
+        $crate::service! {
+            @expand
+            $( $expanded )*
+        }
     };
-// Pattern for when the next rpc has an implicit unit return type.
+// Pattern for when the next attribute of the next rpc is #[error(..)].
     (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
         {
-            $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* );
+            #[error($err:ty)]
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = $idempotent] [error = [$err]] [default = $default]
+            [timeout = $timeout] [ $( $attrs )* ]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+        }
+    };
+// Pattern for when the next attribute of the next rpc is #[timeout = ".."].
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            #[timeout = $t:tt]
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = $idempotent] [error = $error] [default = $default]
+            [timeout = [$t]] [ $( $attrs )* ]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+        }
+    };
+// Pattern for when the next attribute of the next rpc is #[idempotent].
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            #[idempotent]
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = true] [error = $error] [default = $default]
+            [timeout = $timeout] [ $( $attrs )* ]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+        }
+    };
+// Pattern for when the next attribute of the next rpc is attached to the generated items.
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            #[$attr:meta]
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = $idempotent] [error = $error] [default = $default]
+            [timeout = $timeout] [ $( $attrs )* #[$attr] ]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+        }
+    };
+// Pattern for when the next rpc streams its inputs and its responses.
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            rpc $fn_name:ident(
+                stream $input:ident : $item:ty $( , $arg:ident : $in_:ty )*
+            ) -> stream $out:ty;
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            [idempotent = $idempotent] [stream] [ $input : $item ] $error $timeout
+            $( $attrs )*
+            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+        }
+    };
+// Pattern for when the next rpc streams its inputs and has an explicit return type.
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            rpc $fn_name:ident(
+                stream $input:ident : $item:ty $( , $arg:ident : $in_:ty )*
+            ) -> $out:ty;
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            [idempotent = $idempotent] [unary] [ $input : $item ] $error $timeout
+            $( $attrs )*
+            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+        }
+    };
+// Pattern for when the next rpc streams its inputs and has an implicit unit return type.
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            rpc $fn_name:ident(
+                stream $input:ident : $item:ty $( , $arg:ident : $in_:ty )*
+            );
 
             $( $unexpanded:tt )*
         }
         $( $expanded:tt )*
     ) => {
         $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
             { $( $unexpanded )* }
 
             $( $expanded )*
 
-            $(#[$attr])*
+            [idempotent = $idempotent] [unary] [ $input : $item ] $error $timeout
+            $( $attrs )*
             rpc $fn_name( $( $arg : $in_ ),* ) -> ();
         }
     };
+// Pattern for when the next rpc streams its responses.
+    (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> stream $out:ty;
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            [idempotent = $idempotent] [stream] [] $error $timeout
+            $( $attrs )*
+            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+        }
+    };
 // Pattern for when the next rpc has an explicit return type.
     (
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
         {
-            $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
 
             $( $unexpanded:tt )*
@@ -95,18 +612,52 @@ macro_rules! service {
         $( $expanded:tt )*
     ) => {
         $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
             { $( $unexpanded )* }
 
             $( $expanded )*
 
-            $(#[$attr])*
+            [idempotent = $idempotent] [unary] [] $error $timeout
+            $( $attrs )*
             rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
         }
     };
-// Pattern for when all return types have been expanded
+// Pattern for when the next rpc has an implicit unit return type.
     (
-        { } // none left to expand
+        @rpc [idempotent = $idempotent:tt] [error = $error:tt] [default = $default:tt]
+        [timeout = $timeout:tt] [ $( $attrs:tt )* ]
+        {
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* );
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = $default] [default = $default] [timeout = []] []
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            [idempotent = $idempotent] [unary] [] $error $timeout
+            $( $attrs )*
+            rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+        }
+    };
+// Pattern for when the next rpc can't be parsed.
+    (
+        @rpc $options:tt $error:tt $default:tt $timeout:tt $attrs:tt
+        { $( $unexpanded:tt )* }
+        $( $expanded:tt )*
+    ) => {
+        compile_error!(concat!("Could not parse rpc: ", stringify!($( $unexpanded )*)));
+    };
+// Pattern for when all rpcs have been expanded.
+    (
+        @expand
         $(
+            [idempotent = $idempotent:tt] [$kind:ident] [ $( $input:ident : $item:ty )* ]
+            [ $( $err:ty )* ] [ $( $timeout:tt )* ]
             $(#[$attr:meta])*
             rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
         )*
@@ -118,7 +669,20 @@ macro_rules! service {
             --
             pub enum Request__ {
                 $(
-                    $fn_name{ $($arg: $in_,)* }
+                    $fn_name{ $($arg: $in_,)* },
+                )*
+                Input__(Input__),
+            }
+        }
+
+        $crate::add_serde_if_enabled! {
+            #[derive(Debug)]
+            #[doc(hidden)]
+            #[allow(non_camel_case_types, unused)]
+            --
+            pub enum Input__ {
+                $(
+                    $fn_name($crate::rpc_kind__!(@input_ty [ $( $input : $item )* ]))
                 ),*
             }
         }
@@ -130,7 +694,7 @@ macro_rules! service {
             --
             pub enum Response__ {
                 $(
-                    $fn_name($out)
+                    $fn_name($crate::rpc_kind__!(@out_ty [ $( $err )* ] $out))
                 ),*
             }
         }
@@ -138,18 +702,22 @@ macro_rules! service {
         // TODO: proc_macro can't currently parse $crate, so this needs to be imported for the
         // usage of snake_to_camel! to work.
         use $crate::futures::Future as Future__;
+        #[allow(unused_imports)]
+        use $crate::futures::Stream as Stream__;
 
         /// Defines the RPC service. The additional trait bounds are required so that services can
         /// multiplex requests across multiple tasks, potentially on multiple threads.
         pub trait Service: Clone + Send + 'static {
             $(
-                $crate::snake_to_camel! {
-                    /// The type of future returned by `{}`.
-                    type $fn_name: Future__<Output = $out> + Send;
-                }
+                $crate::rpc_kind__!(@assoc_type [$kind] [ $( $err )* ] $fn_name $out);
 
                 $(#[$attr])*
-                fn $fn_name(&self, ctx: $crate::context::Context, $($arg:$in_),*) -> $crate::ty_snake_to_camel!(Self::$fn_name);
+                fn $fn_name(
+                    &self,
+                    ctx: $crate::context::Context,
+                    $( $input: $crate::server::Inputs<$item>, )*
+                    $($arg:$in_),*
+                ) -> $crate::rpc_kind__!(@ty [$kind] Self::$fn_name);
             )*
         }
 
@@ -157,8 +725,9 @@ macro_rules! service {
         #[allow(non_camel_case_types)]
         pub enum Response<S: Service> {
             $(
-                $fn_name($crate::ty_snake_to_camel!(<S as Service>::$fn_name)),
+                $fn_name($crate::rpc_kind__!(@response_ty [$kind] <S as Service>::$fn_name)),
             )*
+            Invalid__(&'static str),
         }
 
         impl<S: Service> ::std::fmt::Debug for Response<S> {
@@ -167,75 +736,324 @@ macro_rules! service {
             }
         }
 
-        impl<S: Service> ::std::future::Future for Response<S> {
-            type Output = ::std::io::Result<Response__>;
-
-            fn poll(self: ::std::pin::Pin<&mut Self>, waker: &::std::task::LocalWaker)
-                -> ::std::task::Poll<::std::io::Result<Response__>>
-            {
-                unsafe {
-                    match ::std::pin::Pin::get_mut_unchecked(self) {
-                        $(
-                            Response::$fn_name(resp) =>
-                                ::std::pin::Pin::new_unchecked(resp)
-                                    .poll(waker)
-                                    .map(Response__::$fn_name)
-                                    .map(Ok),
-                        )*
-                    }
+        impl<S: Service> ::std::future::Future for Response<S> {
+            type Output = ::std::io::Result<$crate::server::Reply<Response__>>;
+
+            fn poll(self: ::std::pin::Pin<&mut Self>, waker: &::std::task::LocalWaker)
+                -> ::std::task::Poll<::std::io::Result<$crate::server::Reply<Response__>>>
+            {
+                // Futures are polled in place. Streams have never been polled when they're moved
+                // out to be boxed.
+                unsafe {
+                    match ::std::pin::Pin::get_mut_unchecked(self) {
+                        $(
+                            Response::$fn_name(resp) =>
+                                $crate::rpc_kind__!(@poll [$kind] $fn_name resp waker),
+                        )*
+                        Response::Invalid__(detail) => ::std::task::Poll::Ready(Err(
+                            ::std::io::Error::new(::std::io::ErrorKind::InvalidInput, *detail),
+                        )),
+                    }
+                }
+            }
+        }
+
+        /// Returns a serving function to pass to rpc::server::Server's `respond_with_extras`.
+        pub fn serve<S: Service>(service: S)
+            -> impl FnMut(
+                $crate::context::Context,
+                Request__,
+                $crate::server::Extras<Request__>,
+            ) -> $crate::server::Capped<Response<S>> + Send + 'static + Clone {
+                serve_with_callbacks(move |_| service.clone())
+            }
+
+        /// Like `serve`, but for a service that makes callbacks to its clients: `new_service`
+        /// makes the service impl for each request, from the client for callbacks to the peer
+        /// that the request came from, if the peer serves any.
+        pub fn serve_with_callbacks<S, F>(mut new_service: F)
+            -> impl FnMut(
+                $crate::context::Context,
+                Request__,
+                $crate::server::Extras<Request__>,
+            ) -> $crate::server::Capped<Response<S>> + Send + 'static + Clone
+        where
+            S: Service,
+            F: FnMut(Option<$crate::transport::Callbacks>) -> S + Send + 'static + Clone,
+        {
+                move |ctx, req, extras| {
+                    let service = new_service(extras.callbacks);
+                    #[allow(unused_variables)]
+                    let inputs = extras.inputs;
+                    match req {
+                        $(
+                            Request__::$fn_name{ $($arg,)* } => {
+                                let (ctx, cap) =
+                                    $crate::rpc_kind__!(@cap ctx [ $( $timeout )* ]);
+                                $(
+                                    let $input = match inputs {
+                                        Some(inputs) => inputs.try_map(|input| match input {
+                                            Request__::Input__(Input__::$fn_name(input)) => {
+                                                Ok(input)
+                                            }
+                                            _ => Err(::std::io::Error::new(
+                                                ::std::io::ErrorKind::InvalidData,
+                                                concat!(
+                                                    "Unexpected input to rpc ",
+                                                    stringify!($fn_name)
+                                                ),
+                                            )),
+                                        }),
+                                        None => return $crate::server::Capped::new(
+                                            Response::Invalid__(concat!(
+                                                "Rpc ",
+                                                stringify!($fn_name),
+                                                " was called without inputs"
+                                            )),
+                                            None,
+                                        ),
+                                    };
+                                )*
+                                let resp = Service::$fn_name(
+                                    &service, ctx, $($input,)* $($arg),*
+                                );
+                                let resp = $crate::rpc_kind__!(@wrap [$kind] resp);
+                                $crate::server::Capped::new(Response::$fn_name(resp), cap)
+                            }
+                        )*
+                        Request__::Input__(_) => $crate::server::Capped::new(
+                            Response::Invalid__("Received an rpc's input as a request"),
+                            None,
+                        ),
+                    }
+                }
+            }
+
+        /// Returns the handshake of this service, which identifies it by the name of the
+        /// enclosing module and the signatures of its rpcs.
+        pub fn handshake() -> $crate::Handshake {
+            $crate::Handshake::new(
+                module_path!().rsplit("::").next().unwrap_or_default(),
+                concat!($(
+                    stringify!(
+                        rpc $fn_name( $( stream $input : $item, )* $( $arg : $in_ ),* )
+                            -> $kind $out $( error $err )*;
+                    )
+                ),*),
+            )
+        }
+
+        #[allow(unused)]
+        #[derive(Debug)]
+        /// The client stub that makes RPC calls to the server. Exposes a Future interface.
+        ///
+        /// By default, the stub has a connection of its own. A stub of a service
+        /// [multiplexed](tarpc::multiplex) with others shares a connection with them, and has the
+        /// request and response types of the multiplexed services.
+        pub struct Client<Req = Request__, Resp = Response__>($crate::client::Client<Req, Resp>);
+
+        impl<Req, Resp> Clone for Client<Req, Resp> {
+            fn clone(&self) -> Self {
+                Client(self.0.clone())
+            }
+        }
+
+        /// Returns a new client stub that sends requests over the given transport.
+        pub async fn new_stub<T>(config: $crate::client::Config, transport: T)
+            -> ::std::io::Result<Client>
+        where
+            T: $crate::Transport<
+                    Item = $crate::ServerMessage<Response__>,
+                    SinkItem = $crate::ClientMessage<Request__>> + Send,
+        {
+            Ok(Client(await!($crate::client::Client::new(config, transport))?))
+        }
+
+        impl<Req, Resp> From<$crate::client::Client<Req, Resp>> for Client<Req, Resp> {
+            fn from(client: $crate::client::Client<Req, Resp>) -> Self {
+                Client(client)
+            }
+        }
+
+        impl<Req, Resp> Client<Req, Resp>
+        where
+            Req: From<Request__> + Send,
+            Resp: Into<Option<Response__>> + Send,
+        {
+            $(
+                $crate::rpc_kind__!(
+                    @client_method [$kind] [ $( $input : $item )* ] [idempotent = $idempotent]
+                    [ $( $err )* ] [ $( $timeout )* ] [ $( #[$attr] )* ]
+                    $fn_name( $( $arg : $in_ ),* ) -> $out
+                );
+            )*
+        }
+    };
+// Entry point for services whose rpcs have a default error type.
+    (
+        #![error($err:ty)]
+
+        $( $rpcs:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = [$err]] [default = [$err]] [timeout = []] []
+            { $( $rpcs )* }
+        }
+    };
+// Entry point
+    (
+        $( $rpcs:tt )*
+    ) => {
+        $crate::service! {
+            @rpc [idempotent = false] [error = []] [default = []] [timeout = []] []
+            { $( $rpcs )* }
+        }
+    };
+}
+
+/// Composes services defined with [`service!`](tarpc::service), so that one server can serve
+/// them all, and clients can reach them all, over a single connection.
+///
+/// Each service is named, and given by the path of the module in which it is defined:
+///
+/// ```ignore
+/// mod all {
+///     tarpc::multiplex! {
+///         hello: super::hello,
+///         math: super::math,
+///     }
+/// }
+/// ```
+///
+/// Requests and responses are tagged with their service, which routes them to the service. Over
+/// formats that encode enum variants by position, such as bincode, the tag is the position of
+/// the service in the list rather than its name, so clients and servers must list the same
+/// services in the same order. The handshake covers the order, so configure it to detect a
+/// mismatch when a client connects. The following items are expanded in the enclosing module:
+///
+/// * `Request__` and `Response__` -- the request and response types of the composed services,
+///   for use with [`Server`](rpc::Server) and [`Client`](rpc::client::Client).
+/// * `fn serve` -- turns an impl of each service, in order, into a request handler.
+/// * `fn handshake` -- returns a [`Handshake`](rpc::Handshake) covering every composed service,
+///   in order.
+///
+/// A client stub for any of the services wraps a clone of a shared
+/// [`Client`](rpc::client::Client):
+///
+/// ```ignore
+/// let client = await!(Client::<all::Request__, all::Response__>::new(config, transport))?;
+/// let mut hello = hello::Client::from(client.clone());
+/// let mut math = math::Client::from(client);
+/// ```
+#[macro_export]
+macro_rules! multiplex {
+    (
+        $( $name:ident : $( $service:ident )::+ ),* $(,)*
+    ) => {
+        $crate::add_serde_if_enabled! {
+            #[derive(Debug)]
+            #[doc(hidden)]
+            #[allow(non_camel_case_types, unused)]
+            --
+            pub enum Request__ {
+                $(
+                    $name($( $service )::+::Request__)
+                ),*
+            }
+        }
+
+        $crate::add_serde_if_enabled! {
+            #[derive(Debug)]
+            #[doc(hidden)]
+            #[allow(non_camel_case_types, unused)]
+            --
+            pub enum Response__ {
+                $(
+                    $name($( $service )::+::Response__)
+                ),*
+            }
+        }
+
+        $(
+            impl From<$( $service )::+::Request__> for Request__ {
+                fn from(request: $( $service )::+::Request__) -> Self {
+                    Request__::$name(request)
                 }
             }
-        }
 
-        /// Returns a serving function to use with rpc::server::Server.
-        pub fn serve<S: Service>(service: S)
-            -> impl FnMut($crate::context::Context, Request__) -> Response<S> + Send + 'static + Clone {
-                move |ctx, req| {
-                    match req {
-                        $(
-                            Request__::$fn_name{ $($arg,)* } => {
-                                let resp = Service::$fn_name(&mut service.clone(), ctx, $($arg),*);
-                                Response::$fn_name(resp)
-                            }
-                        )*
+            impl From<Response__> for Option<$( $service )::+::Response__> {
+                #[allow(unreachable_patterns)]
+                fn from(response: Response__) -> Self {
+                    match response {
+                        Response__::$name(response) => Some(response),
+                        _ => None,
                     }
                 }
             }
+        )*
 
-        #[allow(unused)]
-        #[derive(Clone, Debug)]
-        /// The client stub that makes RPC calls to the server. Exposes a Future interface.
-        pub struct Client($crate::client::Client<Request__, Response__>);
-
-        /// Returns a new client stub that sends requests over the given transport.
-        pub async fn new_stub<T>(config: $crate::client::Config, transport: T)
-            -> ::std::io::Result<Client>
-        where
-            T: $crate::Transport<
-                    Item = $crate::Response<Response__>,
-                    SinkItem = $crate::ClientMessage<Request__>> + Send,
+        /// Returns a serving function to pass to rpc::server::Server's `respond_with_extras`,
+        /// which routes each request to the impl of its service.
+        pub fn serve($( $name: impl $( $service )::+::Service ),*)
+            -> impl FnMut(
+                $crate::context::Context,
+                Request__,
+                $crate::server::Extras<Request__>,
+            ) -> $crate::futures::future::BoxFuture<
+                    'static,
+                    ::std::io::Result<$crate::server::Reply<Response__>>,
+                >
+                + Send + 'static + Clone
         {
-            Ok(Client(await!($crate::client::Client::new(config, transport))?))
+            $( let mut $name = $( $service )::+::serve($name); )*
+            move |ctx, req, extras| {
+                match req {
+                    $(
+                        Request__::$name(req) => {
+                            // Inputs are unwrapped for the service, like the request itself.
+                            let extras = extras.map_inputs(|inputs| {
+                                inputs.try_map(|input| match input {
+                                    Request__::$name(input) => Ok(input),
+                                    #[allow(unreachable_patterns)]
+                                    _ => Err(::std::io::Error::new(
+                                        ::std::io::ErrorKind::InvalidData,
+                                        concat!("Unexpected input to service ", stringify!($name)),
+                                    )),
+                                })
+                            });
+                            let resp = $name(ctx, req, extras);
+                            $crate::futures::FutureExt::boxed(
+                                $crate::futures::TryFutureExt::map_ok(
+                                    resp,
+                                    |reply| reply.map(Response__::$name),
+                                ),
+                            )
+                        }
+                    )*
+                }
+            }
         }
 
-        impl Client {
+        /// Returns the handshake of the composed services, which identifies them by the name of
+        /// the enclosing module and the handshakes of the individual services.
+        pub fn handshake() -> $crate::Handshake {
+            // The services are listed in order, as requests may be routed by their position.
+            let mut schema = String::new();
             $(
-                #[allow(unused)]
-                $(#[$attr])*
-                pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
-                    -> impl ::std::future::Future<Output = ::std::io::Result<$out>> + '_ {
-                    let request__ = Request__::$fn_name { $($arg,)* };
-                    let resp = self.0.call(ctx, request__);
-                    async move {
-                        match await!(resp)? {
-                            Response__::$fn_name(msg__) => ::std::result::Result::Ok(msg__),
-                            _ => unreachable!(),
-                        }
-                    }
-                }
+                let service = $( $service )::+::handshake();
+                schema.push_str(&format!(
+                    "{}={}:{:016x};",
+                    stringify!($name),
+                    service.service_name,
+                    service.schema_fingerprint,
+                ));
             )*
+            $crate::Handshake::new(
+                module_path!().rsplit("::").next().unwrap_or_default(),
+                &schema,
+            )
         }
-    }
+    };
 }
 
 // allow dead code; we're just testing that the macro expansion compiles
@@ -259,6 +1077,50 @@ mod syntax_test {
         rpc no_arg_implicit_return_error();
         #[doc="attr"]
         rpc one_arg_implicit_return_error(foo: String);
+        #[idempotent]
+        rpc idempotent(foo: String, bar: u64) -> String;
+        #[doc="attr"]
+        #[idempotent]
+        rpc idempotent_implicit_return(foo: String);
+        rpc no_args_stream() -> stream String;
+        #[doc="attr"]
+        rpc two_args_stream(bar: String, baz: u64) -> stream u64;
+        rpc input_stream(stream items: String) -> u64;
+        rpc input_stream_no_return(stream items: u64);
+        #[doc="attr"]
+        rpc bidi_stream(stream items: String, foo: u64) -> stream String;
+        #[error(String)]
+        rpc fallible(foo: String) -> u64;
+        #[error(String)]
+        rpc fallible_no_return();
+        #[doc="attr"]
+        #[error(String)]
+        #[idempotent]
+        rpc fallible_idempotent(foo: String);
+        #[error(String)]
+        rpc fallible_stream() -> stream u64;
+        #[error(String)]
+        rpc fallible_bidi_stream(stream items: String) -> stream String;
+        #[timeout = "250ms"]
+        rpc timed(foo: String) -> u64;
+        #[doc="attr"]
+        #[timeout = "1m 30s"]
+        #[idempotent]
+        rpc timed_idempotent();
+        #[error(String)]
+        #[timeout = "1s"]
+        rpc timed_stream(stream items: String) -> stream String;
+    }
+
+    mod default_error {
+        service! {
+            #![error(String)]
+
+            rpc fallible() -> u64;
+            #[error(u32)]
+            rpc overridden(foo: String);
+            rpc fallible_stream() -> stream u64;
+        }
     }
 }
 
@@ -266,20 +1128,33 @@ mod syntax_test {
 mod functional_test {
     use futures::{
         compat::TokioDefaultSpawner,
-        future::{ready, Ready},
+        future::{empty, ready, BoxFuture, Empty, Ready},
         prelude::*,
+        stream::BoxStream,
     };
     use rpc::{
         client, context,
         server::{self, Handler},
         transport::channel,
+        Code, ServerError,
+    };
+    use crate::Error;
+    use std::{
+        io,
+        time::{Duration, SystemTime},
     };
-    use std::io;
     use tokio::runtime::current_thread;
 
     service! {
         rpc add(x: i32, y: i32) -> i32;
         rpc hey(name: String) -> String;
+        rpc count(n: u32) -> stream u32;
+        rpc sum(stream numbers: i32) -> i32;
+        rpc echo(stream lines: String, prefix: String) -> stream String;
+        #[error(String)]
+        rpc div(x: i32, y: i32) -> i32;
+        #[timeout = "10ms"]
+        rpc stall();
     }
 
     #[derive(Clone)]
@@ -297,6 +1172,49 @@ mod functional_test {
         fn hey(&self, _: context::Context, name: String) -> Self::HeyFut {
             ready(format!("Hey, {}.", name))
         }
+
+        type CountStream = stream::Iter<::std::ops::Range<u32>>;
+
+        fn count(&self, _: context::Context, n: u32) -> Self::CountStream {
+            stream::iter(0..n)
+        }
+
+        type SumFut = BoxFuture<'static, i32>;
+
+        fn sum(&self, _: context::Context, numbers: server::Inputs<i32>) -> Self::SumFut {
+            numbers
+                .try_fold(0, |sum, n| ready(Ok(sum + n)))
+                .map(|sum| sum.unwrap_or(-1))
+                .boxed()
+        }
+
+        type EchoStream = BoxStream<'static, String>;
+
+        fn echo(
+            &self,
+            _: context::Context,
+            lines: server::Inputs<String>,
+            prefix: String,
+        ) -> Self::EchoStream {
+            lines
+                .map(move |line| match line {
+                    Ok(line) => format!("{}{}", prefix, line),
+                    Err(e) => format!("error: {}", e),
+                })
+                .boxed()
+        }
+
+        type DivFut = Ready<Result<i32, String>>;
+
+        fn div(&self, _: context::Context, x: i32, y: i32) -> Self::DivFut {
+            ready(x.checked_div(y).ok_or_else(|| format!("Can't divide {} by {}", x, y)))
+        }
+
+        type StallFut = Empty<()>;
+
+        fn stall(&self, _: context::Context) -> Self::StallFut {
+            empty()
+        }
     }
 
     #[test]
@@ -309,7 +1227,7 @@ mod functional_test {
             tokio_executor::spawn(
                 rpc::Server::new(server::Config::default())
                     .incoming(stream::once(ready(Ok(rx))))
-                    .respond_with(serve(Server))
+                    .respond_with_extras(serve(Server))
                     .unit_error()
                     .boxed()
                     .compat()
@@ -338,7 +1256,7 @@ mod functional_test {
             tokio_executor::spawn(
                 rpc::Server::new(server::Config::default())
                     .incoming(stream::once(ready(Ok(rx))))
-                    .respond_with(serve(Server))
+                    .respond_with_extras(serve(Server))
                     .unit_error()
                     .boxed()
                     .compat()
@@ -361,4 +1279,271 @@ mod functional_test {
 
         current_thread::block_on_all(test.boxed().compat()).unwrap();
     }
+
+    #[test]
+    fn stream() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            let mut client = await!(new_stub(client::Config::default(), tx))?;
+            let counts = await!(client.count(context::current(), 3))?;
+            let counts: Vec<u32> = await!(counts.try_collect())?;
+            assert_eq!(counts, vec![0, 1, 2]);
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn input_stream() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            let mut client = await!(new_stub(client::Config::default(), tx))?;
+            let sum = await!(client.sum(context::current(), stream::iter(vec![1, 2, 3])))?;
+            assert_eq!(sum, 6);
+
+            let lines = stream::iter(vec!["a".to_string(), "b".to_string()]);
+            let echoes = await!(client.echo(context::current(), lines, "> ".to_string()))?;
+            let echoes: Vec<String> = await!(echoes.try_collect())?;
+            assert_eq!(echoes, vec!["> a", "> b"]);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn application_error() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            let mut client = await!(new_stub(client::Config::default(), tx))?;
+            match await!(client.div(context::current(), 6, 3)) {
+                Ok(2) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            match await!(client.div(context::current(), 1, 0)) {
+                Err(Error::Application(e)) => assert_eq!(e, "Can't divide 1 by 0"),
+                other => panic!("unexpected result: {:?}", other),
+            }
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            let mut client = await!(new_stub(client::Config::default(), tx))?;
+            match await!(client.stall(context::current())) {
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn server_timeout() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            // A raw client doesn't know the rpc's timeout, so only the server can enforce it.
+            let mut client = await!(client::Client::<Request__, Response__>::new(
+                client::Config::default(),
+                tx
+            ))?;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_secs(60);
+            match await!(client.call(ctx, Request__::stall {})) {
+                Err(ref e) => {
+                    let e = ServerError::downcast_ref(e).unwrap();
+                    assert_eq!(e.code, Code::DeadlineExceeded);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod multiplex_test {
+    use futures::{
+        compat::TokioDefaultSpawner,
+        future::{ready, Ready},
+        prelude::*,
+    };
+    use rpc::{
+        client::{self, Client},
+        context,
+        server::{self, Handler},
+        transport::channel,
+    };
+    use std::io;
+    use tokio::runtime::current_thread;
+
+    mod math {
+        service! {
+            rpc add(x: i32, y: i32) -> i32;
+        }
+    }
+
+    mod greeter {
+        service! {
+            rpc hey(name: String) -> String;
+        }
+    }
+
+    mod all {
+        multiplex! {
+            math: super::math,
+            greeter: super::greeter,
+        }
+    }
+
+    mod reordered {
+        multiplex! {
+            greeter: super::greeter,
+            math: super::math,
+        }
+    }
+
+    #[derive(Clone)]
+    struct Server;
+
+    impl math::Service for Server {
+        type AddFut = Ready<i32>;
+
+        fn add(&self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+            ready(x + y)
+        }
+    }
+
+    impl greeter::Service for Server {
+        type HeyFut = Ready<String>;
+
+        fn hey(&self, _: context::Context, name: String) -> Self::HeyFut {
+            ready(format!("Hey, {}.", name))
+        }
+    }
+
+    #[test]
+    fn shared_connection() {
+        let _ = env_logger::try_init();
+        rpc::init(TokioDefaultSpawner);
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::new(server::Config::default())
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with_extras(all::serve(Server, Server))
+                    .unit_error()
+                    .boxed()
+                    .compat()
+            );
+
+            let client = await!(Client::<all::Request__, all::Response__>::new(
+                client::Config::default(),
+                tx
+            ))?;
+            let mut math = math::Client::from(client.clone());
+            let mut greeter = greeter::Client::from(client);
+            assert_eq!(3, await!(math.add(context::current(), 1, 2))?);
+            assert_eq!(
+                "Hey, Tim.",
+                await!(greeter.hey(context::current(), "Tim".to_string()))?
+            );
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!(e.to_string()));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn handshake_covers_every_service() {
+        assert_ne!(all::handshake(), math::handshake());
+        assert_eq!(all::handshake().service_name, "all");
+        assert_eq!(math::handshake().service_name, "math");
+    }
+
+    #[test]
+    fn handshake_covers_service_order() {
+        assert_ne!(
+            all::handshake().schema_fingerprint,
+            reordered::handshake().schema_fingerprint
+        );
+    }
 }
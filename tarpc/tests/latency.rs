@@ -54,7 +54,7 @@ async fn bench() -> io::Result<()> {
         Server::new(server::Config::default())
             .incoming(listener)
             .take(1)
-            .respond_with(ack::serve(Serve))
+            .respond_with_extras(ack::serve(Serve))
             .unit_error()
             .boxed()
             .compat()
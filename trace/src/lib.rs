@@ -74,6 +74,16 @@ impl Context {
             parent_id: None,
         }
     }
+
+    /// Constructs a new context for a span caused by the current span. The new context has the
+    /// same trace ID as the current context, a new span ID, and the current span as its parent.
+    pub fn new_child(&self) -> Self {
+        Context {
+            trace_id: self.trace_id,
+            span_id: SpanId::random(&mut rand::thread_rng()),
+            parent_id: Some(self.span_id),
+        }
+    }
 }
 
 impl TraceId {